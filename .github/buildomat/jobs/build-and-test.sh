@@ -3,7 +3,7 @@
 #: name = "helios / build-and-test"
 #: variety = "basic"
 #: target = "helios"
-#: rust_toolchain = "1.75"
+#: rust_toolchain = "stable"
 #: output_rules = []
 #:
 
@@ -0,0 +1,27 @@
+#!/bin/bash
+#:
+#: name = "helios / msrv"
+#: variety = "basic"
+#: target = "helios"
+#: rust_toolchain = "1.85"
+#: output_rules = []
+#:
+
+set -o errexit
+set -o pipefail
+set -o xtrace
+
+cargo --version
+rustc --version
+
+#
+# Check that the library builds with the minimum supported Rust version, the
+# `rust-version` in Cargo.toml, which is documented in the README and crate
+# documentation. The tests aren't built, since development dependencies may
+# need a newer toolchain.
+#
+export RUSTFLAGS="-D warnings"
+banner check
+ptime -m cargo check
+banner features
+ptime -m cargo check --all-features
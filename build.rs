@@ -13,17 +13,33 @@
 // limitations under the License.
 //
 
+use std::env;
+
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
 
-    if !version_check::is_min_version("1.59").unwrap_or(false) {
+    let feat_asm = env::var_os("CARGO_FEATURE_ASM").is_some();
+    let is_nightly = version_check::is_feature_flaggable().unwrap_or(false);
+    // `asm` was stabilized in 1.59, and `asm_sym` in 1.66.
+    let have_stable_asm = version_check::is_min_version("1.59").unwrap_or(false);
+    let have_stable_asm_sym = version_check::is_min_version("1.66").unwrap_or(false);
+
+    // Mirror the choice of backend made by `usdt`, which compiles probes to no-ops unless the
+    // `asm` feature is enabled, the target supports DTrace, and the toolchain can build the
+    // assembly, either because it is stable or because feature flags are allowed.
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let need_asm_sym = target_os == "macos" && !have_stable_asm_sym;
+    let probes = feat_asm
+        && matches!(target_os.as_str(), "illumos" | "solaris" | "macos")
+        && (is_nightly || (have_stable_asm && !need_asm_sym));
+    if !probes {
+        return;
+    }
+    println!("cargo:rustc-cfg=slog_dtrace_probes");
+    if !have_stable_asm {
         println!("cargo:rustc-cfg=usdt_need_asm");
     }
-
-    #[cfg(target_os = "macos")]
-    if version_check::supports_feature("asm_sym").unwrap_or(false)
-        && !version_check::is_min_version("1.67").unwrap_or(false)
-    {
+    if need_asm_sym {
         println!("cargo:rustc-cfg=usdt_need_asm_sym");
     }
 }
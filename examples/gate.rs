@@ -0,0 +1,30 @@
+//! Example skipping expensive logging entirely unless DTrace is tracing the process.
+use slog::{info, o, Logger};
+use slog_dtrace::{Dtrace, ProbeRegistration};
+use std::sync::Arc;
+
+// A snapshot of some state, which is costly to build, and only useful when someone is looking.
+fn snapshot() -> Vec<String> {
+    eprintln!("building the snapshot");
+    (0..1000).map(|i| format!("entry-{i}")).collect()
+}
+
+fn main() {
+    let (drain, registration) = Dtrace::new();
+    if let ProbeRegistration::Failed(ref e) = registration {
+        panic!("Failed to register probes: {:#?}", e);
+    }
+    let drain = Arc::new(drain);
+    let log = Logger::root(Arc::clone(&drain), o!());
+    loop {
+        // The gate is only enabled while a D clause matches the `gate` probe, so neither the
+        // snapshot nor the logger holding it are built otherwise. Run with and without
+        // `dtrace -n 'slog*:::gate {} slog*:::info { printf("%s\n", copyinstr(arg0)); }'` to see
+        // the difference.
+        if drain.any_enabled() {
+            let log = log.new(o!("entries" => format!("{:?}", snapshot())));
+            info!(log, "a snapshot of the state");
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,33 @@
+//! Example deferring the construction of an expensive value until DTrace is tracing.
+use slog::{debug, o, FnValue, Logger};
+use slog_dtrace::{Dtrace, ProbeRegistration};
+use std::sync::Arc;
+
+// A value which is costly to compute, and only useful when someone is looking.
+fn expensive_summary() -> String {
+    eprintln!("computing the summary");
+    (0..1000).map(|i| i * i).sum::<u64>().to_string()
+}
+
+fn main() {
+    let (drain, registration) = Dtrace::new();
+    if let ProbeRegistration::Failed(ref e) = registration {
+        panic!("Failed to register probes: {:#?}", e);
+    }
+    let drain = Arc::new(drain);
+    let log = Logger::root(Arc::clone(&drain), o!());
+    loop {
+        // The closure is only called when the message is serialized, which the `Dtrace` drain only
+        // does if the `debug` probe is enabled. Run with and without
+        // `dtrace -n 'slog*:::debug { printf("%s\n", copyinstr(arg0)); }'` to see the difference.
+        debug!(log, "a costly message"; "summary" => FnValue(|_| expensive_summary()));
+
+        // Alternatively, skip the whole message unless anyone is tracing the provider, which is
+        // the case while a D clause matches its `gate` probe, as with `slog*:::`.
+        if drain.is_enabled() {
+            let summary = expensive_summary();
+            debug!(log, "a guarded message"; "summary" => summary);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
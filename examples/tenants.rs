@@ -0,0 +1,21 @@
+//! Example routing the logs of several tenants to separate DTrace providers.
+use slog::{info, o, Drain, Logger};
+use slog_dtrace::{DtraceBuilder, ProbeRegistration};
+
+slog_dtrace::provider!(Alpha, alpha_probes, "alpha");
+slog_dtrace::provider!(Beta, beta_probes, "beta");
+
+fn main() {
+    let (alpha, registration) = DtraceBuilder::new().provider::<Alpha>().build();
+    if let ProbeRegistration::Failed(ref e) = registration {
+        panic!("Failed to register probes: {:#?}", e);
+    }
+    let (beta, _) = DtraceBuilder::new().provider::<Beta>().build();
+    let alpha = Logger::root(alpha.fuse(), o!("tenant" => "alpha"));
+    let beta = Logger::root(beta.fuse(), o!("tenant" => "beta"));
+    loop {
+        info!(alpha, "a request for alpha");
+        info!(beta, "a request for beta");
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
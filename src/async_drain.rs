@@ -0,0 +1,359 @@
+//! A [`Dtrace`](crate::Dtrace) drain which builds and emits messages on a background thread.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    find_numeric_value, finish_message, fire_probe, fire_request, record_tag, start_message, Batch,
+    Config, Format, JsonMap, LevelCounters, Location, Message, Provider, Serializer, Slog,
+    StatsTimer, LOGGER_SCOPE, RECORD_SCOPE,
+};
+use slog::{Drain, KV};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A [`slog::Drain`] that forwards all log messages to DTrace from a background thread.
+///
+/// This is built with [`DtraceBuilder::build_async`](crate::DtraceBuilder::build_async). The
+/// logging thread only captures the parts of each message which borrow from the record: its text,
+/// its own key-value pairs, and the timestamp and thread. The remaining key-value pairs are
+/// serialized, and the probe fired, on a worker thread. As with the [`Dtrace`](crate::Dtrace)
+/// drain, the message is only serialized there if the probe is enabled.
+///
+/// Messages are queued in the order they are logged, and emitted by a single worker, so they fire
+/// in that order. If the queue is full, further messages are dropped rather than blocking the
+/// logging thread, and counted in [`LevelCounters::dropped`]. The worker may also emit several
+/// messages with each probe, see [`DtraceBuilder::batch`](crate::DtraceBuilder::batch). When the
+/// drain is dropped, the
+/// worker emits any messages remaining in the queue before exiting, and the drop waits for it.
+/// To wait for queued messages while the drain is still in use, for example before exiting the
+/// process, see [`AsyncDtrace::flush`].
+#[derive(Debug)]
+pub struct AsyncDtrace<P = Slog> {
+    config: Config,
+    counters: Arc<LevelCounters>,
+    queued: Arc<Queued>,
+    stats: Option<StatsTimer>,
+    sender: Option<SyncSender<Pending>>,
+    // Held in a mutex only so that the drain is unwind-safe, as required by `slog::Logger`.
+    worker: Mutex<Option<JoinHandle<()>>>,
+    _provider: std::marker::PhantomData<fn() -> P>,
+}
+
+impl<P> AsyncDtrace<P>
+where
+    P: Provider + 'static,
+{
+    pub(crate) fn from_config(config: Config, capacity: usize) -> Self {
+        let (sender, receiver) = sync_channel(capacity);
+        let counters = Arc::<LevelCounters>::default();
+        let queued = Arc::<Queued>::default();
+        let worker = {
+            let config = config.clone();
+            let counters = Arc::clone(&counters);
+            let queued = Arc::clone(&queued);
+            std::thread::Builder::new()
+                .name(String::from("slog-dtrace"))
+                .spawn(move || run_worker::<P>(&config, &counters, &queued, receiver))
+                .expect("failed to spawn DTrace worker thread")
+        };
+        Self {
+            stats: config.stats_interval.map(StatsTimer::new),
+            config,
+            counters,
+            queued,
+            sender: Some(sender),
+            worker: Mutex::new(Some(worker)),
+            _provider: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<P> AsyncDtrace<P> {
+    /// Return the counts of messages handled by this drain, see [`LevelCounters`].
+    pub fn counters(&self) -> Arc<LevelCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// Wait until all queued messages have been emitted, or until `timeout` elapses.
+    ///
+    /// Returns `true` if the queue was emptied, or `false` if the timeout elapsed first. Messages
+    /// logged by other threads while waiting are also waited for.
+    ///
+    /// This blocks on a mutex and condition variable, and so is not async-signal-safe: it must not
+    /// be called from a signal handler. Use [`AsyncDtrace::flush_handle`] to flush once the drain
+    /// has been moved into a logger.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.queued.wait(timeout)
+    }
+
+    /// Return a handle with which to flush this drain, once it has been moved into a logger.
+    pub fn flush_handle(&self) -> FlushHandle {
+        FlushHandle {
+            queued: Arc::clone(&self.queued),
+        }
+    }
+}
+
+/// A handle to flush an [`AsyncDtrace`] drain, returned by [`AsyncDtrace::flush_handle`].
+#[derive(Debug, Clone)]
+pub struct FlushHandle {
+    queued: Arc<Queued>,
+}
+
+impl FlushHandle {
+    /// Wait until all queued messages have been emitted, or until `timeout` elapses.
+    ///
+    /// See [`AsyncDtrace::flush`] for details.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.queued.wait(timeout)
+    }
+}
+
+// The number of messages queued for the worker, and a condition signalled when it reaches zero.
+#[derive(Debug, Default)]
+struct Queued {
+    count: AtomicUsize,
+    lock: Mutex<()>,
+    emptied: Condvar,
+}
+
+impl Queued {
+    fn wait(&self, timeout: Duration) -> bool {
+        let guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let result = self
+            .emptied
+            .wait_timeout_while(guard, timeout, |_| self.count.load(Ordering::Acquire) > 0);
+        match result {
+            Ok((_, result)) => !result.timed_out(),
+            Err(e) => !e.into_inner().1.timed_out(),
+        }
+    }
+
+    fn done(&self) {
+        if self.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Take the lock so that the notification can't be missed by a waiter which has just
+            // checked the count.
+            let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.emptied.notify_all();
+        }
+    }
+}
+
+impl<P> Drop for AsyncDtrace<P> {
+    fn drop(&mut self) {
+        // Close the queue, so that the worker exits once it has emitted the remaining messages.
+        self.sender.take();
+        let worker = match self.worker.get_mut() {
+            Ok(worker) => worker.take(),
+            Err(e) => e.into_inner().take(),
+        };
+        if let Some(worker) = worker {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<P> Drain for AsyncDtrace<P>
+where
+    P: Provider,
+{
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let level = record.level();
+        if let Some(stats) = &self.stats {
+            stats.fire::<P>(&self.counters);
+        }
+        if !self.config.forwards(&self.counters, level, record.module()) {
+            return Ok(());
+        }
+        let value = match self.config.format {
+            Format::Json => self
+                .config
+                .numeric_key()
+                .and_then(|key| find_numeric_value(record, values, key)),
+            Format::MessagePack
+            | Format::Tsv
+            | Format::BareJson
+            | Format::FramedJson
+            | Format::Fields => None,
+        };
+        let location = Location::from_record(record);
+        let mut msg = start_message(
+            &self.config,
+            location,
+            record.column(),
+            level,
+            record.msg().to_string(),
+            JsonMap::default(),
+        );
+        msg.tag = record_tag(&self.config, record);
+        let mut serializer = Serializer::with_config(&self.config);
+        serializer.scope = RECORD_SCOPE;
+        let result = record.kv().serialize(record, &mut serializer);
+        serializer.record_result(result);
+        let pending = Pending {
+            location: *record.location(),
+            level,
+            tag: record.tag().to_string(),
+            msg,
+            serializer,
+            values: values.clone(),
+            value,
+        };
+        if let Some(sender) = &self.sender {
+            self.queued.count.fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = sender.try_send(pending) {
+                self.queued.done();
+                if let TrySendError::Full(_) = e {
+                    self.counters.record_dropped(level);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A message captured on the logging thread, to be completed by the worker.
+struct Pending {
+    location: slog::RecordLocation,
+    level: slog::Level,
+    tag: String,
+    // The message, without its key-value pairs.
+    msg: Message,
+    // The key-value pairs of the record, to which those of the logger are added.
+    serializer: Serializer,
+    values: slog::OwnedKVList,
+    value: Option<u64>,
+}
+
+impl Pending {
+    // Complete the message, adding the key-value pairs of the logger.
+    fn message<P>(&self, config: &Config) -> Message
+    where
+        P: Provider,
+    {
+        self.with_record(|record| {
+            let mut msg = self.msg.clone();
+            let mut serializer = self.serializer.clone();
+            if config.loggers.is_none() {
+                serializer.scope = LOGGER_SCOPE;
+                let result = self.values.serialize(record, &mut serializer);
+                serializer.record_result(result);
+            }
+            msg.scopes = serializer.scopes.take();
+            msg.kv = serializer.finish();
+            let mut msg = finish_message(config, msg);
+            if let Some(loggers) = &config.loggers {
+                msg.logger_id = Some(loggers.id::<P>(record, &self.values, config));
+            }
+            msg
+        })
+    }
+
+    // Call `f` with a record reconstructed from the captured parts of the original.
+    fn with_record<R>(&self, f: impl FnOnce(&slog::Record<'_>) -> R) -> R {
+        let rstatic = slog::RecordStatic {
+            location: &self.location,
+            tag: &self.tag,
+            level: self.level,
+        };
+        f(&slog::Record::new(
+            &rstatic,
+            &format_args!("{}", self.msg.message),
+            slog::BorrowedKV(&()),
+        ))
+    }
+}
+
+// Emit each message received from the drain, until it is dropped.
+fn run_worker<P>(
+    config: &Config,
+    counters: &LevelCounters,
+    queued: &Queued,
+    receiver: Receiver<Pending>,
+) where
+    P: Provider,
+{
+    if let Some(batch) = config.batch {
+        return run_batches::<P>(config, counters, queued, receiver, batch);
+    }
+    for pending in receiver {
+        let f = || pending.message::<P>(config);
+        fire_probe::<P, _>(config, counters, pending.level, pending.value, f);
+        fire_others::<P>(config, &pending);
+        queued.done();
+    }
+}
+
+// Emit the messages received from the drain in batches, until it is dropped.
+fn run_batches<P>(
+    config: &Config,
+    counters: &LevelCounters,
+    queued: &Queued,
+    receiver: Receiver<Pending>,
+    batch: Batch,
+) where
+    P: Provider,
+{
+    let mut pending = Vec::with_capacity(batch.max_messages);
+    while let Ok(first) = receiver.recv() {
+        pending.push(first);
+        let deadline = Instant::now() + batch.window;
+        while pending.len() < batch.max_messages {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(next) => pending.push(next),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        P::fire_batch(|| {
+            pending
+                .iter()
+                .map(|pending| {
+                    counters.record_fired(pending.level);
+                    let msg = pending.message::<P>(config);
+                    if msg.truncated {
+                        counters.record_truncated(pending.level);
+                    }
+                    msg
+                })
+                .collect()
+        });
+        for pending in pending.drain(..) {
+            fire_others::<P>(config, &pending);
+            queued.done();
+        }
+    }
+}
+
+// Emit a message via the `request` probe, if configured, and the `tagged` probe, if it has a tag.
+fn fire_others<P>(config: &Config, pending: &Pending)
+where
+    P: Provider,
+{
+    fire_request::<P, _>(config, || pending.message::<P>(config));
+    if !pending.tag.is_empty() {
+        P::fire_tagged(|| (pending.tag.clone(), pending.message::<P>(config)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Consume the messages emitted by this crate's probes, by running `dtrace(1)`.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{probe_spec, Message, ParseError};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, BufReader, Split};
+use tokio::process::{Child, ChildStdout, Command};
+
+/// A [`Stream`] of the messages printed by a `dtrace(1)` process.
+///
+/// This runs `dtrace` as a child process, and parses each line it prints with
+/// [`Message::from_probe_line`], yielding the result. Blank lines are skipped, and lines which
+/// aren't valid UTF-8, for example because DTrace's `strsize` cut a character in two, are
+/// parsed lossily, and so usually yield [`ParseError::Truncated`]. The stream ends when `dtrace` closes
+/// its output, usually because it exited, or if the output can't be read. The exit status is
+/// then available from [`Consumer::wait`].
+///
+/// The child is killed if the `Consumer` is dropped, or with [`Consumer::stop`]. Messages must
+/// be emitted in the [`Json`](crate::Format::Json) format, via the probe for each level, which is
+/// the default.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use slog::Level;
+/// use slog_dtrace::Consumer;
+///
+/// let mut command = Consumer::command("slog", &[Level::Warning, Level::Error]);
+/// command.arg("-p").arg("1234");
+/// let mut consumer = Consumer::spawn(command)?;
+/// while let Some(msg) = consumer.next_message().await {
+///     match msg {
+///         Ok(msg) => println!("{}: {}", msg.level, msg.message),
+///         Err(e) => eprintln!("{e}"),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Consumer {
+    child: Child,
+    output: Split<BufReader<ChildStdout>>,
+}
+
+impl Consumer {
+    /// Return a command running `dtrace` to print the messages emitted via the probes of
+    /// `provider` for each of `levels`, see [`probe_spec`].
+    ///
+    /// The command traces every process with the provider, including those which start later.
+    /// It may be modified before it is passed to [`Consumer::spawn`], for example to trace a
+    /// single process with `-p`, or to raise `strsize` with `-x`. Tracing usually requires
+    /// privileges, so it may instead be useful to build a command running `pfexec` or `sudo`,
+    /// with the same arguments as this.
+    pub fn command(provider: &str, levels: &[slog::Level]) -> Command {
+        let mut command = Command::new("dtrace");
+        command.args(["-q", "-Z", "-n"]).arg(format!(
+            r#"{} {{ printf("%s\n", copyinstr(arg0)); }}"#,
+            probe_spec(provider, levels),
+        ));
+        command
+    }
+
+    /// Spawn `command`, and return a `Consumer` of the messages it prints.
+    ///
+    /// This is usually a command returned by [`Consumer::command`], though any process printing
+    /// one message per line may be used. Its standard output is captured, and its standard input
+    /// closed.
+    pub fn spawn(mut command: Command) -> std::io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| std::io::Error::other("standard output of `dtrace` is not captured"))?;
+        Ok(Self {
+            child,
+            output: BufReader::new(stdout).split(b'\n'),
+        })
+    }
+
+    /// Return the next message, or `None` once the stream has ended.
+    ///
+    /// This is equivalent to `StreamExt::next`, without depending on a crate providing it.
+    pub async fn next_message(&mut self) -> Option<Result<Message, ParseError>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Return the ID of the `dtrace` process, if it hasn't yet been waited for.
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Wait for the `dtrace` process to exit, and return its exit status.
+    pub async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.child.wait().await
+    }
+
+    /// Kill the `dtrace` process, and wait for it to exit.
+    pub async fn stop(mut self) -> std::io::Result<()> {
+        self.child.kill().await
+    }
+}
+
+impl Stream for Consumer {
+    type Item = Result<Message, ParseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let line = match Pin::new(&mut self.output).poll_next_segment(cx) {
+                Poll::Ready(Ok(Some(line))) => line,
+                Poll::Ready(Ok(None) | Err(_)) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                return Poll::Ready(Some(Message::from_probe_line(&line)));
+            }
+        }
+    }
+}
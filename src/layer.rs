@@ -0,0 +1,300 @@
+//! Forward `tracing` events to the same DTrace probes as the [`Dtrace`](crate::Dtrace) drain.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    error_chain, fire_probe, fire_request, float_value, new_message, Config, DtraceBuilder, Format,
+    JsonMap, LevelCounters, Location, ProbeRegistration, Provider, Serializer, Slog,
+};
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A [`tracing_subscriber::Layer`] that forwards all events to DTrace.
+///
+/// Events are emitted as a [`Message`](crate::Message) through the same probes as the
+/// [`Dtrace`](crate::Dtrace) drain, so existing DTrace scripts work with either. The `message`
+/// field of an event becomes the message itself, and its other fields are added to the `kv` map,
+/// along with the fields of all spans containing the event. Fields of the event take precedence
+/// over those of its spans, and fields of inner spans over those of outer spans.
+///
+/// A `DtraceLayer` is configured with the same [`DtraceBuilder`] as the drain. Note that when a
+/// [`numeric_key`](DtraceBuilder::numeric_key) is set, only the fields of the event itself are
+/// searched for it.
+///
+/// # Span probes
+///
+/// Each time a span is entered or exited, the layer fires the `span_enter` or `span_exit` probe,
+/// rather than emitting a message. Both take the same arguments:
+///
+/// - `arg0`: the ID of the span, as a `u64`.
+/// - `arg1`: the name of the span, as a string.
+/// - `arg2`: the fields recorded for the span so far, as a JSON object wrapped like any other
+///   argument, e.g., `{"ok":{"key":"value"}}`.
+///
+/// A span may be entered and exited several times, for example each time the future it
+/// instruments is polled, and on different threads, so entries are best matched to exits by the
+/// ID of the span rather than by thread. The IDs of spans which have closed may be reused, but
+/// never while the span is open. For example, this script aggregates the time spent in each span
+/// by name:
+///
+/// ```text
+/// slog*:::span_enter
+/// {
+///     start[pid, arg0] = timestamp;
+/// }
+///
+/// slog*:::span_exit
+/// /start[pid, arg0]/
+/// {
+///     @[copyinstr(arg1)] = quantize(timestamp - start[pid, arg0]);
+///     start[pid, arg0] = 0;
+/// }
+/// ```
+///
+/// The span probes fire while the layer is [verbose](DtraceBuilder::verbose), regardless of the
+/// level of the span or the other filters of the builder, since they cost nothing unless enabled.
+#[derive(Debug)]
+pub struct DtraceLayer<P = Slog> {
+    config: Config,
+    counters: Arc<LevelCounters>,
+    _provider: std::marker::PhantomData<fn() -> P>,
+}
+
+impl DtraceLayer {
+    /// Create a new layer, emitting events to DTrace.
+    ///
+    /// See [`Dtrace::new`](crate::Dtrace::new) for details on the returned [`ProbeRegistration`].
+    pub fn new() -> (Self, ProbeRegistration) {
+        DtraceBuilder::new().build_layer()
+    }
+}
+
+impl<P> DtraceLayer<P> {
+    /// Return the counts of events handled by this layer, see [`LevelCounters`].
+    pub fn counters(&self) -> Arc<LevelCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    pub(crate) fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            counters: Arc::default(),
+            _provider: std::marker::PhantomData,
+        }
+    }
+
+    // Return the arguments of the `span_enter` and `span_exit` probes for the span `id`.
+    fn span_args<S>(&self, id: &Id, ctx: &Context<'_, S>) -> (u64, String, JsonMap)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut serializer = Serializer::with_config(&self.config);
+        let name = match ctx.span(id) {
+            Some(span) => {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    for (key, value) in fields {
+                        let _ = serializer.emit(key, value.clone());
+                    }
+                }
+                span.name()
+            }
+            None => "",
+        };
+        (id.into_u64(), name.to_string(), serializer.finish())
+    }
+}
+
+// The fields recorded for a span, stored in its extensions.
+struct SpanFields(Vec<(&'static str, serde_json::Value)>);
+
+impl<S, P> Layer<S> for DtraceLayer<P>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    P: Provider + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = FieldVisitor::default();
+            attrs.record(&mut visitor);
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = FieldVisitor::default();
+            values.record(&mut visitor);
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<SpanFields>() {
+                Some(SpanFields(fields)) => fields.extend(visitor.fields),
+                None => extensions.insert(SpanFields(visitor.fields)),
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.config.verbose.is_verbose() {
+            P::fire_span_enter(|| self.span_args(id, &ctx));
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.config.verbose.is_verbose() {
+            P::fire_span_exit(|| self.span_args(id, &ctx));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = slog_level(metadata.level());
+        let module = metadata.module_path().unwrap_or_default();
+        if !self.config.forwards(&self.counters, level, module) {
+            return;
+        }
+        let value = match (self.config.format, self.config.numeric_key()) {
+            (Format::Json, Some(key)) => {
+                let mut finder = KeyVisitor { key, value: None };
+                event.record(&mut finder);
+                finder.value
+            }
+            _ => None,
+        };
+        let f = || {
+            let metadata = event.metadata();
+            let location = Location {
+                module: metadata.module_path().unwrap_or_default().to_string(),
+                file: metadata.file().unwrap_or_default().to_string(),
+                line: metadata.line().unwrap_or_default(),
+                function: None,
+            };
+
+            let mut serializer = Serializer::with_config(&self.config);
+            if let Some(scope) = ctx.event_scope(event) {
+                for span in scope.from_root() {
+                    serializer.scope = span.name();
+                    if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                        for (key, value) in fields {
+                            let _ = serializer.emit(key, value.clone());
+                        }
+                    }
+                }
+            }
+            serializer.scope = "event";
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+            let mut message = String::new();
+            for (key, value) in visitor.fields {
+                match (key, value) {
+                    ("message", serde_json::Value::String(s)) => message = s,
+                    (key, value) => {
+                        let _ = serializer.emit(key, value);
+                    }
+                }
+            }
+            let scopes = serializer.scopes.take();
+            let kv = serializer.finish();
+            new_message(&self.config, location, level, message, kv, scopes)
+        };
+        fire_probe::<P, _>(&self.config, &self.counters, level, value, f);
+        fire_request::<P, _>(&self.config, f);
+    }
+}
+
+// Map a `tracing` level to the `slog` level with the same probe.
+fn slog_level(level: &tracing::Level) -> slog::Level {
+    match *level {
+        tracing::Level::TRACE => slog::Level::Trace,
+        tracing::Level::DEBUG => slog::Level::Debug,
+        tracing::Level::INFO => slog::Level::Info,
+        tracing::Level::WARN => slog::Level::Warning,
+        tracing::Level::ERROR => slog::Level::Error,
+    }
+}
+
+// Type used to collect the fields of `tracing` events and spans as JSON.
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Vec<(&'static str, serde_json::Value)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.fields
+            .push((field.name(), format!("{:?}", value).into()));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.push((field.name(), float_value(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.fields.push((field.name(), value.to_string().into()));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.fields.push((field.name(), value.to_string().into()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.push((field.name(), value.into()));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.fields.push((field.name(), error_chain(value)));
+    }
+}
+
+// Type used to find the integer value of a single field, ignoring all others.
+struct KeyVisitor<'a> {
+    key: &'a str,
+    value: Option<u64>,
+}
+
+impl Visit for KeyVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, _: &dyn std::fmt::Debug) {
+        if field.name() == self.key {
+            self.value = None;
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == self.key {
+            // Negative values can't be passed as the unsigned probe argument.
+            self.value = u64::try_from(value).ok();
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == self.key {
+            self.value = Some(value);
+        }
+    }
+}
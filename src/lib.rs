@@ -24,7 +24,7 @@
 //! -------
 //!
 //! ```bash
-//! $ cargo +nightly run --example simple
+//! $ cargo run --example simple
 //!
 //! ```
 //!
@@ -48,10 +48,60 @@
 //! {"ok": {"location":{"module":"simple","file":"examples/simple.rs","line":17},"level":"DEBUG","timestamp":"2021-10-19T17:57:30.578681933Z","message":"only dtrace gets debug messages","kv":{"cool":true,"hello":"from dtrace","key":"value"}}}
 //! ```
 //!
-//! Notes
-//! -----
+//! Always print a message with a format string, as above, rather than passing it as the format
+//! itself, as in `printf(copyinstr(arg0))`. Messages may contain `%` characters, which `printf`
+//! would then interpret as conversions, garbling the output or failing with an error. Where
+//! scripts can't be trusted to do so, [`DtraceBuilder::printf_safe`] escapes them instead.
 //!
-//! This crate inherits a reliance on a nightly toolchain from the `usdt` crate.
+//! Custom providers
+//! ----------------
+//!
+//! By default, probes are emitted by a DTrace provider named `slog`. When several programs on a
+//! system use this crate, it can be useful to give each its own provider name. The [`provider!`]
+//! macro defines a new provider with the same probes, which can be selected with the
+//! [`DtraceBuilder`]. Messages are then available via probes such as `nexus*:::info`.
+//!
+//! `tracing` support
+//! -----------------
+//!
+//! With the `tracing` feature enabled, the `DtraceLayer` type can be added to a
+//! `tracing_subscriber` registry to forward `tracing` events through the same probes. The fields
+//! of each event and its enclosing spans are included in the message's key-value pairs. Entering
+//! and exiting a span also fire the `span_enter` and `span_exit` probes, from which DTrace can
+//! measure how long each span was entered.
+//!
+//! `log` support
+//! -------------
+//!
+//! With the `log` feature enabled, the `DtraceLogger` type can be installed as the logger of the
+//! `log` crate, to forward the records of libraries using it through the same probes. The
+//! key-value pairs of each record are included in the message.
+//!
+//! OpenTelemetry support
+//! ---------------------
+//!
+//! With the `otel` feature enabled, each message includes the `trace_id` and `span_id` of the
+//! active span in the current `opentelemetry::Context`, as hex strings. They are omitted when no
+//! span is active.
+//!
+//! Toolchains and platforms
+//! ------------------------
+//!
+//! The minimum supported Rust version is 1.85, as declared by `rust-version` in `Cargo.toml`, and
+//! checked in CI. Probes are implemented with inline assembly, enabled by the default `asm`
+//! feature, which every supported toolchain can build. Whether they are available depends on the
+//! target:
+//!
+//! | Target                  | Behavior    |
+//! |-------------------------|-------------|
+//! | illumos, Solaris, macOS | Probes fire |
+//! | Any other target        | No-op drain |
+//! | Any, without `asm`      | No-op drain |
+//!
+//! Where probes are unavailable, the drain returned by [`with_drain`] is a [no-op](Dtrace::noop),
+//! so the same logging setup can be used on all platforms, and the crate never fails to build.
+//! Disabling default features, with `default-features = false`, avoids inline assembly entirely.
+//! See [`is_supported`] for how this is detected.
 
 // Copyright 2022 Oxide Computer Company
 //
@@ -73,231 +123,7947 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use slog::{Drain, KV};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod async_drain;
+pub use async_drain::{AsyncDtrace, FlushHandle};
+
+mod recent;
+pub use recent::RecentMessages;
+
+#[cfg(feature = "tracing")]
+mod layer;
+#[cfg(feature = "tracing")]
+pub use layer::DtraceLayer;
+
+#[cfg(feature = "log")]
+mod logger;
+#[cfg(feature = "log")]
+pub use logger::DtraceLogger;
+
+#[cfg(feature = "consumer")]
+mod consumer;
+#[cfg(feature = "consumer")]
+pub use consumer::Consumer;
+
+#[cfg(feature = "test-sink")]
+mod test_sink;
+#[cfg(feature = "test-sink")]
+pub use test_sink::{Captured, CapturedSpan, TestSink};
 
 /// Type alias for a generic JSON map.
 pub type JsonMap = serde_json::Map<String, serde_json::Value>;
 
-#[usdt::provider(provider = "slog", probe_format = "{probe}_")]
-mod probes {
-    use crate::Message;
-    fn trace(msg: &Message) {}
-    fn debug(msg: &Message) {}
-    fn info(msg: &Message) {}
-    fn warn(msg: &Message) {}
-    fn error(msg: &Message) {}
-    fn critical(msg: &Message) {}
+/// Define a DTrace provider to which log messages may be forwarded.
+///
+/// By default, the [`Dtrace`] drain fires probes in a provider named `slog`. Because `usdt`
+/// resolves provider names when its macros are expanded, a different name can't be chosen at
+/// runtime. This macro instead defines a new provider in the calling crate, along with a type
+/// implementing [`Provider`], which can be handed to [`DtraceBuilder::provider`].
+///
+/// The arguments are the name of the generated type, the name of the module that will contain the
+/// probe definitions, and the DTrace provider name. The provider contains the same probes as the
+/// default `slog` provider, so the example below allows one to run `dtrace -n 'nexus*:::info'`.
+/// Note that the calling crate must also depend on `usdt`.
+///
+/// ```
+/// slog_dtrace::provider!(pub Nexus, nexus_probes, "nexus");
+///
+/// let (drain, registration) = slog_dtrace::DtraceBuilder::new()
+///     .provider::<Nexus>()
+///     .build();
+/// ```
+///
+/// Any number of providers may be defined and used at once, for example to give each tenant of a
+/// multi-tenant process its own provider, as in the `tenants` example. Each needs a distinct
+/// module name and provider name, and a logger is routed to a provider by building it from a drain
+/// using that provider. Note that `usdt` registers the probes of every provider in the process
+/// together, so registration can't fail for one provider and succeed for another: each builder
+/// reports the result of registering all of them, and the first call suffices.
+#[macro_export]
+macro_rules! provider {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $module:ident, $provider:literal) => {
+        #[::usdt::provider(provider = $provider, probe_format = "{probe}_")]
+        mod $module {
+            use $crate::{JsonMap, Message};
+            fn trace(msg: &Message) {}
+            fn debug(msg: &Message) {}
+            fn info(msg: &Message) {}
+            fn warn(msg: &Message) {}
+            fn error(msg: &Message) {}
+            fn critical(msg: &Message) {}
+            fn level1(msg: &Message) {}
+            fn level2(msg: &Message) {}
+            fn level3(msg: &Message) {}
+            fn level4(msg: &Message) {}
+            fn level5(msg: &Message) {}
+            fn level6(msg: &Message) {}
+            fn trace_value(msg: &Message, value: u64) {}
+            fn debug_value(msg: &Message, value: u64) {}
+            fn info_value(msg: &Message, value: u64) {}
+            fn warn_value(msg: &Message, value: u64) {}
+            fn error_value(msg: &Message, value: u64) {}
+            fn critical_value(msg: &Message, value: u64) {}
+            fn trace_bytes(buf: *const u8, len: u64) {}
+            fn debug_bytes(buf: *const u8, len: u64) {}
+            fn info_bytes(buf: *const u8, len: u64) {}
+            fn warn_bytes(buf: *const u8, len: u64) {}
+            fn error_bytes(buf: *const u8, len: u64) {}
+            fn critical_bytes(buf: *const u8, len: u64) {}
+            fn tagged(tag: &str, msg: &Message) {}
+            fn message(msg: &Message) {}
+            fn batch(msgs: &[Message]) {}
+            fn request(msg: &Message, latency_ns: u64, bytes: u64) {}
+            fn startup(pid: u64, start_time: &str, argv_hash: u64) {}
+            fn logger(id: u64, kv: &JsonMap) {}
+            fn fields(level: u8, msg: &str, module: &str, file: &str, line: u32, kv: &JsonMap) {}
+            fn stats(stats: &$crate::Degradation) {}
+            fn gate() {}
+            fn trace_gate() {}
+            fn debug_gate() {}
+            fn info_gate() {}
+            fn warn_gate() {}
+            fn error_gate() {}
+            fn critical_gate() {}
+            fn span_enter(id: u64, name: &str, fields: &JsonMap) {}
+            fn span_exit(id: u64, name: &str, fields: &JsonMap) {}
+        }
+
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, Default)]
+        $vis struct $name;
+
+        impl $crate::Provider for $name {
+            fn fire<F>(level: $crate::__private::Level, f: F)
+            where
+                F: FnOnce() -> $crate::Message + Clone,
+            {
+                use $crate::__private::Level;
+                match level {
+                    Level::Trace => $module::trace_!(|| f()),
+                    Level::Debug => $module::debug_!(|| f()),
+                    Level::Info => $module::info_!(|| f()),
+                    Level::Warning => $module::warn_!(|| f()),
+                    Level::Error => $module::error_!(|| f()),
+                    Level::Critical => $module::critical_!(|| f()),
+                }
+            }
+
+            fn fire_numbered<F>(level: $crate::__private::Level, f: F)
+            where
+                F: FnOnce() -> $crate::Message + Clone,
+            {
+                use $crate::__private::Level;
+                match level {
+                    Level::Critical => $module::level1_!(|| f()),
+                    Level::Error => $module::level2_!(|| f()),
+                    Level::Warning => $module::level3_!(|| f()),
+                    Level::Info => $module::level4_!(|| f()),
+                    Level::Debug => $module::level5_!(|| f()),
+                    Level::Trace => $module::level6_!(|| f()),
+                }
+            }
+
+            fn fire_value<F>(level: $crate::__private::Level, f: F)
+            where
+                F: FnOnce() -> ($crate::Message, u64) + Clone,
+            {
+                use $crate::__private::Level;
+                match level {
+                    Level::Trace => $module::trace_value_!(|| f()),
+                    Level::Debug => $module::debug_value_!(|| f()),
+                    Level::Info => $module::info_value_!(|| f()),
+                    Level::Warning => $module::warn_value_!(|| f()),
+                    Level::Error => $module::error_value_!(|| f()),
+                    Level::Critical => $module::critical_value_!(|| f()),
+                }
+            }
+
+            fn fire_bytes<F>(level: $crate::__private::Level, f: F)
+            where
+                F: FnOnce() -> Vec<u8> + Clone,
+            {
+                use $crate::__private::{Level, ProbeBuffer};
+                let args = || ProbeBuffer::new(f());
+                match level {
+                    Level::Trace => $module::trace_bytes_!(|| args()),
+                    Level::Debug => $module::debug_bytes_!(|| args()),
+                    Level::Info => $module::info_bytes_!(|| args()),
+                    Level::Warning => $module::warn_bytes_!(|| args()),
+                    Level::Error => $module::error_bytes_!(|| args()),
+                    Level::Critical => $module::critical_bytes_!(|| args()),
+                }
+            }
+
+            fn fire_tagged<F>(f: F)
+            where
+                F: FnOnce() -> (String, $crate::Message) + Clone,
+            {
+                $module::tagged_!(|| f())
+            }
+
+            fn fire_message<F>(f: F)
+            where
+                F: FnOnce() -> $crate::Message + Clone,
+            {
+                $module::message_!(|| f())
+            }
+
+            fn fire_batch<F>(f: F)
+            where
+                F: FnOnce() -> Vec<$crate::Message> + Clone,
+            {
+                $module::batch_!(|| f())
+            }
+
+            fn fire_request<F>(f: F)
+            where
+                F: FnOnce() -> ($crate::Message, u64, u64) + Clone,
+            {
+                $module::request_!(|| f())
+            }
+
+            fn fire_startup<F>(f: F)
+            where
+                F: FnOnce() -> (u64, String, u64) + Clone,
+            {
+                $module::startup_!(|| f())
+            }
+
+            fn fire_logger<F>(f: F)
+            where
+                F: FnOnce() -> (u64, $crate::JsonMap) + Clone,
+            {
+                $module::logger_!(|| f())
+            }
+
+            fn fire_fields<F>(f: F)
+            where
+                F: FnOnce() -> $crate::Message + Clone,
+            {
+                $module::fields_!(|| {
+                    let msg = f();
+                    (
+                        msg.level_num,
+                        msg.message,
+                        msg.location.module,
+                        msg.location.file,
+                        msg.location.line,
+                        msg.kv,
+                    )
+                })
+            }
+
+            fn fire_stats<F>(f: F)
+            where
+                F: FnOnce() -> $crate::Degradation + Clone,
+            {
+                $module::stats_!(|| f())
+            }
+
+            fn gate_enabled() -> bool {
+                let enabled = ::std::cell::Cell::new(false);
+                $module::gate_!(|| enabled.set(true));
+                enabled.get()
+            }
+
+            fn level_gate_enabled(level: $crate::__private::Level) -> bool {
+                use $crate::__private::Level;
+                let enabled = ::std::cell::Cell::new(false);
+                match level {
+                    Level::Trace => $module::trace_gate_!(|| enabled.set(true)),
+                    Level::Debug => $module::debug_gate_!(|| enabled.set(true)),
+                    Level::Info => $module::info_gate_!(|| enabled.set(true)),
+                    Level::Warning => $module::warn_gate_!(|| enabled.set(true)),
+                    Level::Error => $module::error_gate_!(|| enabled.set(true)),
+                    Level::Critical => $module::critical_gate_!(|| enabled.set(true)),
+                }
+                enabled.get()
+            }
+
+            fn fire_span_enter<F>(f: F)
+            where
+                F: FnOnce() -> (u64, String, $crate::JsonMap) + Clone,
+            {
+                $module::span_enter_!(|| f())
+            }
+
+            fn fire_span_exit<F>(f: F)
+            where
+                F: FnOnce() -> (u64, String, $crate::JsonMap) + Clone,
+            {
+                $module::span_exit_!(|| f())
+            }
+        }
+    };
+}
+
+/// The key-value pairs identifying the crate which issues messages, for [`Message::source_crate`]
+/// and [`Message::source_version`].
+///
+/// `slog` doesn't record which crate a message comes from, so this expands to key-value pairs
+/// with the name and version of the calling crate, taken from `CARGO_PKG_NAME` and
+/// `CARGO_PKG_VERSION` when it is compiled. A library can add them once to the logger it is
+/// given, and every message it issues then carries them. They are set with the reserved keys
+/// [`SOURCE_CRATE_KEY`] and [`SOURCE_VERSION_KEY`], which are removed from the key-value pairs of
+/// the message, but are emitted as usual by other drains.
+///
+/// ```
+/// use slog::{info, Logger};
+///
+/// fn library_logger(log: &Logger) -> Logger {
+///     log.new(slog_dtrace::source!())
+/// }
+///
+/// let log = library_logger(&Logger::root(slog::Discard, slog::o!()));
+/// info!(log, "a message from the library");
+/// ```
+#[macro_export]
+macro_rules! source {
+    () => {
+        ::slog::o!(
+            $crate::SOURCE_CRATE_KEY => env!("CARGO_PKG_NAME"),
+            $crate::SOURCE_VERSION_KEY => env!("CARGO_PKG_VERSION"),
+        )
+    };
+}
+
+/// The key-value pair naming the function which issues a message, for [`Location::function`].
+///
+/// `slog` records the module, file and line of each message, but not the function, whose name is
+/// often more useful than a line number which shifts with every edit. This macro expands to a
+/// key-value pair with the path of the calling function, such as `my_app::http::handle`, taken
+/// from [`std::any::type_name`] when it is compiled. Add it to the key-value pairs of each
+/// message whose function should be recorded. It is set with the reserved key [`FUNCTION_KEY`],
+/// which is removed from the key-value pairs of the message, but is emitted as usual by other
+/// drains. Within a closure or an `async` block, the function is the one enclosing it.
+///
+/// ```
+/// use slog::{info, Logger};
+///
+/// fn handle(log: &Logger) {
+///     info!(log, "handling a request"; slog_dtrace::function!(), "id" => 1);
+/// }
+///
+/// handle(&Logger::root(slog::Discard, slog::o!()));
+/// ```
+#[macro_export]
+macro_rules! function {
+    () => {
+        ::slog::SingleKV::from(($crate::FUNCTION_KEY, {
+            fn f() {}
+            $crate::__private::function_name(&f)
+        }))
+    };
+}
+
+/// The version of the schema of each [`Message`], emitted as [`Message::schema_version`].
+///
+/// This is incremented whenever the serialized form of a message changes in a way which
+/// consumers might need to distinguish, so that they can tell which fields to expect. The versions
+/// are:
+///
+/// - 1: Messages emitted before the version was included, which lack it.
+/// - 2: Adds `schema_version`. `level_num` and `severity` are always present.
+pub const SCHEMA_VERSION: u16 = 2;
+
+// The schema version of messages without one, see `SCHEMA_VERSION`.
+fn legacy_schema_version() -> u16 {
+    1
+}
+
+/// The default size in bytes above which messages are replaced, see
+/// [`DtraceBuilder::max_serialized_bytes`].
+pub const DEFAULT_MAX_SERIALIZED_BYTES: usize = 1024 * 1024;
+
+/// The environment variable which disables every [`Dtrace`] drain, when set to a non-empty value
+/// other than `0`.
+///
+/// This is consulted when each drain is built, and takes precedence over every builder setting:
+/// the drain is then a [no-op](Dtrace::noop), which returns from [`Drain::log`] before
+/// constructing any message. Probes are still registered as usual. Other drains, such as those
+/// given to [`with_drain`], continue to receive every message. This allows forwarding to DTrace to
+/// be turned off, for example for benchmarking, by restarting the process with
+/// `SLOG_DTRACE_DISABLE=1`, without changing any code.
+pub const DISABLE_ENV_VAR: &str = "SLOG_DTRACE_DISABLE";
+
+/// The reserved key of the crate name set by [`source!`].
+pub const SOURCE_CRATE_KEY: &str = "__source_crate";
+
+/// The reserved key of the crate version set by [`source!`].
+pub const SOURCE_VERSION_KEY: &str = "__source_version";
+
+/// The reserved key of the function name set by [`function!`].
+pub const FUNCTION_KEY: &str = "__function";
+
+// Items used by the `provider!` macro, which are not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use slog::Level;
+
+    use std::cell::Cell;
+
+    // Return the path of the function enclosing the item `f`, which is named `f`, see `function!`.
+    pub fn function_name<T>(_: &T) -> &'static str {
+        let name = std::any::type_name::<T>();
+        let mut name = name.strip_suffix("::f").unwrap_or(name);
+        while let Some(outer) = name.strip_suffix("::{{closure}}") {
+            name = outer;
+        }
+        name
+    }
+
+    // The largest buffer kept for reuse, so that one large message doesn't pin its memory.
+    const MAX_REUSED_CAPACITY: usize = 64 * 1024;
+
+    thread_local! {
+        // A buffer reused for each message encoded on this thread.
+        static BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+    }
+
+    // Take this thread's reusable buffer, which is empty but may have spare capacity.
+    //
+    // If a message is logged while another is being encoded on the same thread, such as from a
+    // `Serialize` implementation, the inner one finds the buffer already taken and allocates a
+    // new one. Only one of them is returned for reuse.
+    pub(crate) fn take_buffer() -> Vec<u8> {
+        BUFFER.try_with(Cell::take).unwrap_or_default()
+    }
+
+    // Return a buffer for reuse by this thread.
+    fn release_buffer(mut buf: Vec<u8>) {
+        if buf.capacity() > MAX_REUSED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        let _ = BUFFER.try_with(|cell| {
+            let current = cell.take();
+            cell.set(if current.capacity() > buf.capacity() {
+                current
+            } else {
+                buf
+            });
+        });
+    }
+
+    // A buffer passed to a probe as a pointer, which keeps its contents alive until the probe
+    // has fired, and is then returned for reuse.
+    pub struct ProbeBuffer {
+        buf: Vec<u8>,
+        ptr: *const u8,
+    }
+
+    impl ProbeBuffer {
+        // Return the buffer and its length, as the arguments to a probe.
+        pub fn new(buf: Vec<u8>) -> (Self, u64) {
+            let ptr = buf.as_ptr();
+            let len = buf.len() as u64;
+            (Self { buf, ptr }, len)
+        }
+    }
+
+    impl Drop for ProbeBuffer {
+        fn drop(&mut self) {
+            release_buffer(std::mem::take(&mut self.buf));
+        }
+    }
+
+    impl std::borrow::Borrow<*const u8> for ProbeBuffer {
+        fn borrow(&self) -> &*const u8 {
+            &self.ptr
+        }
+    }
+}
+
+provider!(
+    /// The default DTrace provider, named `slog`.
+    pub Slog,
+    probes,
+    "slog"
+);
+
+/// A `Provider` fires the DTrace probes for each log level.
+///
+/// Each method takes a closure which constructs the arguments to the probe. The closure is only
+/// called if the corresponding probe is enabled. Implementations are generated with the
+/// [`provider!`] macro, and the default provider is [`Slog`].
+pub trait Provider {
+    /// Fire the probe for messages at `level`, e.g., `warn`.
+    fn fire<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Message + Clone;
+
+    /// Fire the numbered probe for messages at `level`, e.g., `level3`.
+    ///
+    /// See [`ProbeNames::Numeric`] for details.
+    fn fire_numbered<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Message + Clone;
+
+    /// Fire the probe for messages at `level` carrying a numeric value, e.g., `warn_value`.
+    ///
+    /// See [`DtraceBuilder::numeric_key`] for details.
+    fn fire_value<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> (Message, u64) + Clone;
+
+    /// Fire the probe for messages at `level` encoded as bytes, e.g., `warn_bytes`.
+    ///
+    /// See [`DtraceBuilder::format`] for details.
+    fn fire_bytes<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Vec<u8> + Clone;
+
+    /// Fire the `tagged` probe, for messages at any level with a tag.
+    ///
+    /// See [`Dtrace`] for details.
+    fn fire_tagged<F>(f: F)
+    where
+        F: FnOnce() -> (String, Message) + Clone;
+
+    /// Fire the `message` probe, for messages at all levels.
+    ///
+    /// See [`DtraceBuilder::single_probe`] for details.
+    fn fire_message<F>(f: F)
+    where
+        F: FnOnce() -> Message + Clone;
+
+    /// Fire the `batch` probe, for several messages at once.
+    ///
+    /// See [`DtraceBuilder::batch`] for details.
+    fn fire_batch<F>(f: F)
+    where
+        F: FnOnce() -> Vec<Message> + Clone;
+
+    /// Fire the `request` probe, with the message and its typed arguments.
+    ///
+    /// See [`DtraceBuilder::request_probe`] for details.
+    fn fire_request<F>(f: F)
+    where
+        F: FnOnce() -> (Message, u64, u64) + Clone;
+
+    /// Fire the `startup` probe, with the process ID, start time, and hash of its arguments.
+    ///
+    /// See [`DtraceBuilder::startup_probe`] for details.
+    fn fire_startup<F>(f: F)
+    where
+        F: FnOnce() -> (u64, String, u64) + Clone;
+
+    /// Fire the `logger` probe, with the ID and key-value pairs of a set of loggers.
+    ///
+    /// See [`DtraceBuilder::logger_kv_once`] for details.
+    fn fire_logger<F>(f: F)
+    where
+        F: FnOnce() -> (u64, JsonMap) + Clone;
+
+    /// Fire the `fields` probe, with the fields of a message as separate arguments.
+    ///
+    /// See [`Format::Fields`] for details.
+    fn fire_fields<F>(f: F)
+    where
+        F: FnOnce() -> Message + Clone;
+
+    /// Fire the `stats` probe, with the counts of messages degraded by the drain.
+    ///
+    /// See [`DtraceBuilder::stats_interval`] for details.
+    fn fire_stats<F>(f: F)
+    where
+        F: FnOnce() -> Degradation + Clone;
+
+    /// Return `true` if the `gate` probe is enabled.
+    ///
+    /// See [`Dtrace::any_enabled`] for details.
+    fn gate_enabled() -> bool;
+
+    /// Return `true` if the gate probe for `level` is enabled, e.g., `warn_gate`.
+    ///
+    /// See [`Dtrace::level_enabled`] for details.
+    fn level_gate_enabled(level: slog::Level) -> bool;
+
+    /// Fire the `span_enter` probe, with the ID, name and fields of a `tracing` span.
+    ///
+    /// See `DtraceLayer`, with the `tracing` feature, for details.
+    fn fire_span_enter<F>(f: F)
+    where
+        F: FnOnce() -> (u64, String, JsonMap) + Clone;
+
+    /// Fire the `span_exit` probe, with the ID, name and fields of a `tracing` span.
+    ///
+    /// See `DtraceLayer`, with the `tracing` feature, for details.
+    fn fire_span_exit<F>(f: F)
+    where
+        F: FnOnce() -> (u64, String, JsonMap) + Clone;
 }
 
+/// The scopes in which each key of a message was set, see [`Message::scopes`].
+pub type Scopes = std::collections::BTreeMap<String, Vec<String>>;
+
 /// `Location` describes the location in the source from which a log message was issued.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
 pub struct Location {
     /// The Rust module from which the message was issued.
+    #[serde(alias = "m")]
     pub module: String,
 
     /// The source file from which the message was issued.
+    #[serde(alias = "f")]
     pub file: String,
 
     /// The line of the source file from which the message was issued.
+    #[serde(alias = "ln")]
     pub line: u32,
+
+    /// The path of the function from which the message was issued, if known.
+    ///
+    /// `slog` doesn't record the function, so this is only set for messages with the key-value
+    /// pair of the [`function!`] macro, or built from a [`slog::Record`] whose function is set.
+    /// It is omitted when unset.
+    #[serde(default, alias = "fn", skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+}
+
+impl Location {
+    /// Create a new `Location`, without a function.
+    pub fn new(module: impl Into<String>, file: impl Into<String>, line: u32) -> Self {
+        Self {
+            module: module.into(),
+            file: file.into(),
+            line,
+            function: None,
+        }
+    }
+
+    // Return the location from which `record` was issued.
+    pub(crate) fn from_record(record: &slog::Record) -> Self {
+        Self {
+            module: record.module().to_string(),
+            file: record.file().to_string(),
+            line: record.line(),
+            function: (!record.function().is_empty()).then(|| record.function().to_string()),
+        }
+    }
+}
+
+// A map serialized with its keys, and those of any nested objects, in sorted order.
+struct SortedMap<'a>(&'a JsonMap);
+
+impl Serialize for SortedMap<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        serializer.collect_map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, SortedValue(value))),
+        )
+    }
+}
+
+// A value serialized with the keys of any objects within it in sorted order.
+struct SortedValue<'a>(&'a serde_json::Value);
+
+impl Serialize for SortedValue<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self.0 {
+            serde_json::Value::Object(map) => SortedMap(map).serialize(serializer),
+            serde_json::Value::Array(values) => {
+                serializer.collect_seq(values.iter().map(SortedValue))
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+// A `Location`, serialized with the short field names of a compact `Message`.
+#[derive(Serialize)]
+struct CompactLocation<'a> {
+    #[serde(rename = "m")]
+    module: &'a str,
+    #[serde(rename = "f")]
+    file: &'a str,
+    #[serde(rename = "ln")]
+    line: u32,
+    #[serde(rename = "fn", skip_serializing_if = "Option::is_none")]
+    function: Option<&'a str>,
 }
 
 /// A `Message` captures the all information about a single log message.
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+///
+/// Messages are serialized with the field names below, or with shorter ones if
+/// [`Message::compact`] is set. Deserializing accepts either. In JSON, newlines and other control
+/// characters in strings are escaped, so each message is emitted as a single line.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[non_exhaustive]
 pub struct Message {
+    /// The version of the schema of the message, see [`SCHEMA_VERSION`].
+    ///
+    /// This is serialized first. When parsing a message without it, it is taken to be 1.
+    #[serde(default = "legacy_schema_version", alias = "sv")]
+    pub schema_version: u16,
+
     /// The information about the [`Location`] of a message in the source.
+    #[serde(alias = "l")]
     pub location: Location,
 
     /// The logging level, see [`slog::Level`].
+    #[serde(alias = "lvl")]
     pub level: String,
 
+    /// The logging level as a number, as returned by [`slog::Level::as_usize`].
+    ///
+    /// This ranges from 1 for `CRITICAL` to 6 for `TRACE`, so more severe levels have lower
+    /// numbers. It allows a DTrace predicate to select a range of levels without comparing
+    /// strings, for example `/strtoll(json(copyinstr(arg0), "ok.level_num")) <= 2/` for errors
+    /// and worse. When parsing a message without it, it is derived from [`Message::level`].
+    #[serde(default, alias = "lvln")]
+    pub level_num: u8,
+
+    /// The syslog severity of the message, as returned by [`syslog_severity`].
+    ///
+    /// This allows DTrace output forwarded to syslog or journald to keep the severity of each
+    /// message. When parsing a message without it, it is derived from [`Message::level`].
+    #[serde(default, alias = "sev")]
+    pub severity: u8,
+
     /// The timestamp at which the message was issued.
     ///
     /// As there may be latencies between a message's emission and consumption in DTrace, this can
     /// be useful.
-    pub timestamp: DateTime<Utc>,
+    ///
+    /// This is serialized according to [`Message::timestamp_format`] and
+    /// [`Message::timestamp_precision`], and may be parsed from either format, with any precision.
+    /// It is omitted when disabled with [`DtraceBuilder::timestamps`].
+    #[serde(default, alias = "ts", deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<DateTime<Utc>>,
+
+    /// The source of [`Message::timestamp`], if it isn't the system's realtime clock.
+    ///
+    /// This is omitted for the default clock, [`Utc::now`], so a message with a timestamp but
+    /// without this field was timestamped with the realtime clock. When a different clock is set
+    /// with [`DtraceBuilder::clock`], this is `"custom"`, or the name given with
+    /// [`DtraceBuilder::clock_source`], such as `"monotonic"`, so that consumers can tell how to
+    /// interpret the timestamp. It is omitted along with the timestamp. It doesn't depend on
+    /// [`Message::timestamp_format`], which only changes how the same time is written.
+    #[serde(default, alias = "cs")]
+    pub clock_source: Option<String>,
+
+    /// The time at which the message was issued, in nanoseconds since the drain was constructed.
+    ///
+    /// Unlike [`Message::timestamp`], this is derived from a monotonic clock, and so may be used
+    /// to accurately compute the interval between messages, even if the system clock is adjusted.
+    /// It is only comparable between messages from the same drain, within the lifetime of a
+    /// single process. It is only included when enabled with
+    /// [`DtraceBuilder::monotonic_timestamps`].
+    #[serde(default, alias = "mono")]
+    pub monotonic_ns: Option<u64>,
+
+    /// The time since the drain emitted its previous message, in nanoseconds.
+    ///
+    /// This is measured with a monotonic clock, and is absent from the first message. It is only
+    /// included when enabled with [`DtraceBuilder::message_deltas`].
+    #[serde(default, alias = "dt")]
+    pub delta_ns: Option<u64>,
 
     /// The string message emitted in the log entry.
+    #[serde(alias = "msg")]
     pub message: String,
 
     /// The key-value pairs in this log message, including those of parent loggers.
+    ///
+    /// Values wrapped in [`slog::Serde`] are emitted as their full JSON representation, so that
+    /// structured values can be addressed with the DTrace `json()` subroutine. For example,
+    /// `"config" => slog::Serde(config)` emits `{"config": {"port": 80, ...}}`, where
+    /// `config` implements [`serde::Serialize`] and `Clone`.
+    ///
+    /// Errors logged with slog's `#` syntax, e.g., `"error" => #e`, are emitted as an array of the
+    /// error's message followed by those of each of its [`source`](std::error::Error::source)s.
+    /// 128-bit integers are emitted as decimal strings, since JSON numbers can't represent them
+    /// exactly.
+    ///
+    /// A value which fails to serialize doesn't prevent the others from being emitted. If it can
+    /// be attributed to a key, as for a [`slog::Serde`] value which can't be represented as JSON,
+    /// the key's value is an object whose `__error` field describes the failure. Otherwise, a
+    /// `slog::Value` which returns an error stops the serialization of any later pairs from the
+    /// same record or logger, and the first such error is emitted under the key `__error`.
     pub kv: JsonMap,
-}
 
-/// `ProbeRegistration` stores the result of registering probes with the DTrace kernel module.
-///
-/// Though unlikely, it's possible that probe registration fails. This may happen, for example, if
-/// the DTrace kernel module is extremely low on memory. One may want to abort the application in
-/// this case, or one might decide that a running but degraded application is better than nothing i
-/// such a situation. The `ProbeRegistration` enum contains information about whether probes were
-/// successfully registered. The caller may decide how to handle such a case.
-#[allow(clippy::large_enum_variant)]
-#[derive(Debug)]
-pub enum ProbeRegistration {
-    Success,
-    Failed(String),
-}
+    /// The context of the drain which emitted the message, if any.
+    ///
+    /// This is set with [`DtraceBuilder::context`], and is the same for every message from a
+    /// drain. It is omitted when not set.
+    #[serde(default, alias = "ctx")]
+    pub context: Option<serde_json::Value>,
 
-impl ProbeRegistration {
-    /// Helper to check if the variant is `Success`.
-    pub fn is_success(&self) -> bool {
-        matches!(self, ProbeRegistration::Success)
-    }
-}
+    /// The [tag](slog::Record::tag) of the record, if it has one.
+    ///
+    /// Records are tagged with, for example, `info!(log, #"audit", "a message")`. This allows
+    /// messages to be filtered by tag in any probe, for example with
+    /// `/json(copyinstr(arg0), "ok.tag") == "audit"/`. It is only included when enabled with
+    /// [`DtraceBuilder::tags`], and is omitted for records without a tag.
+    #[serde(default)]
+    pub tag: Option<String>,
 
-/// A [`slog::Drain`] that forwards all log messages to DTrace.
-#[derive(Debug)]
-pub struct Dtrace<D> {
-    _phantom: std::marker::PhantomData<D>,
-}
+    /// A hash identifying the site in the source from which the message was issued.
+    ///
+    /// This is a stable hash of the module, file, and line of [`Message::location`], and of the
+    /// column of the logging statement, so that messages from the same site can be aggregated in
+    /// DTrace without hashing their text, for example with
+    /// `@[json(copyinstr(arg0), "ok.msg_hash")] = count()`. `slog` only passes the message to a
+    /// drain already formatted, so its format string can't be hashed, and the column instead tells
+    /// apart statements on the same line. Neither the formatted text of the message nor its
+    /// key-value pairs participate, so every message from a site has the same hash, whatever its
+    /// arguments. Messages from `tracing` and `log`, which don't record the column, are hashed
+    /// by their line alone. It is only included when enabled with
+    /// [`DtraceBuilder::message_hashes`].
+    #[serde(default, alias = "mh")]
+    pub msg_hash: Option<u64>,
 
-impl Dtrace<slog::Discard> {
-    /// Create a new DTrace logger, emitting messages only to DTrace.
+    /// The ID of the key-value pairs of the loggers which issued the message, if they are emitted
+    /// separately.
     ///
-    /// This method will create a `Dtrace` drain that sends messages _only_ to DTrace. If you wish
-    /// to emit messages to another location as well, you can use [`with_drain`] or call
-    /// [`slog::Duplicate`].
+    /// When enabled with [`DtraceBuilder::logger_kv_once`], the key-value pairs of the loggers are
+    /// omitted from [`Message::kv`], and emitted via the `logger` probe with this ID instead. It is
+    /// a hash of the pairs, so messages from loggers with the same pairs share an ID.
+    #[serde(default, alias = "lid")]
+    pub logger_id: Option<u64>,
+
+    /// An identifier for the thread which issued the message.
     ///
-    /// Note that it's possible for probe registration to fail. The result of registering is
-    /// returned as the second tuple element. It may be inspected so that callers can decide how to
-    /// handle failure. See [`ProbeRegistration`] for more information.
-    pub fn new() -> (Self, ProbeRegistration) {
-        let registration = match usdt::register_probes() {
-            Ok(_) => ProbeRegistration::Success,
-            Err(e) => ProbeRegistration::Failed(e.to_string()),
-        };
-        (
-            Self {
-                _phantom: std::marker::PhantomData,
-            },
-            registration,
-        )
-    }
-}
+    /// This is unique within the process, and is assigned the first time each thread issues a
+    /// message. Note that it is not the same as the `tid` variable in DTrace.
+    #[serde(default, alias = "tid")]
+    pub thread_id: u64,
 
-/// Combine the [`Dtrace`] drain with another drain.
-///
-/// This duplicates all log messages to `drain` and a new `Dtrace` drain.
-///
-/// Note that probe registration can fail, see [`ProbeRegistration`] and [`Dtrace::new`] for more
-/// information.
-pub fn with_drain<D>(drain: D) -> (slog::Duplicate<D, Dtrace<slog::Discard>>, ProbeRegistration)
-where
-    D: Drain,
-{
-    let (d, registration) = Dtrace::new();
-    (slog::Duplicate(drain, d), registration)
-}
+    /// The ID of the process which issued the message.
+    #[serde(default)]
+    pub pid: u32,
 
-// Create a message to emit to DTrace
-fn create_dtrace_message(record: &slog::Record, values: &slog::OwnedKVList) -> Message {
-    let location = Location {
-        module: record.module().to_string(),
-        file: record.file().to_string(),
-        line: record.line(),
-    };
-    let mut serializer = Serializer::default();
-    let kv = match record
-        .kv()
-        .serialize(record, &mut serializer)
-        .and_then(|_| values.serialize(record, &mut serializer))
-    {
-        Ok(()) => serializer.map,
-        Err(e) => {
-            let mut map = JsonMap::default();
-            let _ = map.insert(
-                String::from("err"),
-                serde_json::Value::from(format!("{}", e)),
-            );
-            map
-        }
-    };
-    let msg = Message {
-        location,
-        timestamp: Utc::now(),
-        level: record.level().as_str().to_string(),
-        message: record.msg().to_string(),
-        kv,
-    };
-    msg
-}
+    /// A random token identifying the drain which emitted the message.
+    ///
+    /// This distinguishes messages from different processes which happen to share a PID, such as
+    /// a service which has been restarted. It is only included when enabled with
+    /// [`DtraceBuilder::process_token`].
+    #[serde(default, alias = "tok")]
+    pub process_token: Option<u64>,
 
-impl<D> Drain for Dtrace<D>
-where
-    D: Drain<Ok = (), Err = slog::Never>,
-{
-    type Ok = ();
-    type Err = slog::Never;
+    /// The name of the host on which the message was issued.
+    ///
+    /// This distinguishes messages collected from many hosts. It is looked up once when the drain
+    /// is built, and is only included when enabled with [`DtraceBuilder::hostname`].
+    #[serde(default, alias = "host")]
+    pub hostname: Option<String>,
 
-    fn log(
-        &self,
-        record: &slog::Record<'_>,
-        values: &slog::OwnedKVList,
-    ) -> Result<Self::Ok, Self::Err> {
-        match record.level() {
-            slog::Level::Trace => probes::trace_!(|| create_dtrace_message(record, values)),
-            slog::Level::Debug => probes::debug_!(|| create_dtrace_message(record, values)),
-            slog::Level::Info => probes::info_!(|| create_dtrace_message(record, values)),
-            slog::Level::Warning => probes::warn_!(|| create_dtrace_message(record, values)),
-            slog::Level::Error => probes::error_!(|| create_dtrace_message(record, values)),
-            slog::Level::Critical => probes::critical_!(|| create_dtrace_message(record, values)),
+    /// The ID of the active OpenTelemetry trace when the message was issued, as a hex string.
+    ///
+    /// This allows messages to be joined with distributed traces. It is only included with the
+    /// `otel` feature enabled, when the current `opentelemetry::Context` has a valid span.
+    #[serde(default, alias = "trace")]
+    pub trace_id: Option<String>,
+
+    /// The ID of the active OpenTelemetry span when the message was issued, as a hex string.
+    ///
+    /// See [`Message::trace_id`] for details.
+    #[serde(default, alias = "span")]
+    pub span_id: Option<String>,
+
+    /// The name of the crate which issued the message, if its logger was created with
+    /// [`source!`].
+    #[serde(default, alias = "crate")]
+    pub source_crate: Option<String>,
+
+    /// The version of the crate which issued the message, if its logger was created with
+    /// [`source!`].
+    #[serde(default, alias = "ver")]
+    pub source_version: Option<String>,
+
+    /// The name of the thread which issued the message, if it has one.
+    ///
+    /// This is only included when enabled with [`DtraceBuilder::thread_names`].
+    #[serde(default, alias = "tname")]
+    pub thread_name: Option<String>,
+
+    /// A backtrace of the thread which issued the message, if one was captured.
+    ///
+    /// This is formatted as by [`std::backtrace::Backtrace`], with one or more lines per frame.
+    /// It is only captured for the levels set with [`DtraceBuilder::capture_backtrace_for`].
+    #[serde(default, alias = "bt")]
+    pub backtrace: Option<String>,
+
+    /// Set if the message was truncated to fit within a maximum size.
+    ///
+    /// See [`DtraceBuilder::max_message_bytes`] for details.
+    #[serde(default, alias = "trunc")]
+    pub truncated: bool,
+
+    /// Set if each `%` in the strings of the message is escaped as `%%`.
+    ///
+    /// See [`DtraceBuilder::printf_safe`] for details. Parsing a message with
+    /// [`Message::from_probe_line`] or [`Message::from_framed_json`] reverses the escaping, and
+    /// so always leaves this unset.
+    #[serde(default, alias = "pesc")]
+    pub printf_escaped: bool,
+
+    /// The rate at which messages were sampled, if sampling is enabled.
+    ///
+    /// Only one in this many messages at its level is emitted, so counts derived from the emitted
+    /// messages may be multiplied by this to estimate the true count. See
+    /// [`DtraceBuilder::sample_rate`] and [`DtraceBuilder::sample_rates`] for details.
+    #[serde(default, alias = "rate")]
+    pub sample_rate: Option<u64>,
+
+    /// The serialized length of the message before it was truncated, if it was.
+    #[serde(default, alias = "olen")]
+    pub original_len: Option<usize>,
+
+    /// The number of identical consecutive messages this one stands for, if it was coalesced.
+    ///
+    /// This includes the message itself. See [`DtraceBuilder::coalesce`] for details.
+    #[serde(default, alias = "rep")]
+    pub repeat_count: Option<u64>,
+
+    /// The scopes in which each key in [`Message::kv`] was set, in the order they were visited.
+    ///
+    /// For messages from the [`Dtrace`] drain, the scope is `record` for pairs given in the
+    /// logging statement itself, and `logger` for those of its logger or any of its ancestors,
+    /// since `slog` doesn't expose the boundaries between loggers. For events from the
+    /// `DtraceLayer`, the scope is the name of the span which set the field, or `event`. A key
+    /// set in more than one scope lists each of them. This is only included when enabled with
+    /// [`DtraceBuilder::key_scopes`].
+    #[serde(default, alias = "sc")]
+    pub scopes: Option<Scopes>,
+
+    /// Set if the message is serialized with short field names.
+    ///
+    /// For example, `location` is serialized as `l`, and `timestamp` as `ts`. This is set when
+    /// enabled with [`DtraceBuilder::compact_field_names`]. It is not itself serialized, and so is
+    /// always unset in parsed messages.
+    #[serde(skip)]
+    pub compact: bool,
+
+    /// The format in which [`Message::timestamp`] is serialized.
+    ///
+    /// This is set with [`DtraceBuilder::timestamp_format`]. It is not itself serialized, and so
+    /// is always the default in parsed messages.
+    #[serde(skip)]
+    pub timestamp_format: TimestampFormat,
+
+    /// The precision with which [`Message::timestamp`] is serialized as an RFC 3339 string.
+    ///
+    /// This is set with [`DtraceBuilder::timestamp_precision`]. It is not itself serialized, and
+    /// so is always the default in parsed messages.
+    #[serde(skip)]
+    pub timestamp_precision: TimestampPrecision,
+
+    /// Set if the keys of [`Message::kv`] are serialized in sorted order.
+    ///
+    /// Otherwise, keys are serialized in the iteration order of [`JsonMap`]. That is sorted by
+    /// default, but is the order of insertion if any crate in the build enables the
+    /// `preserve_order` feature of `serde_json`, so the same message may be serialized
+    /// differently by different builds. When set, the keys of every object, including those
+    /// nested in values, are sorted by their bytes regardless. This is set when enabled with
+    /// [`DtraceBuilder::sorted_keys`]. It is not itself serialized, and so is always unset in
+    /// parsed messages.
+    #[serde(skip)]
+    pub sorted_keys: bool,
+}
+
+/// The format in which the timestamp of a [`Message`] is serialized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// An RFC 3339 string, such as `"2021-10-19T17:55:55.260393409Z"`.
+    #[default]
+    Rfc3339,
+
+    /// An integer number of nanoseconds since the Unix epoch.
+    ///
+    /// This is more compact, and may be compared numerically in DTrace. Times which can't be
+    /// represented this way, before 1677 or after 2262, are serialized as RFC 3339 strings.
+    EpochNanos,
+}
+
+/// The precision of the fractional seconds of RFC 3339 timestamps, see
+/// [`DtraceBuilder::timestamp_precision`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampPrecision {
+    /// Whole seconds, such as `"2021-10-19T17:55:55Z"`.
+    Seconds,
+
+    /// Milliseconds, such as `"2021-10-19T17:55:55.260Z"`.
+    Millis,
+
+    /// Microseconds, such as `"2021-10-19T17:55:55.260393Z"`.
+    Micros,
+
+    /// Nanoseconds, such as `"2021-10-19T17:55:55.260393409Z"`.
+    ///
+    /// Unlike the others, this omits trailing groups of zeros: a time on a whole millisecond is
+    /// written with three digits, and one on a whole second with none.
+    #[default]
+    Nanos,
+}
+
+impl TimestampPrecision {
+    // Return the format of the seconds of a timestamp with this precision, or `None` for the
+    // default format of `chrono`.
+    fn seconds_format(self) -> Option<chrono::SecondsFormat> {
+        match self {
+            TimestampPrecision::Seconds => Some(chrono::SecondsFormat::Secs),
+            TimestampPrecision::Millis => Some(chrono::SecondsFormat::Millis),
+            TimestampPrecision::Micros => Some(chrono::SecondsFormat::Micros),
+            TimestampPrecision::Nanos => None,
         }
-        Ok(())
     }
 }
 
-// Type used to serialize slog's key-value pairs into JSON.
-#[derive(Debug, Clone, Default)]
-struct Serializer {
-    map: crate::JsonMap,
+// Deserialize a timestamp from either an RFC 3339 string or nanoseconds since the Unix epoch.
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Rfc3339(DateTime<Utc>),
+        EpochNanos(i64),
+    }
+
+    match Timestamp::deserialize(deserializer)? {
+        Timestamp::Rfc3339(timestamp) => Ok(Some(timestamp)),
+        Timestamp::EpochNanos(nanos) => Ok(Some(DateTime::from_timestamp_nanos(nanos))),
+    }
 }
 
-impl Serializer {
-    fn emit<T>(&mut self, key: slog::Key, value: T) -> slog::Result
+impl Serialize for Message {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
-        T: Into<serde_json::Value>,
+        S: serde::Serializer,
     {
-        self.map.insert(key.to_string(), value.into());
-        Ok(())
+        use serde::ser::SerializeStruct;
+
+        // Optional fields are omitted when unset, and the number of fields must be known up
+        // front for formats such as MessagePack.
+        let optional = [
+            self.timestamp.is_some(),
+            self.clock_source.is_some(),
+            self.monotonic_ns.is_some(),
+            self.delta_ns.is_some(),
+            self.context.is_some(),
+            self.logger_id.is_some(),
+            self.msg_hash.is_some(),
+            self.tag.is_some(),
+            self.process_token.is_some(),
+            self.hostname.is_some(),
+            self.trace_id.is_some(),
+            self.span_id.is_some(),
+            self.source_crate.is_some(),
+            self.source_version.is_some(),
+            self.thread_name.is_some(),
+            self.backtrace.is_some(),
+            self.truncated,
+            self.printf_escaped,
+            self.sample_rate.is_some(),
+            self.original_len.is_some(),
+            self.repeat_count.is_some(),
+            self.scopes.is_some(),
+        ];
+        let len = 9 + optional.iter().filter(|present| **present).count();
+        let name = |full, short| if self.compact { short } else { full };
+
+        let mut s = serializer.serialize_struct("Message", len)?;
+        s.serialize_field(name("schema_version", "sv"), &self.schema_version)?;
+        if self.compact {
+            s.serialize_field(
+                "l",
+                &CompactLocation {
+                    module: &self.location.module,
+                    file: &self.location.file,
+                    line: self.location.line,
+                    function: self.location.function.as_deref(),
+                },
+            )?;
+        } else {
+            s.serialize_field("location", &self.location)?;
+        }
+        s.serialize_field(name("level", "lvl"), &self.level)?;
+        s.serialize_field(name("level_num", "lvln"), &self.level_num)?;
+        s.serialize_field(name("severity", "sev"), &self.severity)?;
+        if let Some(timestamp) = &self.timestamp {
+            match (self.timestamp_format, timestamp.timestamp_nanos_opt()) {
+                (TimestampFormat::EpochNanos, Some(nanos)) => {
+                    s.serialize_field(name("timestamp", "ts"), &nanos)?
+                }
+                _ => match self.timestamp_precision.seconds_format() {
+                    Some(format) => s.serialize_field(
+                        name("timestamp", "ts"),
+                        &timestamp.to_rfc3339_opts(format, true),
+                    )?,
+                    None => s.serialize_field(name("timestamp", "ts"), timestamp)?,
+                },
+            }
+        }
+        if let Some(clock_source) = &self.clock_source {
+            s.serialize_field(name("clock_source", "cs"), clock_source)?;
+        }
+        if let Some(monotonic_ns) = self.monotonic_ns {
+            s.serialize_field(name("monotonic_ns", "mono"), &monotonic_ns)?;
+        }
+        if let Some(delta_ns) = self.delta_ns {
+            s.serialize_field(name("delta_ns", "dt"), &delta_ns)?;
+        }
+        s.serialize_field(name("message", "msg"), &self.message)?;
+        if let Some(tag) = &self.tag {
+            s.serialize_field("tag", tag)?;
+        }
+        if self.sorted_keys {
+            s.serialize_field("kv", &SortedMap(&self.kv))?;
+        } else {
+            s.serialize_field("kv", &self.kv)?;
+        }
+        if let Some(msg_hash) = self.msg_hash {
+            s.serialize_field(name("msg_hash", "mh"), &msg_hash)?;
+        }
+        if let Some(logger_id) = self.logger_id {
+            s.serialize_field(name("logger_id", "lid"), &logger_id)?;
+        }
+        if let Some(context) = &self.context {
+            if self.sorted_keys {
+                s.serialize_field(name("context", "ctx"), &SortedValue(context))?;
+            } else {
+                s.serialize_field(name("context", "ctx"), context)?;
+            }
+        }
+        s.serialize_field(name("thread_id", "tid"), &self.thread_id)?;
+        s.serialize_field("pid", &self.pid)?;
+        if let Some(process_token) = self.process_token {
+            s.serialize_field(name("process_token", "tok"), &process_token)?;
+        }
+        if let Some(hostname) = &self.hostname {
+            s.serialize_field(name("hostname", "host"), hostname)?;
+        }
+        if let Some(trace_id) = &self.trace_id {
+            s.serialize_field(name("trace_id", "trace"), trace_id)?;
+        }
+        if let Some(span_id) = &self.span_id {
+            s.serialize_field(name("span_id", "span"), span_id)?;
+        }
+        if let Some(source_crate) = &self.source_crate {
+            s.serialize_field(name("source_crate", "crate"), source_crate)?;
+        }
+        if let Some(source_version) = &self.source_version {
+            s.serialize_field(name("source_version", "ver"), source_version)?;
+        }
+        if let Some(thread_name) = &self.thread_name {
+            s.serialize_field(name("thread_name", "tname"), thread_name)?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            s.serialize_field(name("backtrace", "bt"), backtrace)?;
+        }
+        if self.truncated {
+            s.serialize_field(name("truncated", "trunc"), &self.truncated)?;
+        }
+        if self.printf_escaped {
+            s.serialize_field(name("printf_escaped", "pesc"), &self.printf_escaped)?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            s.serialize_field(name("sample_rate", "rate"), &sample_rate)?;
+        }
+        if let Some(original_len) = self.original_len {
+            s.serialize_field(name("original_len", "olen"), &original_len)?;
+        }
+        if let Some(repeat_count) = self.repeat_count {
+            s.serialize_field(name("repeat_count", "rep"), &repeat_count)?;
+        }
+        if let Some(scopes) = &self.scopes {
+            s.serialize_field(name("scopes", "sc"), scopes)?;
+        }
+        s.end()
     }
 }
 
-macro_rules! impl_emit {
-    ($method:ident, $ty:ty) => {
-        fn $method(&mut self, key: slog::Key, value: $ty) -> slog::Result {
-            self.emit(key, value).unwrap();
-            Ok(())
+impl Message {
+    /// Create a new `Message`, timestamped with the current time.
+    ///
+    /// The remaining fields are empty or unset, and may be assigned directly. For example:
+    ///
+    /// ```
+    /// use slog_dtrace::{Location, Message};
+    ///
+    /// let mut msg = Message::new(
+    ///     Location::new("my_crate", "src/lib.rs", 1),
+    ///     slog::Level::Info,
+    ///     "a message",
+    /// );
+    /// msg.kv.insert(String::from("key"), "value".into());
+    /// ```
+    pub fn new(location: Location, level: slog::Level, message: impl Into<String>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            location,
+            level: level.as_str().to_string(),
+            level_num: level.as_usize() as u8,
+            severity: syslog_severity(level),
+            timestamp: Some(Utc::now()),
+            monotonic_ns: None,
+            delta_ns: None,
+            message: message.into(),
+            kv: JsonMap::default(),
+            thread_id: 0,
+            pid: 0,
+            process_token: None,
+            hostname: None,
+            trace_id: None,
+            span_id: None,
+            source_crate: None,
+            source_version: None,
+            context: None,
+            logger_id: None,
+            msg_hash: None,
+            tag: None,
+            thread_name: None,
+            backtrace: None,
+            truncated: false,
+            printf_escaped: false,
+            sample_rate: None,
+            original_len: None,
+            repeat_count: None,
+            clock_source: None,
+            scopes: None,
+            compact: false,
+            timestamp_format: TimestampFormat::default(),
+            timestamp_precision: TimestampPrecision::default(),
+            sorted_keys: false,
         }
-    };
+    }
+
+    /// Return the string emitted to DTrace for this message.
+    ///
+    /// This is the same string that the level probes pass as their first argument when the
+    /// [`Format`] is JSON, without the terminating NUL byte: the message as JSON, wrapped in an
+    /// object with the key `"ok"`, or `"err"` if it could not be serialized. It can be parsed
+    /// with [`Message::from_probe_line`].
+    pub fn to_probe_json(&self) -> String {
+        match usdt::to_json(self) {
+            Ok(json) => format!("{{\"ok\":{}}}", json),
+            Err(e) => format!("{{\"err\":\"{}\"}}", e),
+        }
+    }
+
+    /// Return the string emitted to DTrace for this message with [`Format::BareJson`].
+    ///
+    /// This is the message as JSON, without the wrapper of [`Message::to_probe_json`]. If the
+    /// message could not be serialized, it is `{"err": ...}` with the error, as for that method.
+    pub fn to_bare_json(&self) -> String {
+        match usdt::to_json(self) {
+            Ok(json) => json,
+            Err(e) => format!("{{\"err\":\"{}\"}}", e),
+        }
+    }
+
+    /// Format this message as a line of tab-separated values, as emitted with [`Format::Tsv`].
+    ///
+    /// The columns are the timestamp, or an empty string if there is none, the level, the module
+    /// and line as `module:line`, and the text of the message. Each key-value pair follows in a
+    /// column of its own, as `key=value`, with string values as they are and others as JSON. The
+    /// line has no terminating newline.
+    ///
+    /// Within each column, a backslash is escaped as `\\`, a tab as `\t`, a newline as `\n`,
+    /// and a carriage return as `\r`, so that columns and lines can be split unambiguously. An
+    /// `=` in a key is also escaped as `\=`, so that each pair can be split at its first
+    /// unescaped `=`.
+    ///
+    /// ```
+    /// use slog_dtrace::{Location, Message};
+    ///
+    /// let mut msg = Message::new(Location::new("app", "src/main.rs", 7), slog::Level::Info, "hi");
+    /// msg.timestamp = None;
+    /// msg.kv.insert(String::from("path"), "a\tb".into());
+    /// msg.kv.insert(String::from("n"), 1.into());
+    /// assert_eq!(msg.to_tsv(), "\tINFO\tapp:7\thi\tn=1\tpath=a\\tb");
+    /// ```
+    pub fn to_tsv(&self) -> String {
+        let mut line = String::new();
+        if let Some(timestamp) = &self.timestamp {
+            match (self.timestamp_format, timestamp.timestamp_nanos_opt()) {
+                (TimestampFormat::EpochNanos, Some(nanos)) => line.push_str(&nanos.to_string()),
+                _ => {
+                    let format = self
+                        .timestamp_precision
+                        .seconds_format()
+                        .unwrap_or(chrono::SecondsFormat::Nanos);
+                    line.push_str(&timestamp.to_rfc3339_opts(format, true))
+                }
+            }
+        }
+        line.push('\t');
+        line.push_str(&self.level);
+        line.push('\t');
+        push_tsv_escaped(&mut line, &self.location.module, false);
+        line.push(':');
+        line.push_str(&self.location.line.to_string());
+        line.push('\t');
+        push_tsv_escaped(&mut line, &self.message, false);
+        let mut pairs: Vec<_> = self.kv.iter().collect();
+        if self.sorted_keys {
+            pairs.sort_unstable_by_key(|(key, _)| *key);
+        }
+        for (key, value) in pairs {
+            line.push('\t');
+            push_tsv_escaped(&mut line, key, true);
+            line.push('=');
+            match value {
+                serde_json::Value::String(value) => push_tsv_escaped(&mut line, value, false),
+                value => push_tsv_escaped(&mut line, &value.to_string(), false),
+            }
+        }
+        line
+    }
+
+    /// Parse a `Message` from a line of DTrace output.
+    ///
+    /// The probes emit each message as JSON, wrapped in an object with a single key, `"ok"`,
+    /// or `"err"` if the message could not be serialized. This parses a line printed with, for
+    /// example, `printf("%s\n", copyinstr(arg0))`, removing that wrapper. Messages emitted
+    /// without the wrapper, with [`Format::BareJson`], are parsed as they are.
+    pub fn from_probe_line(line: &str) -> Result<Message, ParseError> {
+        Self::from_probe_result(serde_json::from_str(line.trim()))
+    }
+
+    /// Return the frame emitted for this message with [`Format::FramedJson`].
+    ///
+    /// This is [`Message::to_probe_json`], prefixed with its length as a 4-byte big-endian
+    /// integer.
+    pub fn to_framed_json(&self) -> Vec<u8> {
+        let json = self.to_probe_json();
+        let len = u32::try_from(json.len()).unwrap_or(u32::MAX);
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + json.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(json.as_bytes());
+        frame
+    }
+
+    /// Parse a `Message` from the start of `buf`, which holds frames emitted with
+    /// [`Format::FramedJson`].
+    ///
+    /// This returns the message and the length of its frame, so that frames written back to back
+    /// can be decoded in turn. [`ParseError::Truncated`] is returned if `buf` ends before the
+    /// frame does, and [`ParseError::Json`] if the payload is not valid UTF-8 or not a message.
+    ///
+    /// ```
+    /// use slog_dtrace::{Location, Message};
+    ///
+    /// let msg = Message::new(Location::new("app", "src/main.rs", 7), slog::Level::Info, "a\nb");
+    /// let mut buf = msg.to_framed_json();
+    /// buf.extend(msg.to_framed_json());
+    ///
+    /// let mut rest = &buf[..];
+    /// while !rest.is_empty() {
+    ///     let (parsed, len) = Message::from_framed_json(rest).unwrap();
+    ///     assert_eq!(parsed.message, "a\nb");
+    ///     rest = &rest[len..];
+    /// }
+    /// ```
+    pub fn from_framed_json(buf: &[u8]) -> Result<(Message, usize), ParseError> {
+        let header = buf.get(..FRAME_HEADER_LEN).ok_or(ParseError::Truncated)?;
+        let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        let end = FRAME_HEADER_LEN.saturating_add(len);
+        let payload = buf
+            .get(FRAME_HEADER_LEN..end)
+            .ok_or(ParseError::Truncated)?;
+        Self::from_probe_result(serde_json::from_slice(payload)).map(|msg| (msg, end))
+    }
+
+    // Unwrap a message parsed from the output of a probe, filling in any fields it lacks.
+    fn from_probe_result(result: serde_json::Result<Payload>) -> Result<Message, ParseError> {
+        match result {
+            Ok(Payload::Wrapped(ProbeResult::Ok(mut msg)) | Payload::Bare(mut msg)) => {
+                if let Ok(level) = msg.level.parse::<slog::Level>() {
+                    if msg.level_num == 0 {
+                        msg.level_num = level.as_usize() as u8;
+                    }
+                    if msg.severity == 0 {
+                        msg.severity = syslog_severity(level);
+                    }
+                }
+                if msg.printf_escaped {
+                    map_message_strings(&mut msg, |s| s.replace("%%", "%"));
+                    msg.printf_escaped = false;
+                }
+                Ok(msg)
+            }
+            Ok(Payload::Wrapped(ProbeResult::Err(e))) => Err(ParseError::Serialization(e)),
+            Err(e) if e.is_eof() => Err(ParseError::Truncated),
+            Err(e) => Err(ParseError::Json(e)),
+        }
+    }
 }
 
-impl slog::Serializer for Serializer {
-    fn emit_arguments(&mut self, key: slog::Key, values: &std::fmt::Arguments<'_>) -> slog::Result {
-        self.map
-            .insert(key.to_string(), format!("{}", values).into());
-        Ok(())
+// A message emitted by the probes, either in its result-like wrapper, or bare.
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Wrapped(ProbeResult),
+    Bare(Message),
+}
+
+// The result-like wrapper around each message emitted by the probes.
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ProbeResult {
+    Ok(Message),
+    Err(String),
+}
+
+/// An error parsing a [`Message`] from DTrace output.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The line ended before the message did.
+    ///
+    /// This usually means the message was larger than DTrace's `strsize`. See
+    /// [`DtraceBuilder::max_message_bytes`] for a way to avoid this.
+    Truncated,
+
+    /// The message could not be serialized in the process which emitted it.
+    Serialization(String),
+
+    /// The line is not a valid message.
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated => write!(f, "message is truncated"),
+            ParseError::Serialization(e) => write!(f, "failed to serialize message: {}", e),
+            ParseError::Json(e) => write!(f, "invalid message: {}", e),
+        }
     }
+}
 
-    impl_emit!(emit_u8, u8);
-    impl_emit!(emit_u16, u16);
-    impl_emit!(emit_u32, u32);
-    impl_emit!(emit_u64, u64);
-    impl_emit!(emit_i8, i8);
-    impl_emit!(emit_i16, i16);
-    impl_emit!(emit_i32, i32);
-    impl_emit!(emit_i64, i64);
-    impl_emit!(emit_isize, isize);
-    impl_emit!(emit_usize, usize);
-    impl_emit!(emit_bool, bool);
-    impl_emit!(emit_f32, f32);
-    impl_emit!(emit_f64, f64);
-    impl_emit!(emit_str, &str);
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-    fn emit_unit(&mut self, key: slog::Key) -> slog::Result {
-        self.emit(key, ())
+/// `ProbeRegistration` stores the result of registering probes with the DTrace kernel module.
+///
+/// Though unlikely, it's possible that probe registration fails. This may happen, for example, if
+/// the DTrace kernel module is extremely low on memory. One may want to abort the application in
+/// this case, or one might decide that a running but degraded application is better than nothing i
+/// such a situation. The `ProbeRegistration` enum contains information about whether probes were
+/// successfully registered, and if not, a [`RegistrationError`] describing why. The caller may
+/// decide how to handle such a case.
+///
+/// Registration is all-or-nothing: `usdt` registers every probe in the process with the kernel in
+/// a single operation, so it's not possible for the probes of some levels to be registered while
+/// others are not. `Success` therefore means that the probes for all levels are active, and
+/// `Failed` that none of them are. There is no partial result to report.
+///
+/// Probes are registered for the lifetime of the process: `usdt` has no way to unregister them,
+/// so they remain registered after every drain is dropped. Instead, they are registered only once.
+/// After the first success, building further drains returns `Success` without registering again,
+/// so a process may create and drop any number of drains, for example in tests, without
+/// accumulating registrations. After a failure, the next drain built tries again.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum ProbeRegistration {
+    Success,
+    Failed(RegistrationError),
+}
+
+impl ProbeRegistration {
+    /// Helper to check if the variant is `Success`.
+    pub fn is_success(&self) -> bool {
+        matches!(self, ProbeRegistration::Success)
     }
+}
 
-    fn emit_none(&mut self, key: slog::Key) -> slog::Result {
-        self.map.insert(key.to_string(), serde_json::Value::Null);
-        Ok(())
+/// The reason that probe registration failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationError {
+    /// DTrace is not available on this system, for example because the helper device is missing.
+    Unsupported,
+
+    /// The probes have already been registered with the kernel.
+    AlreadyRegistered,
+
+    /// The kernel or DTrace itself failed to register the probes, with the contained reason.
+    Kernel(String),
+}
+
+impl std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistrationError::Unsupported => write!(f, "DTrace is not supported on this system"),
+            RegistrationError::AlreadyRegistered => write!(f, "probes are already registered"),
+            RegistrationError::Kernel(e) => write!(f, "failed to register probes: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+impl RegistrationError {
+    // Classify an error from `usdt`, without exposing its type in our public API.
+    fn from_usdt(e: usdt::Error) -> Self {
+        match e {
+            usdt::Error::IO(ref err) => match err.kind() {
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::Unsupported => {
+                    RegistrationError::Unsupported
+                }
+                std::io::ErrorKind::AlreadyExists => RegistrationError::AlreadyRegistered,
+                _ => RegistrationError::Kernel(e.to_string()),
+            },
+            _ => RegistrationError::Kernel(e.to_string()),
+        }
+    }
+}
+
+/// A [`slog::Drain`] that forwards all log messages to DTrace.
+///
+/// Messages are emitted via the probes of the provider `P`, which defaults to [`Slog`].
+///
+/// Messages with a [tag](slog::Record::tag), such as those logged with
+/// `info!(log, #"audit", "a message")`, are also emitted via the `tagged` probe, in addition to
+/// the probe for their level. Its first argument is the tag, and its second the message as JSON,
+/// regardless of the [`Format`]. This allows tracing messages in a category at all levels, with
+/// `dtrace -n 'slog*:::tagged /copyinstr(arg0) == "audit"/ { ... }'`, for example.
+///
+/// Messages are only serialized if the probe for their level is enabled. Expensive values can be
+/// deferred with [`slog::FnValue`], whose closure is only called when the message is serialized,
+/// as in the `lazy` example, or skipped altogether with [`Dtrace::level_enabled`]. Note that other
+/// drains which accept the message, such as those given to [`with_drain`], still serialize it.
+///
+/// Probes fire synchronously, within the call to `log`, so there is nothing to flush when a
+/// `Dtrace` drain is dropped. When messages must not be lost, note that wrapping it in
+/// `slog_async` queues messages which are dropped at exit unless its guard is dropped first. The
+/// [`AsyncDtrace`] drain can instead be flushed explicitly.
+///
+/// Every `Dtrace` drain built while the [`DISABLE_ENV_VAR`] environment variable is set discards
+/// all messages, regardless of how it is configured.
+#[derive(Debug)]
+pub struct Dtrace<D, P = Slog> {
+    config: Config,
+    counters: Arc<LevelCounters>,
+    noop: bool,
+    // Whether probes have been registered, and a lock serializing attempts to register them.
+    registered: AtomicBool,
+    registering: Mutex<()>,
+    recent: Option<Arc<RecentMessages>>,
+    coalescer: Option<Coalescer>,
+    stats: Option<StatsTimer>,
+    _phantom: std::marker::PhantomData<(D, fn() -> P)>,
+}
+
+impl Dtrace<slog::Discard> {
+    /// Create a new DTrace logger, emitting messages only to DTrace.
+    ///
+    /// This method will create a `Dtrace` drain that sends messages _only_ to DTrace. If you wish
+    /// to emit messages to another location as well, you can use [`with_drain`], which returns a
+    /// [`DtraceDuplicate`].
+    ///
+    /// Note that it's possible for probe registration to fail. The result of registering is
+    /// returned as the second tuple element. It may be inspected so that callers can decide how to
+    /// handle failure. See [`ProbeRegistration`] for more information.
+    pub fn new() -> (Self, ProbeRegistration) {
+        DtraceBuilder::new().build()
+    }
+
+    /// Create a `Dtrace` drain that discards all messages, without registering any probes.
+    ///
+    /// The returned drain does nothing in its [`Drain::log`] method, not even constructing a
+    /// [`Message`] or updating its [`counters`](Dtrace::counters). This is what [`with_drain`]
+    /// returns on platforms without DTrace, see [`is_supported`].
+    pub fn noop() -> Self {
+        Self::from_config(Config::default(), true, true)
+    }
+
+    /// Create a new DTrace logger, without registering probes.
+    ///
+    /// See [`DtraceBuilder::build_lazy`] for details.
+    pub fn new_lazy() -> Self {
+        DtraceBuilder::new().build_lazy()
+    }
+}
+
+impl<D, P> Dtrace<D, P> {
+    /// Return the counts of messages handled by this drain, see [`LevelCounters`].
+    pub fn counters(&self) -> Arc<LevelCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    // Log the queued warnings about slow messages to `drain`, or to the logger given to
+    // `DtraceBuilder::slow_message_logger`, see `DtraceBuilder::warn_slow_messages`.
+    fn log_slow_warnings<E>(&self, drain: &E)
+    where
+        E: Drain,
+    {
+        if let Some(slow) = &self.config.slow_messages {
+            for site in slow.take() {
+                match &self.config.slow_message_logger {
+                    Some(logger) => site.warn(slow.threshold, logger),
+                    None => site.warn(slow.threshold, drain),
+                }
+            }
+        }
+    }
+
+    /// Return the most recent messages handled by this drain, from the oldest to the newest.
+    ///
+    /// This is empty unless enabled with [`DtraceBuilder::recent_messages`].
+    pub fn recent(&self) -> Vec<Message> {
+        self.recent
+            .as_ref()
+            .map(|recent| recent.messages())
+            .unwrap_or_default()
+    }
+
+    /// Return a handle to the most recent messages handled by this drain, if enabled with
+    /// [`DtraceBuilder::recent_messages`].
+    ///
+    /// This may be kept once the drain has been moved into a logger, e.g., to print the messages
+    /// from a panic hook. See also [`Dtrace::recent`].
+    pub fn recent_messages(&self) -> Option<Arc<RecentMessages>> {
+        self.recent.clone()
+    }
+
+    /// Turn forwarding to DTrace on or off, see [`VerboseGate`].
+    ///
+    /// This also affects any other drains built from the same builder.
+    pub fn set_verbose(&self, verbose: bool) {
+        self.config.verbose.set_verbose(verbose);
+    }
+
+    /// Return `true` if this drain is forwarding messages, see [`VerboseGate`].
+    pub fn is_verbose(&self) -> bool {
+        self.config.verbose.is_verbose()
+    }
+
+    /// Return `true` if this drain discards all messages, see [`Dtrace::noop`].
+    pub fn is_noop(&self) -> bool {
+        self.noop
+    }
+
+    /// Register probes with DTrace, if they haven't been already.
+    ///
+    /// A drain built with [`DtraceBuilder::build_lazy`] discards all messages until this
+    /// succeeds, and it may be called again after a failure to retry. Other drains register probes
+    /// when they are built, so this returns [`ProbeRegistration::Success`] without registering
+    /// again, unless that failed, in which case they also discard all messages until this
+    /// succeeds. Note that `usdt` registers the probes of every provider in the process at once, so
+    /// this also registers those of any other drains.
+    ///
+    /// This may be called from several threads at once. Attempts to register are serialized, and
+    /// once one succeeds, the others return `Success` without registering again. Messages logged
+    /// while registration is in progress are discarded. To call this after the drain has been
+    /// moved into a logger, wrap it in an `Arc`, which also implements [`Drain`], and keep a clone.
+    pub fn register_probes_now(&self) -> ProbeRegistration
+    where
+        P: Provider,
+    {
+        let _guard = self.registering.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_registered() {
+            return ProbeRegistration::Success;
+        }
+        let registration = register_probes::<P>(&self.config);
+        if registration.is_success() {
+            self.registered.store(true, Ordering::Release);
+        }
+        registration
+    }
+
+    /// Return `true` if anyone is tracing the messages at `level` emitted by this drain.
+    ///
+    /// `usdt` can only tell whether a probe is enabled by firing it, which would emit a message,
+    /// so each level has a gate probe for this check, such as `trace_gate`, in the same way as
+    /// the `gate` probe of [`Dtrace::any_enabled`]. It takes no arguments, and firing it does
+    /// nothing but test whether it is enabled, which is a single load when it isn't. So this is
+    /// cheap, and may be used to skip building an expensive value for a message nobody would
+    /// see. Other drains which receive the message may still want it, though.
+    ///
+    /// DTrace enables each probe separately, so a D clause for the messages at a level should
+    /// also match its gate: `slog*:::trace*` matches both `trace` and `trace_gate`, for example,
+    /// but `slog*:::trace` matches only the former. This also returns `false` if probes aren't
+    /// [registered](Dtrace::is_registered), if this is a [no-op](Dtrace::noop) drain, if its
+    /// [`VerboseGate`] is closed, or if `level` is excluded with [`DtraceBuilder::levels`] or
+    /// [`DtraceBuilder::min_level`], including on platforms without DTrace, where the gates can
+    /// never be enabled.
+    ///
+    /// ```
+    /// use slog::{o, trace, Logger};
+    /// use slog_dtrace::Dtrace;
+    /// use std::sync::Arc;
+    ///
+    /// let (drain, _) = Dtrace::new();
+    /// let drain = Arc::new(drain);
+    /// let log = Logger::root(Arc::clone(&drain), o!());
+    /// if drain.level_enabled(slog::Level::Trace) {
+    ///     let state = format!("{:?}", (0..1000).collect::<Vec<_>>());
+    ///     trace!(log, "expensive state"; "state" => state);
+    /// }
+    /// ```
+    pub fn level_enabled(&self, level: slog::Level) -> bool
+    where
+        P: Provider,
+    {
+        !self.noop
+            && self.is_registered()
+            && self.is_verbose()
+            && self
+                .config
+                .levels
+                .as_ref()
+                .is_none_or(|levels| levels.contains(&level))
+            && P::level_gate_enabled(level)
+    }
+
+    /// Return `true` if anyone is tracing this drain's provider.
+    ///
+    /// This is an alias of [`Dtrace::any_enabled`], which asks `usdt` whether the provider's
+    /// `gate` probe is enabled, and so is cheap enough to guard the construction of expensive
+    /// messages or loggers, as in the `lazy` example. Note that it shadows [`Drain::is_enabled`],
+    /// which may still be called as `Drain::is_enabled(&drain, level)`.
+    pub fn is_enabled(&self) -> bool
+    where
+        P: Provider,
+    {
+        self.any_enabled()
+    }
+
+    /// Return `true` if anyone is tracing this drain's provider, as shown by its `gate` probe.
+    ///
+    /// The `gate` probe exists only for this check: it takes no arguments, and firing it does
+    /// nothing but test whether it is enabled, which is a single load when it isn't. So this is
+    /// cheaper than any message, and may be used to skip building the arguments of a message,
+    /// or a whole [`slog::Logger`] with its key-value pairs, when nobody is watching.
+    ///
+    /// DTrace enables each probe separately, so the `gate` probe is enabled by any D clause whose
+    /// description matches it, such as `slog*:::` for all probes of the provider, but not by one
+    /// naming only the probes of some levels. A script enabling those should also enable the
+    /// gate, with an empty clause such as `slog*:::gate {}`. Conversely, an enabled gate doesn't
+    /// mean that the probe for any particular level is enabled, which
+    /// [`Dtrace::level_enabled`] tells. This also returns `false` if probes aren't registered, if
+    /// this is a no-op drain, or if its [`VerboseGate`] is closed, including on platforms without
+    /// DTrace, where the probe can never be enabled. See the `gate` example.
+    ///
+    /// ```
+    /// use slog::{info, o, Logger};
+    /// use slog_dtrace::Dtrace;
+    /// use std::sync::Arc;
+    ///
+    /// let (drain, _) = Dtrace::new();
+    /// let drain = Arc::new(drain);
+    /// let log = Logger::root(Arc::clone(&drain), o!());
+    /// if drain.any_enabled() {
+    ///     let log = log.new(o!("state" => format!("{:?}", (0..1000).collect::<Vec<_>>())));
+    ///     info!(log, "handling request");
+    /// }
+    /// ```
+    pub fn any_enabled(&self) -> bool
+    where
+        P: Provider,
+    {
+        !self.noop && self.is_registered() && self.is_verbose() && P::gate_enabled()
+    }
+
+    /// Emit a synthetic message with the text `text` through the probe for `level`.
+    ///
+    /// This allows confirming that a DTrace script sees messages from the process, without
+    /// waiting for a real one to be logged. The message bypasses `slog` entirely: it is emitted
+    /// even if `level` is excluded with [`DtraceBuilder::levels`] or [`DtraceBuilder::min_level`],
+    /// or by sampling or module filters, though not by a [no-op](Dtrace::noop) drain or one whose
+    /// probes aren't yet registered. It is emitted in the configured [`Format`], with the location
+    /// of the caller, and the single key [`TEST_KEY`] set to `true`.
+    ///
+    /// ```
+    /// use slog_dtrace::Dtrace;
+    ///
+    /// let (drain, _) = Dtrace::new();
+    /// drain.emit_test_message(slog::Level::Info, "ping");
+    /// ```
+    #[track_caller]
+    pub fn emit_test_message(&self, level: slog::Level, text: &str)
+    where
+        P: Provider,
+    {
+        if self.noop || !self.is_registered() {
+            return;
+        }
+        let caller = std::panic::Location::caller();
+        let f = || {
+            let location = Location::new("", caller.file(), caller.line());
+            let mut kv = JsonMap::default();
+            kv.insert(String::from(TEST_KEY), true.into());
+            let msg = start_message(
+                &self.config,
+                location,
+                caller.column(),
+                level,
+                text.to_string(),
+                kv,
+            );
+            finish_message(&self.config, msg)
+        };
+        fire_probe::<P, _>(&self.config, &self.counters, level, None, f);
+    }
+
+    /// Return `true` if probes have been registered for this drain, see
+    /// [`Dtrace::register_probes_now`].
+    pub fn is_registered(&self) -> bool {
+        self.registered.load(Ordering::Acquire)
+    }
+
+    // Create a drain from `config`, given whether its probes have been registered.
+    fn from_config(config: Config, noop: bool, registered: bool) -> Self {
+        let recent = (config.recent_messages > 0)
+            .then(|| Arc::new(RecentMessages::new(config.recent_messages)));
+        let coalescer = config.coalesce.map(Coalescer::new);
+        let stats = config.stats_interval.map(StatsTimer::new);
+        Self {
+            config,
+            counters: Arc::default(),
+            noop: noop || disabled_by_env(std::env::var_os(DISABLE_ENV_VAR)),
+            registered: AtomicBool::new(registered),
+            registering: Mutex::new(()),
+            recent,
+            coalescer,
+            stats,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+/// Counts of the messages handled by a [`Dtrace`] drain at each level.
+///
+/// These are a cheap way to verify that messages are reaching the drain, without attaching DTrace
+/// to the process.
+#[derive(Debug, Default)]
+pub struct LevelCounters {
+    total: [AtomicU64; 6],
+    fired: [AtomicU64; 6],
+    sampled_out: [AtomicU64; 6],
+    dropped: [AtomicU64; 6],
+    truncated: [AtomicU64; 6],
+    coalesced: [AtomicU64; 6],
+}
+
+impl LevelCounters {
+    /// Return the number of messages at `level` passed to the drain.
+    pub fn total(&self, level: slog::Level) -> u64 {
+        self.total[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages at `level` emitted to DTrace.
+    ///
+    /// This only includes messages for which the corresponding probe was enabled.
+    pub fn fired(&self, level: slog::Level) -> u64 {
+        self.fired[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages at `level` dropped due to sampling.
+    ///
+    /// See [`DtraceBuilder::sample_rate`] and [`DtraceBuilder::sample_rates`] for details.
+    pub fn sampled_out(&self, level: slog::Level) -> u64 {
+        self.sampled_out[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages at `level` dropped because a queue was full.
+    ///
+    /// See [`DtraceBuilder::build_async`] for details.
+    pub fn dropped(&self, level: slog::Level) -> u64 {
+        self.dropped[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages at `level` emitted to DTrace with [`Message::truncated`] set.
+    ///
+    /// Messages are only constructed, and so can only be truncated, when they are emitted. See
+    /// [`DtraceBuilder::max_message_bytes`] and [`DtraceBuilder::max_serialized_bytes`] for
+    /// details.
+    pub fn truncated(&self, level: slog::Level) -> u64 {
+        self.truncated[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages at `level` held back as repeats of the previous one.
+    ///
+    /// See [`DtraceBuilder::coalesce`] for details.
+    pub fn coalesced(&self, level: slog::Level) -> u64 {
+        self.coalesced[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// Return the number of messages degraded in each way, at all levels.
+    pub fn degradation(&self) -> Degradation {
+        let sum = |counts: &[AtomicU64; 6]| {
+            counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .sum()
+        };
+        Degradation {
+            truncated: sum(&self.truncated),
+            dropped: sum(&self.dropped),
+            sampled_out: sum(&self.sampled_out),
+            coalesced: sum(&self.coalesced),
+        }
+    }
+
+    fn index(level: slog::Level) -> usize {
+        level.as_usize() - 1
+    }
+
+    // Record a message at `level`, returning the number previously recorded.
+    fn record_total(&self, level: slog::Level) -> u64 {
+        self.total[Self::index(level)].fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn record_sampled_out(&self, level: slog::Level) {
+        self.sampled_out[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dropped(&self, level: slog::Level) {
+        self.dropped[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_fired(&self, level: slog::Level) {
+        self.fired[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_truncated(&self, level: slog::Level) {
+        self.truncated[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_coalesced(&self, level: slog::Level) {
+        self.coalesced[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The number of messages a drain has degraded in each way, at all levels.
+///
+/// This is returned by [`LevelCounters::degradation`], and emitted as JSON via the `stats` probe,
+/// see [`DtraceBuilder::stats_interval`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Degradation {
+    /// The number of messages emitted with [`Message::truncated`] set.
+    pub truncated: u64,
+
+    /// The number of messages dropped because a queue was full.
+    pub dropped: u64,
+
+    /// The number of messages dropped due to sampling.
+    pub sampled_out: u64,
+
+    /// The number of messages held back as repeats of the previous one.
+    pub coalesced: u64,
+}
+
+// The time at which a drain next fires the `stats` probe, see `DtraceBuilder::stats_interval`.
+#[derive(Debug)]
+struct StatsTimer {
+    interval: std::time::Duration,
+    start: std::time::Instant,
+    // The time of the next firing, in nanoseconds since `start`.
+    next: AtomicU64,
+}
+
+impl StatsTimer {
+    fn new(interval: std::time::Duration) -> Self {
+        Self {
+            interval,
+            start: std::time::Instant::now(),
+            next: AtomicU64::new(interval.as_nanos().try_into().unwrap_or(u64::MAX)),
+        }
+    }
+
+    // Return true if the interval has passed since the last firing, claiming the next one.
+    //
+    // Only one of several threads logging at once claims each firing.
+    fn due(&self) -> bool {
+        let now = u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let next = self.next.load(Ordering::Relaxed);
+        if now < next {
+            return false;
+        }
+        let interval = u64::try_from(self.interval.as_nanos()).unwrap_or(u64::MAX);
+        self.next
+            .compare_exchange(
+                next,
+                now.saturating_add(interval),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    // Fire the `stats` probe with `counters`, if it is due.
+    //
+    // This reads the counters without updating them, so that firing doesn't count as a message.
+    fn fire<P>(&self, counters: &LevelCounters)
+    where
+        P: Provider,
+    {
+        if self.due() {
+            P::fire_stats(|| counters.degradation());
+        }
+    }
+}
+
+// The number of sites warned about by `SlowMessages`, after which no more are.
+const MAX_SLOW_SITES: usize = 1024;
+
+// The minimum interval between warnings logged by `SlowMessages`.
+const SLOW_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Warns about messages which are slow to serialize, see `DtraceBuilder::warn_slow_messages`.
+#[derive(Debug)]
+struct SlowMessages {
+    threshold: std::time::Duration,
+    // Limits the rate of warnings, which, unlike the `stats` probe, are due immediately.
+    timer: StatsTimer,
+    // The sites already warned about, as their module and line.
+    warned: std::sync::Mutex<std::collections::HashSet<(String, u32)>>,
+    // The slow sites yet to be warned about, see `Dtrace::log_slow_warnings`.
+    pending: std::sync::Mutex<Vec<SlowSite>>,
+}
+
+impl SlowMessages {
+    fn new(threshold: std::time::Duration) -> Self {
+        Self {
+            threshold,
+            timer: StatsTimer {
+                interval: SLOW_WARNING_INTERVAL,
+                start: std::time::Instant::now(),
+                next: AtomicU64::new(0),
+            },
+            warned: std::sync::Mutex::default(),
+            pending: std::sync::Mutex::default(),
+        }
+    }
+
+    // Queue a warning if serializing `record` took longer than the threshold, and its site hasn't
+    // been warned about.
+    //
+    // The warning is logged once the message has been emitted, rather than while it is being
+    // serialized, so that it can be logged to the drain wrapping this one.
+    fn check(&self, record: &slog::Record<'_>, elapsed: std::time::Duration) {
+        if elapsed <= self.threshold {
+            return;
+        }
+        let site = (record.module().to_string(), record.line());
+        let mut warned = self
+            .warned
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if warned.len() >= MAX_SLOW_SITES || warned.contains(&site) || !self.timer.due() {
+            return;
+        }
+        warned.insert(site);
+        self.pending
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push(SlowSite {
+                module: record.module(),
+                file: record.file(),
+                line: record.line(),
+                elapsed,
+            });
+    }
+
+    // Take the queued warnings.
+    fn take(&self) -> Vec<SlowSite> {
+        std::mem::take(
+            &mut self
+                .pending
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
+        )
+    }
+}
+
+// The site of a message which was slow to serialize, see `SlowMessages`.
+#[derive(Debug)]
+struct SlowSite {
+    module: &'static str,
+    file: &'static str,
+    line: u32,
+    elapsed: std::time::Duration,
+}
+
+impl SlowSite {
+    // Log a warning about this site to `drain`, ignoring any error.
+    fn warn<D>(&self, threshold: std::time::Duration, drain: &D)
+    where
+        D: Drain,
+    {
+        let _ = drain.log(
+            &slog::record!(
+                slog::Level::Warning,
+                "",
+                &format_args!("a message was slow to serialize for DTrace"),
+                slog::b!(
+                    "module" => self.module,
+                    "file" => self.file,
+                    "line" => self.line,
+                    "elapsed_us" => u64::try_from(self.elapsed.as_micros()).unwrap_or(u64::MAX),
+                    "threshold_us" => u64::try_from(threshold.as_micros()).unwrap_or(u64::MAX),
+                )
+            ),
+            &slog::OwnedKVList::from(slog::o!()),
+        );
+    }
+}
+
+/// A switch which turns forwarding to DTrace on and off at runtime.
+///
+/// While the gate is closed, drains built with it discard every message before constructing it,
+/// even if their probes are enabled, as if it were excluded by [`DtraceBuilder::levels`]. This
+/// lets an operator leave forwarding off until it's needed, and then turn it on without
+/// restarting the process, for example from a signal handler or an administrative endpoint. It
+/// applies in addition to any level, module or sampling filters. Messages discarded by the gate
+/// are counted in [`LevelCounters::total`], and still advance any sampling.
+///
+/// Each builder has its own gate, which is open unless closed with [`DtraceBuilder::verbose`].
+/// It may be taken with [`DtraceBuilder::verbose_gate`] before building, and cloned freely:
+///
+/// ```
+/// use slog::{info, o, Logger};
+/// use slog_dtrace::DtraceBuilder;
+///
+/// let builder = DtraceBuilder::new().verbose(false);
+/// let gate = builder.verbose_gate();
+/// let (drain, _) = builder.build();
+/// let log = Logger::root(drain, o!());
+///
+/// info!(log, "discarded");
+/// gate.set_verbose(true);
+/// info!(log, "forwarded");
+/// ```
+#[derive(Debug, Clone)]
+pub struct VerboseGate(Arc<AtomicBool>);
+
+impl VerboseGate {
+    /// Open the gate if `verbose` is `true`, or close it otherwise.
+    pub fn set_verbose(&self, verbose: bool) {
+        self.0.store(verbose, Ordering::Relaxed);
+    }
+
+    /// Return `true` if the gate is open.
+    pub fn is_verbose(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for VerboseGate {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+/// A builder for configuring a [`Dtrace`] drain.
+///
+/// The builder allows selecting the DTrace provider through which messages are emitted (see
+/// [`provider!`] for details), and controlling how each [`Message`] is constructed.
+#[derive(Debug)]
+pub struct DtraceBuilder<P = Slog> {
+    config: Config,
+    _provider: std::marker::PhantomData<fn() -> P>,
+}
+
+impl DtraceBuilder {
+    /// Create a builder with the default configuration.
+    pub fn new() -> Self {
+        Self {
+            config: Config::default(),
+            _provider: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Default for DtraceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P> DtraceBuilder<P>
+where
+    P: Provider,
+{
+    /// Emit messages through the probes of the provider `Q`, rather than the default `slog`.
+    pub fn provider<Q>(self) -> DtraceBuilder<Q>
+    where
+        Q: Provider,
+    {
+        DtraceBuilder {
+            config: self.config,
+            _provider: std::marker::PhantomData,
+        }
+    }
+
+    /// Reconstruct nested objects from keys containing dots.
+    ///
+    /// When enabled, keys such as `req.method` and `req.path` are emitted in [`Message::kv`] as
+    /// `{"req": {"method": ..., "path": ...}}`, so that sub-fields can be addressed with the DTrace
+    /// `json()` subroutine, e.g., `json(copyinstr(arg0), "ok.kv.req.method")`. If a key conflicts
+    /// with an existing non-object value, it is inserted at the top level unchanged. This is
+    /// disabled by default.
+    pub fn nested_keys(mut self, nested: bool) -> Self {
+        self.config.nested_keys = nested;
+        self
+    }
+
+    /// Serialize messages with short field names, such as `ts` rather than `timestamp`.
+    ///
+    /// This leaves more of DTrace's `strsize` for the message itself, which can halve the size of
+    /// short messages. [`Message::from_probe_line`] parses messages with either set of names. See
+    /// [`Message::compact`] for details. This is disabled by default.
+    pub fn compact_field_names(mut self, compact: bool) -> Self {
+        self.config.compact_field_names = compact;
+        self
+    }
+
+    /// Serialize the keys of each message in sorted order, see [`Message::sorted_keys`].
+    ///
+    /// This makes the serialized form of a message canonical, regardless of the features enabled
+    /// in the build, for example so that it can be hashed or compared with a golden file. It
+    /// costs a sort of the keys of every object, and so is disabled by default.
+    pub fn sorted_keys(mut self, sorted: bool) -> Self {
+        self.config.sorted_keys = sorted;
+        self
+    }
+
+    /// Escape each `%` in the strings of every message as `%%`.
+    ///
+    /// Scripts should print messages with a format string, as in `printf("%s\n",
+    /// copyinstr(arg0))`. A script which instead passes the message as the format, as in
+    /// `printf(copyinstr(arg0))`, has any `%` in it interpreted as a conversion, so that a message
+    /// such as `"100% done"` or one with a literal `%s` is garbled, or fails with an error. When
+    /// enabled, each `%` in the text, key-value pairs and other strings of a message is doubled,
+    /// which such a script prints as a single `%`, and [`Message::printf_escaped`] is set.
+    /// [`Message::from_probe_line`] reverses the escaping, so messages parsed from a correct
+    /// script are unchanged, but other consumers see the doubled characters. The escaping is
+    /// applied after [`DtraceBuilder::max_message_bytes`], and so may make a message slightly
+    /// longer than that limit. This is disabled by default.
+    pub fn printf_safe(mut self, safe: bool) -> Self {
+        self.config.printf_safe = safe;
+        self
+    }
+
+    /// Replace the values of keys matching any of `patterns` with `"***"`.
+    ///
+    /// This prevents sensitive values, such as passwords or tokens, from being exposed to anyone
+    /// able to trace the process. Patterns match whole keys, ignoring case, and may start or end
+    /// with `*` to match any suffix or prefix, respectively. For example, `*_token` matches
+    /// `api_token` and `Session_Token`, and `password` matches only `password`, in any case.
+    /// Only top-level keys are matched, and a redacted key is never used as the
+    /// [`numeric_key`](DtraceBuilder::numeric_key).
+    ///
+    /// This applies only to DTrace: any other drain, such as those given to [`with_drain`], still
+    /// receives the real values.
+    pub fn redact_keys(mut self, patterns: &[&str]) -> Self {
+        self.config.redact_keys = patterns.iter().map(|p| p.to_lowercase()).collect();
+        self
+    }
+
+    /// Include the scope in which each key was set, see [`Message::scopes`].
+    ///
+    /// This helps to reconstruct the hierarchy of loggers, or spans, and to tell which value of a
+    /// repeated key came from where. This is disabled by default.
+    pub fn key_scopes(mut self, include: bool) -> Self {
+        self.config.key_scopes = include;
+        self
+    }
+
+    /// Set the formatter applied to the value of each key-value pair, see [`KvValueFormatter`].
+    ///
+    /// Values are emitted unchanged by default.
+    pub fn kv_formatter<F>(mut self, formatter: F) -> Self
+    where
+        F: KvValueFormatter + 'static,
+    {
+        self.config.kv_formatter = ValueFormatter(Arc::new(formatter));
+        self
+    }
+
+    /// Include `value` in each message, as [`Message::context`].
+    ///
+    /// This is intended for context which is fixed for the lifetime of the drain, such as the
+    /// identity of a deployment or a fingerprint of its configuration, so that it can be added to
+    /// every message without flattening it into key-value pairs. `value` is serialized as JSON
+    /// once, here, and a copy of the result included in each message. If it fails to serialize,
+    /// the context is an object whose `__error` field describes the failure.
+    ///
+    /// ```
+    /// use slog_dtrace::DtraceBuilder;
+    ///
+    /// #[derive(serde::Serialize)]
+    /// struct Deployment {
+    ///     region: &'static str,
+    ///     config_hash: u64,
+    /// }
+    ///
+    /// let builder = DtraceBuilder::new().context(&Deployment {
+    ///     region: "us-west-1",
+    ///     config_hash: 0x1234,
+    /// });
+    /// ```
+    pub fn context<T>(mut self, value: &T) -> Self
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = serde_json::to_value(value)
+            .unwrap_or_else(|e| serde_json::json!({ ERROR_KEY: e.to_string() }));
+        self.config.context = Some(Arc::new(value));
+        self
+    }
+
+    /// Add each of `labels` to the key-value pairs of every message.
+    ///
+    /// This is intended for labels which are fixed for the lifetime of the drain, such as the
+    /// availability zone or service name, so that they needn't be added to every logger. The
+    /// labels are collected into a map once, here, and each message starts with a copy of it, to
+    /// which its own key-value pairs are added. A key-value pair of the message therefore takes
+    /// precedence over a label with the same key. Labels are added as they are, without
+    /// [redaction](DtraceBuilder::redact_keys) or [formatting](DtraceBuilder::kv_formatter), and
+    /// are replaced by those given to a later call.
+    ///
+    /// ```
+    /// use slog_dtrace::DtraceBuilder;
+    /// use std::collections::HashMap;
+    ///
+    /// let labels = HashMap::from([("az", "us-west-1"), ("service", "nexus")]);
+    /// let builder = DtraceBuilder::new().labels(labels);
+    /// ```
+    pub fn labels<I, K, V>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let labels: JsonMap = labels
+            .into_iter()
+            .map(|(key, value)| (key.into(), serde_json::Value::String(value.into())))
+            .collect();
+        self.config.labels = (!labels.is_empty()).then(|| Arc::new(labels));
+        self
+    }
+
+    /// Set a function which may modify each message before it is emitted.
+    ///
+    /// The function is called with the complete message, including its key-value pairs, only when
+    /// a probe is enabled, and before any [`max_message_bytes`](DtraceBuilder::max_message_bytes)
+    /// limit is applied. It may add or remove key-value pairs, or change any other field. If the
+    /// function panics, the panic is caught, and the message is emitted with any changes made
+    /// before the panic.
+    ///
+    /// ```
+    /// use slog_dtrace::DtraceBuilder;
+    ///
+    /// let builder = DtraceBuilder::new().transform(|msg| {
+    ///     msg.kv.insert(String::from("region"), "us-west-1".into());
+    /// });
+    /// ```
+    pub fn transform<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(&mut Message) + Send + Sync + std::panic::RefUnwindSafe + 'static,
+    {
+        self.config.transform = Some(Transform(Arc::new(transform)));
+        self
+    }
+
+    /// Forward to DTrace only the messages whose key-value pairs satisfy `filter`.
+    ///
+    /// This allows capturing just the interesting messages of an investigation, such as requests
+    /// which failed, rather than every message at a level. `filter` is called with
+    /// [`Message::kv`] as it would be emitted, after any [`transform`](DtraceBuilder::transform),
+    /// and messages for which it returns `false` are discarded, as if excluded by
+    /// [`levels`](DtraceBuilder::levels). It runs after the level, module and sampling filters.
+    ///
+    /// Note that a probe can't be un-fired once its arguments are built, so the filter must see
+    /// the message before any probe fires. Each message which reaches the filter is therefore
+    /// constructed, and its key-value pairs serialized, whether or not a probe is enabled. That
+    /// cost can be avoided for messages lacking a key with
+    /// [`require_keys`](DtraceBuilder::require_keys), which is checked first. This applies only
+    /// to the [`Dtrace`] drain, and there is no filter by default.
+    ///
+    /// ```
+    /// use slog_dtrace::DtraceBuilder;
+    ///
+    /// let builder = DtraceBuilder::new()
+    ///     .require_keys(&["status"])
+    ///     .kv_filter(|kv| kv.get("status").and_then(|s| s.as_u64()).is_some_and(|s| s >= 500));
+    /// ```
+    pub fn kv_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&JsonMap) -> bool + Send + Sync + std::panic::RefUnwindSafe + 'static,
+    {
+        self.config.kv_filter = Some(KvFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Forward to DTrace only the messages with a key-value pair for each of `keys`.
+    ///
+    /// This is checked before a message is constructed, by visiting the keys of its record and
+    /// loggers without serializing their values, so it is a cheap way to discard messages before
+    /// a [`kv_filter`](DtraceBuilder::kv_filter) is applied, or on its own. Keys are compared
+    /// exactly, as they are logged. This applies only to the [`Dtrace`] drain, and no keys are
+    /// required by default.
+    pub fn require_keys(mut self, keys: &[&str]) -> Self {
+        self.config.required_keys = keys.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    /// Set how keys which appear more than once in a message are handled.
+    ///
+    /// See [`DuplicateKeyPolicy`] for details.
+    pub fn duplicate_keys(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.config.duplicate_keys = policy;
+        self
+    }
+
+    /// Limit the size of the string emitted to DTrace to `max` bytes.
+    ///
+    /// DTrace copies probe strings into a buffer of `strsize` bytes (256 by default), so larger
+    /// messages are cut off and are no longer valid JSON. When a limit is set, key-value pairs are
+    /// removed from oversized messages, largest first, until the remainder fits, and the text of
+    /// the message is then shortened on a character boundary. Such messages have
+    /// [`Message::truncated`] set, and carry their original length in [`Message::original_len`].
+    ///
+    /// The limit includes the terminating NUL byte, so it may be set to DTrace's `strsize`
+    /// directly. There is no limit by default.
+    pub fn max_message_bytes(mut self, max: usize) -> Self {
+        self.config.max_message_bytes = Some(max);
+        self
+    }
+
+    /// Set the size in bytes above which messages are replaced rather than emitted.
+    ///
+    /// This is a safety valve, so that a message with an enormous key-value pair, for example,
+    /// doesn't stress DTrace with a payload it can't usefully consume. Each message is measured
+    /// as JSON, after any [`max_message_bytes`](DtraceBuilder::max_message_bytes) limit is
+    /// applied. One which is still larger than `max` has its key-value pairs removed and its text
+    /// replaced with a note that it was dropped, and has [`Message::truncated`] set and
+    /// [`Message::original_len`] set to its size. The other fields, such as its location and
+    /// level, are kept.
+    ///
+    /// This defaults to [`DEFAULT_MAX_SERIALIZED_BYTES`]. Setting it to `usize::MAX` disables the
+    /// check, and so avoids measuring each message.
+    pub fn max_serialized_bytes(mut self, max: usize) -> Self {
+        self.config.max_serialized_bytes = Some(max);
+        self
+    }
+
+    /// Limit the number of key-value pairs included in each message to `max`.
+    ///
+    /// Pairs are kept in the order they are serialized, those of the record before those of the
+    /// logger, and the rest are dropped. When any are dropped, a `kv_overflow` pair is added with
+    /// the number dropped. This bounds the size of messages from loggers which accumulate many
+    /// pairs, while keeping them valid. There is no limit by default.
+    pub fn max_kv_pairs(mut self, max: usize) -> Self {
+        self.config.max_kv_pairs = Some(max);
+        self
+    }
+
+    /// Limit the length of each string value to `max` bytes.
+    ///
+    /// A single large value, such as a SQL query or a backtrace, can fill DTrace's buffer by
+    /// itself, crowding out the rest of the message. When a limit is set, longer string values
+    /// are shortened on a character boundary and end with an ellipsis, so that they are at most
+    /// `max` bytes, and a `<key>__len` pair is added with the original length in bytes. This
+    /// applies to each value as it is serialized, whereas
+    /// [`max_message_bytes`](DtraceBuilder::max_message_bytes) applies to the message as a whole.
+    /// Values other than strings are not shortened. There is no limit by default.
+    pub fn max_value_bytes(mut self, max: usize) -> Self {
+        self.config.max_value_bytes = Some(max);
+        self
+    }
+
+    /// Pass the value of the integer key-value pair `key` as a separate probe argument.
+    ///
+    /// Messages that contain `key` with an integer value are emitted via the `<level>_value`
+    /// probes, e.g., `info_value`, rather than the usual per-level probes. These take the message
+    /// as `arg0` and the value as `arg1`, so it may be used directly in aggregations:
+    ///
+    /// ```bash
+    /// # dtrace -Z -n 'slog*:::info_value { @ = quantize(arg1); }'
+    /// ```
+    ///
+    /// Messages without the key, or where it isn't a non-negative integer, are emitted via the
+    /// usual probes, since `arg1` is unsigned.
+    /// Note that looking up the key requires visiting each key-value pair of every message, even
+    /// when no probes are enabled. There is no such key by default.
+    pub fn numeric_key(mut self, key: impl Into<String>) -> Self {
+        self.config.numeric_key = Some(key.into());
+        self
+    }
+
+    /// Also emit each message via the `request` probe, with the values of two keys as integers.
+    ///
+    /// Parsing JSON in DTrace is expensive, so aggregating on the values of key-value pairs is
+    /// easier with native arguments. The `request` probe fires for messages at all levels, in
+    /// addition to the usual probes, with these arguments:
+    ///
+    /// | Argument | Type     | Value                                        |
+    /// |----------|----------|----------------------------------------------|
+    /// | `arg0`   | `string` | The message as JSON, as for the level probes |
+    /// | `arg1`   | `uint64` | The integer value of `latency_key`, or zero  |
+    /// | `arg2`   | `uint64` | The integer value of `bytes_key`, or zero    |
+    ///
+    /// The values are looked up among the top-level keys of [`Message::kv`], and are zero if the
+    /// key is missing or its value isn't an integer. For example, for an HTTP server which logs
+    /// `"latency_ns"` and `"bytes"` with each response:
+    ///
+    /// ```bash
+    /// dtrace -n 'slog*:::request /arg1 != 0/ { @latency = quantize(arg1); @bytes = sum(arg2); }'
+    /// ```
+    ///
+    /// The `request` probe isn't used by default, when only the usual probes fire.
+    pub fn request_probe(
+        mut self,
+        latency_key: impl Into<String>,
+        bytes_key: impl Into<String>,
+    ) -> Self {
+        self.config.request_keys = Some(RequestKeys {
+            latency: latency_key.into(),
+            bytes: bytes_key.into(),
+        });
+        self
+    }
+
+    /// Include the name of the issuing thread in each message, see [`Message::thread_name`].
+    ///
+    /// This requires allocating a copy of the name for each message, and so is disabled by default.
+    pub fn thread_names(mut self, include: bool) -> Self {
+        self.config.thread_names = include;
+        self
+    }
+
+    /// Include the tag of each record in its message, see [`Message::tag`].
+    ///
+    /// Tagged messages are also emitted via the `tagged` probe regardless, see [`Dtrace`]. This is
+    /// disabled by default.
+    pub fn tags(mut self, include: bool) -> Self {
+        self.config.tags = include;
+        self
+    }
+
+    /// Include a hash of the site from which each message was issued, see [`Message::msg_hash`].
+    ///
+    /// This is disabled by default.
+    pub fn message_hashes(mut self, include: bool) -> Self {
+        self.config.message_hashes = include;
+        self
+    }
+
+    /// Set whether the drain initially forwards messages to DTrace, see [`VerboseGate`].
+    ///
+    /// The drain forwards messages by default.
+    pub fn verbose(self, verbose: bool) -> Self {
+        self.config.verbose.set_verbose(verbose);
+        self
+    }
+
+    /// Return the gate with which forwarding to DTrace may be turned on and off at runtime.
+    ///
+    /// This is shared by every drain built from this builder.
+    pub fn verbose_gate(&self) -> VerboseGate {
+        self.config.verbose.clone()
+    }
+
+    /// Return a [`Serializer`] converting key-value pairs as the drains built from this builder
+    /// would.
+    pub fn serializer(&self) -> Serializer {
+        Serializer::with_config(&self.config)
+    }
+
+    /// Coalesce identical consecutive messages, emitting them at most once in each `window`.
+    ///
+    /// A message is identical to the previous one if it has the same level, location, text and
+    /// key-value pairs. The first of a run of identical messages is emitted as usual, and the
+    /// rest are held back, and counted. The latest is emitted, with [`Message::repeat_count`] set
+    /// to the number held back including itself, either when the next identical message arrives
+    /// once `window` has passed since the last one emitted, or immediately before the first
+    /// message which differs. So a flapping condition is emitted at most about once per `window`,
+    /// and every held back message is accounted for once the run ends. Messages still held back
+    /// when logging stops are never emitted, as nothing checks the window between messages.
+    ///
+    /// Messages are then constructed whether or not their probes are enabled, so that they can be
+    /// compared. This applies only to the [`Dtrace`] drain, and is disabled by default.
+    pub fn coalesce(mut self, window: std::time::Duration) -> Self {
+        self.config.coalesce = Some(window);
+        self
+    }
+
+    /// Log a warning to the wrapped drain if the probes can't be registered.
+    ///
+    /// A failure to register is otherwise only reported by the returned [`ProbeRegistration`],
+    /// which is easy to ignore, leaving DTrace silently without messages. When enabled,
+    /// [`build_with_drain`](DtraceBuilder::build_with_drain) and the functions based on it, such
+    /// as [`with_drain`], log a single warning to the wrapped drain when building, with the
+    /// reason in its `error` key, so that the failure shows up in the ordinary logs. Nothing is
+    /// logged when DTrace is unsupported, since the drain is then a no-op by design. This is
+    /// enabled by default, and may be disabled by callers which handle the registration result
+    /// themselves.
+    pub fn warn_on_registration_failure(mut self, warn: bool) -> Self {
+        self.config.quiet_registration_failure = !warn;
+        self
+    }
+
+    /// Fire the `stats` probe at most once per `interval`, with the counts of messages the drain
+    /// has degraded.
+    ///
+    /// The probe's only argument is a [`Degradation`] as JSON, counting the messages truncated,
+    /// dropped, sampled out and coalesced since the drain was built, so that a DTrace session
+    /// watching the messages of a service can also see what the logging pipeline lost:
+    ///
+    /// ```bash
+    /// dtrace -n 'slog*:::stats { printf("%s\n", copyinstr(arg0)); }'
+    /// ```
+    ///
+    /// No thread is started for this: the probe is checked for as each message is logged, before
+    /// it is filtered, and fired once `interval` has passed since it last fired. So it fires
+    /// late, or not at all, while nothing is logged. The first firing is one `interval` after the
+    /// drain is built. Firing the probe doesn't count as a message, nor change any of the
+    /// [`LevelCounters`]. This is disabled by default.
+    pub fn stats_interval(mut self, interval: std::time::Duration) -> Self {
+        self.config.stats_interval = Some(interval);
+        self
+    }
+
+    /// Log a warning when a message takes longer than `threshold` to serialize.
+    ///
+    /// Messages are built on the logging thread, so an expensive `Debug` or `Serialize`
+    /// implementation among the key-value pairs of a message, or contention for a lock it takes,
+    /// stalls the caller whenever DTrace is tracing. When enabled, the [`Dtrace`] drain measures
+    /// how long it takes to serialize the key-value pairs of each message it emits, and for those
+    /// slower than `threshold`, logs a warning with the module, file and line of the message, and
+    /// the time it took. Each site is warned about once, at most one warning is logged per
+    /// second, and no more sites are warned about once 1024 have been, so a slow site can't flood
+    /// the log.
+    ///
+    /// The warning is logged once the message has been forwarded to DTrace. The
+    /// [`DtraceDuplicate`] built by [`DtraceBuilder::build_with_drain`] and the
+    /// [`FallibleDuplicate`] built by [`DtraceBuilder::build_with_fallible_drain`] log it to the
+    /// drain they wrap, as below. A `Dtrace` drain on its own has nowhere else to log it, and so
+    /// forwards the warning to DTrace like any other message, at the `Warning` level. The warning
+    /// may be logged elsewhere with [`slow_message_logger`](DtraceBuilder::slow_message_logger).
+    /// This is disabled by default.
+    ///
+    /// ```
+    /// use slog::{o, Logger};
+    /// use slog_dtrace::DrainErrorPolicy;
+    /// use std::time::Duration;
+    ///
+    /// let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
+    /// let stderr = slog_term::FullFormat::new(decorator).build();
+    /// let (drain, _) = slog_dtrace::DtraceBuilder::new()
+    ///     .warn_slow_messages(Duration::from_millis(1))
+    ///     .build_with_fallible_drain(stderr, DrainErrorPolicy::Ignore);
+    /// let log = Logger::root(drain, o!());
+    /// ```
+    pub fn warn_slow_messages(mut self, threshold: std::time::Duration) -> Self {
+        self.config.slow_messages = Some(Arc::new(SlowMessages::new(threshold)));
+        self
+    }
+
+    /// Log the warnings enabled by [`warn_slow_messages`](DtraceBuilder::warn_slow_messages) to
+    /// `logger`, instead of the drain they would otherwise reach.
+    ///
+    /// This is useful to report slow messages to the drain wrapped by the drain built by
+    /// [`DtraceBuilder::build_with_drain`], which can't log them there itself: pass a logger
+    /// sharing that drain. Don't pass a logger which is itself slow to serialize.
+    pub fn slow_message_logger(mut self, logger: slog::Logger) -> Self {
+        self.config.slow_message_logger = Some(logger);
+        self
+    }
+
+    /// Retain the last `capacity` messages handled by the drain in memory, see
+    /// [`RecentMessages`].
+    ///
+    /// This keeps a record of the messages leading up to a crash, even if DTrace wasn't attached
+    /// to see them. They may be returned with [`Dtrace::recent`], or through the handle from
+    /// [`Dtrace::recent_messages`], e.g., in a panic hook. Note that each message is then
+    /// constructed whether or not its probe is enabled. This applies only to the [`Dtrace`] drain,
+    /// and is disabled by default, or if `capacity` is zero.
+    pub fn recent_messages(mut self, capacity: usize) -> Self {
+        self.config.recent_messages = capacity;
+        self
+    }
+
+    /// Emit the key-value pairs of loggers once, rather than with every message.
+    ///
+    /// A logger's pairs, and those of its parents, are usually repeated in every message it
+    /// issues, which can make up most of each message. When enabled, they are omitted from
+    /// [`Message::kv`], which contains only the pairs of the record itself. Each message instead
+    /// carries a [`Message::logger_id`], identifying its loggers' pairs, and the pairs are emitted
+    /// via the `logger` probe, with the ID as `arg0` and the pairs as JSON in `arg1`, the first
+    /// time a message is emitted with that ID while the probe is enabled. Consumers join the two
+    /// by ID.
+    ///
+    /// The tradeoff is that a consumer which attaches after a logger's pairs were emitted never
+    /// sees them, and must instead match on the ID, or restart the process. IDs are remembered
+    /// for up to 4096 sets of pairs, after which all are forgotten and emitted again, so that
+    /// loggers created per request don't grow memory without bound. The pairs are still
+    /// serialized for each message, to compute their ID. This applies to the [`Dtrace`] and
+    /// [`AsyncDtrace`] drains, and is disabled by default.
+    pub fn logger_kv_once(mut self, enable: bool) -> Self {
+        self.config.loggers = enable.then(Arc::default);
+        self
+    }
+
+    /// Capture a backtrace for messages at one of `levels`, see [`Message::backtrace`].
+    ///
+    /// This allows DTrace to record where errors were logged from, for example, without
+    /// correlating a separate `ustack()` action with the message. As for
+    /// [`Backtrace::capture`](std::backtrace::Backtrace::capture), backtraces are only captured
+    /// when enabled by the `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE` environment variables.
+    ///
+    /// Capturing and symbolizing a backtrace is very expensive, often taking milliseconds, and
+    /// the result is usually several kilobytes, far larger than DTrace's default `strsize`. It
+    /// should be enabled only for rare levels. Backtraces are captured only when the probe for a
+    /// message is enabled, except by [`AsyncDtrace`], which must capture them on the logging
+    /// thread for every message at those levels. This is disabled by default.
+    pub fn capture_backtrace_for(mut self, levels: &[slog::Level]) -> Self {
+        self.config.backtrace_levels = levels.to_vec();
+        self
+    }
+
+    /// Include a random token, generated once when the drain is built, in each message.
+    ///
+    /// See [`Message::process_token`] for details. This is disabled by default.
+    pub fn process_token(mut self, include: bool) -> Self {
+        self.config.process_token = include.then(new_process_token);
+        self
+    }
+
+    /// Include the name of the host, looked up once when the drain is built, in each message.
+    ///
+    /// See [`Message::hostname`] for details. If the name can't be determined, it is omitted.
+    /// This is disabled by default.
+    pub fn hostname(mut self, include: bool) -> Self {
+        self.config.hostname = if include { hostname() } else { None };
+        self
+    }
+
+    /// Fire the `startup` probe once, when probes are registered.
+    ///
+    /// A consumer which attaches to a running process can't see the messages logged before it
+    /// attached, or tell whether there were any. The `startup` probe identifies the process, with
+    /// its ID, the time at which probes were registered as an RFC 3339 string, and a stable hash
+    /// of its arguments, so that a consumer tracing it from the start, such as
+    /// `dtrace -n 'slog*:::startup { ... }' -c ./program`, can tell which process it is watching
+    /// and when its log begins.
+    ///
+    /// The probe fires only if registration succeeds, and only if it is enabled at that instant,
+    /// so a consumer which attaches later never sees it. Probes are registered only once in each
+    /// process, see [`ProbeRegistration`], so it fires at most once, for the first drain built. For a drain built with
+    /// [`build_lazy`](DtraceBuilder::build_lazy), it fires when
+    /// [`Dtrace::register_probes_now`] first succeeds. This is disabled by default.
+    pub fn startup_probe(mut self, enable: bool) -> Self {
+        self.config.startup_probe = enable;
+        self
+    }
+
+    /// Remove the first of `prefixes` which matches the source file of each message.
+    ///
+    /// The file recorded by `slog` comes from [`file!`], which is an absolute path for crates
+    /// built outside the workspace, such as dependencies, or with some build configurations. This
+    /// exposes details of the build machine, such as the home directory of the user, and makes
+    /// the same line appear differently in messages from builds on different machines. Passing
+    /// `env!("CARGO_MANIFEST_DIR")`, for example, keeps only the path relative to the crate being
+    /// built. Prefixes only match whole path components, and paths which match none of them are
+    /// unchanged. This is disabled by default.
+    pub fn strip_file_prefixes(mut self, prefixes: &[&str]) -> Self {
+        self.config.file_prefixes = prefixes.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Emit messages at all levels via a single probe, named `message`.
+    ///
+    /// By default, messages are emitted via a separate probe for each level, such as `info`, so
+    /// that DTrace only pays to copy in messages at the levels which are traced. When enabled,
+    /// messages at all levels are instead emitted as JSON via the `message` probe, whose only
+    /// argument is the message. This allows a single clause to handle all levels, switching on
+    /// the `level` field of the message, for example:
+    ///
+    /// ```bash
+    /// dtrace -n 'slog*:::message /json(copyinstr(arg0), "ok.level") == "WARN"/ { ... }'
+    /// ```
+    ///
+    /// The tradeoff is that every message is serialized whenever the probe is enabled, even if
+    /// only some levels are of interest. The [`format`](DtraceBuilder::format) and
+    /// [`numeric_key`](DtraceBuilder::numeric_key) options are ignored in this mode. This is
+    /// disabled by default.
+    pub fn single_probe(mut self, single: bool) -> Self {
+        self.config.single_probe = single;
+        self
+    }
+
+    /// Set the naming scheme of the probes via which messages at each level are emitted.
+    ///
+    /// By default, the probes are named after the level, such as `warn`. With
+    /// [`ProbeNames::Numeric`], they are instead named after the number of the level, such as
+    /// `level3`. Both sets of probes are always registered, but only those of the chosen scheme
+    /// fire. [`single_probe`](DtraceBuilder::single_probe) and [`Format::Fields`] take precedence
+    /// over this.
+    pub fn probe_names(mut self, names: ProbeNames) -> Self {
+        self.config.probe_names = names;
+        self
+    }
+
+    /// Emit messages from an [`AsyncDtrace`] drain in batches, via the `batch` probe.
+    ///
+    /// Firing a probe has a fixed cost, which dominates when a burst of small messages is logged.
+    /// When enabled, the worker thread of an `AsyncDtrace` drain instead collects up to
+    /// `max_messages` queued messages, waiting at most `window` after the first for more to
+    /// arrive, and emits them as a single JSON array via the `batch` probe. A script can then
+    /// address each message with the `json()` subroutine, for example:
+    ///
+    /// ```bash
+    /// dtrace -n 'slog*:::batch { printf("%s\n", json(copyinstr(arg0), "ok[0].message")); }'
+    /// ```
+    ///
+    /// Messages appear in each batch, and batches are emitted, in the order the messages were
+    /// logged. Batches replace the probes for each level, so the
+    /// [`format`](DtraceBuilder::format), [`numeric_key`](DtraceBuilder::numeric_key), and
+    /// [`single_probe`](DtraceBuilder::single_probe) options are ignored, though tagged messages
+    /// are still also emitted via the `tagged` probe. Note that DTrace copies in at most `strsize`
+    /// bytes of the batch, so `strsize` must be raised to fit `max_messages` messages, or batches
+    /// are cut off. [`max_message_bytes`](DtraceBuilder::max_message_bytes) applies to each
+    /// message, not to the batch. This has no effect on other drains, and is disabled by default.
+    pub fn batch(mut self, max_messages: usize, window: std::time::Duration) -> Self {
+        self.config.batch = Some(Batch {
+            max_messages: max_messages.max(1),
+            window,
+        });
+        self
+    }
+
+    /// Set the format in which the timestamp of each message is serialized.
+    ///
+    /// See [`TimestampFormat`] for details. This defaults to [`TimestampFormat::Rfc3339`].
+    pub fn timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.config.timestamp_format = format;
+        self
+    }
+
+    /// Set the precision of the fractional seconds of each timestamp.
+    ///
+    /// Timestamps are truncated to `precision` when serialized as RFC 3339 strings, which saves
+    /// up to ten bytes of DTrace's `strsize` per message for consumers that don't need
+    /// nanoseconds. [`Message::from_probe_line`] parses timestamps of any precision. This has no
+    /// effect on timestamps serialized with [`TimestampFormat::EpochNanos`]. See
+    /// [`TimestampPrecision`] for details. This defaults to [`TimestampPrecision::Nanos`].
+    pub fn timestamp_precision(mut self, precision: TimestampPrecision) -> Self {
+        self.config.timestamp_precision = precision;
+        self
+    }
+
+    /// Include a timestamp in each message, see [`Message::timestamp`].
+    ///
+    /// DTrace records the time at which each probe fires, in its `timestamp` and `walltimestamp`
+    /// variables, so consumers which only use DTrace may not need the timestamp of the message.
+    /// Disabling it avoids reading the clock and serializing the time for each message, at the
+    /// cost of messages which are no longer self-contained. This is enabled by default.
+    pub fn timestamps(mut self, include: bool) -> Self {
+        self.config.omit_timestamps = !include;
+        self
+    }
+
+    /// Set the function used to timestamp each message, see [`Message::timestamp`].
+    ///
+    /// This defaults to [`Utc::now`], but may be set to return a fixed time in tests, for example,
+    /// or to use a cheaper source of time. Messages then carry [`Message::clock_source`], which is
+    /// `"custom"` unless named with [`clock_source`](DtraceBuilder::clock_source).
+    pub fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> DateTime<Utc> + Send + Sync + std::panic::RefUnwindSafe + 'static,
+    {
+        self.config.clock = Clock(Arc::new(clock));
+        self.config
+            .clock_source
+            .get_or_insert_with(|| String::from("custom"));
+        self
+    }
+
+    /// Name the source of the timestamps of messages, emitted as [`Message::clock_source`].
+    ///
+    /// This describes the function set with [`clock`](DtraceBuilder::clock), for example as
+    /// `"monotonic"` for one which counts from an arbitrary point rather than the Unix epoch,
+    /// so that consumers interpret the timestamps correctly. By default, the field is omitted
+    /// for the realtime clock, and is `"custom"` for any other.
+    pub fn clock_source(mut self, source: impl Into<String>) -> Self {
+        self.config.clock_source = Some(source.into());
+        self
+    }
+
+    /// Include a monotonic timestamp in each message, see [`Message::monotonic_ns`].
+    ///
+    /// Timestamps are relative to the time at which this method is called. This is disabled by
+    /// default.
+    pub fn monotonic_timestamps(mut self, include: bool) -> Self {
+        self.config.epoch = include.then(std::time::Instant::now);
+        self
+    }
+
+    /// Include the time since the previous message in each message, see [`Message::delta_ns`].
+    ///
+    /// The time of the last message is shared by all threads logging to the drain, so this is the
+    /// gap between consecutive messages across the whole process, not those of a single thread.
+    /// Messages are only timed when they are serialized, that is, when a probe is enabled, except
+    /// for the [`AsyncDtrace`] drain, which times each message as it is queued. Updating the shared
+    /// time adds contention between logging threads, so this is disabled by default.
+    pub fn message_deltas(mut self, include: bool) -> Self {
+        self.config.last_message = include.then(Arc::default);
+        self
+    }
+
+    /// Emit only one in every `rate` messages at each level to DTrace.
+    ///
+    /// This limits the volume of messages from extremely hot loggers, which might otherwise cause
+    /// DTrace to drop data. Messages are dropped before they are constructed, and the number
+    /// dropped is available from [`LevelCounters::sampled_out`]. Emitted messages carry the rate
+    /// in [`Message::sample_rate`]. Note that this applies only to DTrace: messages are still
+    /// passed to any other drain, such as those given to [`with_drain`].
+    ///
+    /// A rate of 0 or 1 disables sampling, which is the default. This replaces any rates set with
+    /// [`DtraceBuilder::sample_rates`].
+    pub fn sample_rate(mut self, rate: u64) -> Self {
+        self.config.sample_rates = [Some(rate).filter(|rate| *rate > 1); 6];
+        self
+    }
+
+    /// Emit only one in every `rate` messages at each level given in `rates` to DTrace.
+    ///
+    /// This is as for [`DtraceBuilder::sample_rate`], but with a separate rate for each level, so
+    /// that rare but important messages may all be kept while noisy ones are sampled. Every
+    /// message at a level not in `rates`, or with a rate of 0 or 1, is emitted. For example:
+    ///
+    /// ```
+    /// use slog::Level;
+    /// use slog_dtrace::DtraceBuilder;
+    ///
+    /// let (drain, _) = DtraceBuilder::new()
+    ///     .sample_rates([(Level::Info, 10), (Level::Debug, 1000), (Level::Trace, 1000)])
+    ///     .build();
+    /// ```
+    ///
+    /// This replaces any rate set with [`DtraceBuilder::sample_rate`].
+    pub fn sample_rates<I>(mut self, rates: I) -> Self
+    where
+        I: IntoIterator<Item = (slog::Level, u64)>,
+    {
+        self.config.sample_rates = [None; 6];
+        for (level, rate) in rates {
+            self.config.sample_rates[LevelCounters::index(level)] =
+                Some(rate).filter(|rate| *rate > 1);
+        }
+        self
+    }
+
+    /// Set the format in which messages are emitted to DTrace.
+    ///
+    /// Messages are emitted as JSON strings by default. See [`Format`] for the alternatives.
+    pub fn format(mut self, format: Format) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Forward only messages at `level` or more severe to DTrace.
+    ///
+    /// Messages at other levels are dropped before they are constructed, and so can never be seen
+    /// in DTrace. This replaces any set of levels from [`DtraceBuilder::levels`]. All levels are
+    /// forwarded by default.
+    pub fn min_level(mut self, level: slog::Level) -> Self {
+        self.config.levels = Some(
+            (1..=6)
+                .filter_map(slog::Level::from_usize)
+                .filter(|l| l.is_at_least(level))
+                .collect(),
+        );
+        self
+    }
+
+    /// Forward only messages at one of `levels` to DTrace.
+    ///
+    /// This replaces any minimum level from [`DtraceBuilder::min_level`]. All levels are forwarded
+    /// by default.
+    pub fn levels(mut self, levels: &[slog::Level]) -> Self {
+        self.config.levels = Some(levels.to_vec());
+        self
+    }
+
+    /// Forward only messages from the modules allowed by `filter` to DTrace.
+    ///
+    /// This applies only to DTrace: any other drain, such as those given to [`with_drain`], still
+    /// receives every message. Messages from all modules are forwarded by default.
+    pub fn module_filter(mut self, filter: ModuleFilter) -> Self {
+        self.config.module_filter = Some(filter);
+        self
+    }
+
+    /// Build a `Dtrace` drain, emitting messages only to DTrace.
+    ///
+    /// See [`Dtrace::new`] for details on the returned [`ProbeRegistration`].
+    pub fn build(self) -> (Dtrace<slog::Discard, P>, ProbeRegistration) {
+        let registration = register_probes::<P>(&self.config);
+        self.build_registered(registration)
+    }
+
+    // Build a `Dtrace` drain, given the result of registering its probes.
+    fn build_registered(
+        self,
+        registration: ProbeRegistration,
+    ) -> (Dtrace<slog::Discard, P>, ProbeRegistration) {
+        let registered = registration.is_success();
+        (
+            Dtrace::from_config(self.config, false, registered),
+            registration,
+        )
+    }
+
+    /// Build a drain emitting messages at `threshold` or more severe via the provider `P`, and all
+    /// others via the provider `Q`.
+    ///
+    /// This allows important messages, such as errors, to be traced through a provider which is
+    /// always enabled, while noisier messages go through another which is only enabled
+    /// occasionally. Both `Dtrace` drains share this configuration, but count messages
+    /// separately, and each emits only the messages routed to it. See [`LevelRouter`] to route
+    /// more than two ranges of levels.
+    ///
+    /// `usdt` registers the probes of every provider in the process in a single operation, so
+    /// the returned [`ProbeRegistration`] covers both providers: either both are registered, or
+    /// neither is, and a failure is reported once.
+    pub fn build_routed<Q>(self, threshold: slog::Level) -> (RoutedDtrace<P, Q>, ProbeRegistration)
+    where
+        Q: Provider,
+    {
+        let registration = register_probes::<P>(&self.config);
+        let registered = registration.is_success();
+        let severe = Dtrace::from_config(self.config.clone(), false, registered);
+        let other = Dtrace::from_config(self.config, false, registered);
+        (LevelRouter::new(threshold, severe, other), registration)
+    }
+
+    /// Build a `Dtrace` drain, emitting messages only to DTrace, without registering probes.
+    ///
+    /// This allows the logging setup of a program to be built before DTrace is available, for
+    /// example before privileges are acquired or the kernel module is loaded. The drain discards
+    /// all messages until probes are registered with [`Dtrace::register_probes_now`].
+    pub fn build_lazy(self) -> Dtrace<slog::Discard, P> {
+        Dtrace::from_config(self.config, false, false)
+    }
+
+    /// Build an [`AsyncDtrace`] drain, which emits messages to DTrace from a background thread.
+    ///
+    /// Up to `capacity` messages are queued for the worker thread, beyond which messages are
+    /// dropped. See [`AsyncDtrace`] for details, and [`Dtrace::new`] for details on the returned
+    /// [`ProbeRegistration`].
+    pub fn build_async(self, capacity: usize) -> (AsyncDtrace<P>, ProbeRegistration)
+    where
+        P: Provider + 'static,
+    {
+        let registration = register_probes::<P>(&self.config);
+        (
+            AsyncDtrace::from_config(self.config, capacity),
+            registration,
+        )
+    }
+
+    /// Build a [`DtraceLayer`], which forwards `tracing` events to DTrace.
+    ///
+    /// See [`Dtrace::new`] for details on the returned [`ProbeRegistration`].
+    #[cfg(feature = "tracing")]
+    pub fn build_layer(self) -> (DtraceLayer<P>, ProbeRegistration) {
+        let registration = register_probes::<P>(&self.config);
+        (DtraceLayer::from_config(self.config), registration)
+    }
+
+    /// Build a [`DtraceLogger`], which forwards records from the `log` crate to DTrace.
+    ///
+    /// See [`Dtrace::new`] for details on the returned [`ProbeRegistration`].
+    #[cfg(feature = "log")]
+    pub fn build_logger(self) -> (DtraceLogger<P>, ProbeRegistration) {
+        let registration = register_probes::<P>(&self.config);
+        (DtraceLogger::from_config(self.config), registration)
+    }
+
+    /// Build a `Dtrace` drain, duplicating all log messages to `drain` as well.
+    ///
+    /// As with [`with_drain`], the `Dtrace` drain is a [no-op](Dtrace::noop) on platforms without
+    /// DTrace. If registering the probes fails for another reason, a warning is logged to `drain`
+    /// once, see [`DtraceBuilder::warn_on_registration_failure`].
+    pub fn build_with_drain<D>(self, drain: D) -> (DtraceDuplicate<D, P>, ProbeRegistration)
+    where
+        D: Drain,
+    {
+        let (d, registration) = if is_supported() {
+            self.build()
+        } else {
+            (
+                Dtrace::from_config(self.config, true, true),
+                ProbeRegistration::Success,
+            )
+        };
+        duplicate_with_drain(drain, d, registration)
+    }
+
+    /// Build a `Dtrace` drain, duplicating all log messages to each of `drains` as well.
+    ///
+    /// See [`with_drains`] for details.
+    pub fn build_with_drains(
+        self,
+        drains: Vec<BoxedDrain>,
+    ) -> (DtraceDuplicate<Fanout, P>, ProbeRegistration) {
+        self.build_with_drain(Fanout::new(drains))
+    }
+
+    /// Build a `Dtrace` drain, duplicating all log messages to `drain` as well, where `drain` may
+    /// fail.
+    ///
+    /// See [`with_fallible_drain`] for details.
+    pub fn build_with_fallible_drain<D>(
+        self,
+        drain: D,
+        policy: DrainErrorPolicy,
+    ) -> (FallibleDuplicate<D, P>, ProbeRegistration)
+    where
+        D: Drain,
+    {
+        let (DtraceDuplicate { drain, dtrace }, registration) = self.build_with_drain(drain);
+        let drain = FallibleDuplicate {
+            drain,
+            dtrace,
+            policy,
+        };
+        (drain, registration)
+    }
+}
+
+// Combine a `Dtrace` drain with `drain`, given the result of registering its probes.
+//
+// If DTrace is unsupported at runtime, the `Dtrace` drain becomes a no-op. If registration failed
+// for another reason, a warning is logged to `drain`, unless disabled with
+// `DtraceBuilder::warn_on_registration_failure`.
+fn duplicate_with_drain<D, P>(
+    drain: D,
+    d: Dtrace<slog::Discard, P>,
+    registration: ProbeRegistration,
+) -> (DtraceDuplicate<D, P>, ProbeRegistration)
+where
+    D: Drain,
+    P: Provider,
+{
+    let d = match &registration {
+        ProbeRegistration::Failed(RegistrationError::Unsupported) => {
+            Dtrace::from_config(d.config, true, false)
+        }
+        ProbeRegistration::Failed(e) if !d.config.quiet_registration_failure => {
+            let _ = drain.log(
+                &slog::record!(
+                    slog::Level::Warning,
+                    "",
+                    &format_args!(
+                        "DTrace probes are not registered, messages are not forwarded to DTrace"
+                    ),
+                    slog::b!("error" => %e)
+                ),
+                &slog::OwnedKVList::from(slog::o!()),
+            );
+            d
+        }
+        _ => d,
+    };
+    (DtraceDuplicate { drain, dtrace: d }, registration)
+}
+
+// Return `true` if `value`, of the `DISABLE_ENV_VAR` environment variable, disables the drain.
+fn disabled_by_env(value: Option<std::ffi::OsString>) -> bool {
+    value.is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+// Whether all probes in the process have been registered with DTrace, and a lock serializing
+// attempts to register them. See `ProbeRegistration`.
+static PROCESS_REGISTERED: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+// Register all probes in the process with DTrace, if they haven't been already.
+//
+// If registration succeeds, this also fires the `startup` probe of `P`, if it is enabled in
+// `config`. Once it has succeeded, later calls return `Success` without registering again, or
+// firing the `startup` probe.
+fn register_probes<P>(config: &Config) -> ProbeRegistration
+where
+    P: Provider,
+{
+    let mut registered = PROCESS_REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    if *registered {
+        return ProbeRegistration::Success;
+    }
+    match usdt::register_probes() {
+        Ok(_) => {
+            *registered = true;
+            if config.startup_probe {
+                P::fire_startup(startup_args);
+            }
+            ProbeRegistration::Success
+        }
+        Err(e) => ProbeRegistration::Failed(RegistrationError::from_usdt(e)),
+    }
+}
+
+// Return the arguments to the `startup` probe: the process ID, the current time, and a hash of
+// the arguments of the process.
+fn startup_args() -> (u64, String, u64) {
+    (
+        u64::from(std::process::id()),
+        Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+        argv_hash(std::env::args_os()),
+    )
+}
+
+// Hash the arguments of a process, see `Fnv1a`.
+fn argv_hash<I>(args: I) -> u64
+where
+    I: IntoIterator<Item = std::ffi::OsString>,
+{
+    let mut hash = Fnv1a::default();
+    for arg in args {
+        // Terminate each argument, so that `["ab", "c"]` and `["a", "bc"]` differ.
+        hash.write(arg.as_encoded_bytes());
+        hash.write(&[0]);
+    }
+    hash.0
+}
+
+// Hash the site from which a message was issued, given the column of the statement, or zero if
+// unknown, see `Message::msg_hash`.
+fn site_hash(location: &Location, column: u32) -> u64 {
+    let mut hash = Fnv1a::default();
+    hash.write(location.module.as_bytes());
+    hash.write(&[0]);
+    hash.write(location.file.as_bytes());
+    hash.write(&[0]);
+    hash.write(&location.line.to_le_bytes());
+    hash.write(&column.to_le_bytes());
+    hash.0
+}
+
+// The 64-bit FNV-1a hash, which unlike the hashers of the standard library is stable, so that
+// hashes can be compared between processes and builds.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Return `true` if DTrace probes are supported on the target platform.
+///
+/// This is determined at compile time, in the same way that `usdt` chooses how to implement
+/// probes. They are only implemented on illumos, Solaris, and macOS, when the `asm` feature is
+/// enabled. Otherwise, probes are compiled to no-ops, registration always succeeds, and the probes
+/// can never fire. See the [crate documentation](crate#toolchains-and-platforms) for details.
+pub const fn is_supported() -> bool {
+    cfg!(slog_dtrace_probes)
+}
+
+/// Return the name of the DTrace probe for messages at `level`.
+///
+/// This is the level in lowercase, except for [`slog::Level::Warning`], whose probe is `warn`.
+/// The `_` suffix of the macros generated by `usdt` is not part of the probe name. See
+/// [`ProbeNames::name`] for the names of the numbered probes.
+pub fn probe_name(level: slog::Level) -> &'static str {
+    ProbeNames::Levels.name(level)
+}
+
+/// The naming scheme of the probes via which messages at each level are emitted.
+///
+/// Set with [`DtraceBuilder::probe_names`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProbeNames {
+    /// Probes are named after the level, e.g., `warn`, see [`probe_name`].
+    #[default]
+    Levels,
+
+    /// Probes are named after the number of the level, e.g., `level3`.
+    ///
+    /// This suits tools which generate D clauses programmatically. The number is
+    /// [`Message::level_num`], which is stable:
+    ///
+    /// | Level      | Probe      | Numbered probe |
+    /// |------------|------------|----------------|
+    /// | `Critical` | `critical` | `level1`       |
+    /// | `Error`    | `error`    | `level2`       |
+    /// | `Warning`  | `warn`     | `level3`       |
+    /// | `Info`     | `info`     | `level4`       |
+    /// | `Debug`    | `debug`    | `level5`       |
+    /// | `Trace`    | `trace`    | `level6`       |
+    ///
+    /// Messages are emitted as JSON, exactly as via the probes named after the level, so
+    /// [`DtraceBuilder::format`] and [`DtraceBuilder::numeric_key`] are ignored. For example, to
+    /// print warnings and more severe messages:
+    ///
+    /// ```text
+    /// dtrace -n 'slog*:::level1,slog*:::level2,slog*:::level3 { printf("%s\n", copyinstr(arg0)); }'
+    /// ```
+    Numeric,
+}
+
+impl ProbeNames {
+    /// Return the name of the probe for messages at `level` in this scheme.
+    ///
+    /// ```
+    /// use slog::Level;
+    /// use slog_dtrace::ProbeNames;
+    ///
+    /// assert_eq!(ProbeNames::Levels.name(Level::Warning), "warn");
+    /// assert_eq!(ProbeNames::Numeric.name(Level::Warning), "level3");
+    /// ```
+    pub fn name(self, level: slog::Level) -> &'static str {
+        use slog::Level;
+        match (self, level) {
+            (ProbeNames::Levels, Level::Trace) => "trace",
+            (ProbeNames::Levels, Level::Debug) => "debug",
+            (ProbeNames::Levels, Level::Info) => "info",
+            (ProbeNames::Levels, Level::Warning) => "warn",
+            (ProbeNames::Levels, Level::Error) => "error",
+            (ProbeNames::Levels, Level::Critical) => "critical",
+            (ProbeNames::Numeric, Level::Critical) => "level1",
+            (ProbeNames::Numeric, Level::Error) => "level2",
+            (ProbeNames::Numeric, Level::Warning) => "level3",
+            (ProbeNames::Numeric, Level::Info) => "level4",
+            (ProbeNames::Numeric, Level::Debug) => "level5",
+            (ProbeNames::Numeric, Level::Trace) => "level6",
+        }
+    }
+
+    /// Return a DTrace probe description matching the probes of `provider` for each of `levels`
+    /// in this scheme, see [`probe_spec`].
+    ///
+    /// ```
+    /// use slog::Level;
+    /// use slog_dtrace::ProbeNames;
+    ///
+    /// assert_eq!(
+    ///     ProbeNames::Numeric.spec("nexus", &[Level::Critical, Level::Error]),
+    ///     "nexus*:::level1,nexus*:::level2",
+    /// );
+    /// ```
+    pub fn spec(self, provider: &str, levels: &[slog::Level]) -> String {
+        levels
+            .iter()
+            .map(|level| format!("{provider}*:::{}", self.name(*level)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Return the syslog severity of messages at `level`, see [`Message::severity`].
+///
+/// This follows the severities of RFC 5424, which are stable:
+///
+/// | Level      | Probe      | Severity          |
+/// |------------|------------|-------------------|
+/// | `Critical` | `critical` | 2 (critical)      |
+/// | `Error`    | `error`    | 3 (error)         |
+/// | `Warning`  | `warn`     | 4 (warning)       |
+/// | `Info`     | `info`     | 6 (informational) |
+/// | `Debug`    | `debug`    | 7 (debug)         |
+/// | `Trace`    | `trace`    | 7 (debug)         |
+///
+/// `slog` has no levels corresponding to emergency (0), alert (1) or notice (5), so these are
+/// never used.
+pub fn syslog_severity(level: slog::Level) -> u8 {
+    match level {
+        slog::Level::Critical => 2,
+        slog::Level::Error => 3,
+        slog::Level::Warning => 4,
+        slog::Level::Info => 6,
+        slog::Level::Debug | slog::Level::Trace => 7,
+    }
+}
+
+/// Return a DTrace probe description matching the probes of `provider` for each of `levels`.
+///
+/// For example, `probe_spec("slog", &[Level::Warning, Level::Error])` returns
+/// `"slog*:::warn,slog*:::error"`. The provider name is followed by `*` to match any process ID,
+/// and the descriptions are separated by commas, so that the result can be used as the probe
+/// description of a clause in a D program, or passed to `dtrace -n`. This matches the probes
+/// named after each level, see [`ProbeNames::spec`] for the numbered probes.
+///
+/// ```
+/// use slog::Level;
+///
+/// assert_eq!(
+///     slog_dtrace::probe_spec("nexus", &[Level::Critical, Level::Error]),
+///     "nexus*:::critical,nexus*:::error",
+/// );
+/// ```
+pub fn probe_spec(provider: &str, levels: &[slog::Level]) -> String {
+    ProbeNames::Levels.spec(provider, levels)
+}
+
+/// Combine the [`Dtrace`] drain with another drain.
+///
+/// This duplicates all log messages to `drain` and a new `Dtrace` drain.
+///
+/// Note that probe registration can fail, see [`ProbeRegistration`] and [`Dtrace::new`] for more
+/// information.
+///
+/// On platforms without DTrace, the `Dtrace` drain is a [no-op](Dtrace::noop), so that the same
+/// setup code may be used everywhere without constructing messages that go nowhere. This is the
+/// case if [`is_supported`] returns `false`, in which case no probes are registered and
+/// [`ProbeRegistration::Success`] is returned. It is also the case if registering fails at runtime
+/// with [`RegistrationError::Unsupported`], for example because the DTrace helper device is
+/// missing. If registering fails for another reason, a warning is logged to `drain`, see
+/// [`DtraceBuilder::warn_on_registration_failure`].
+pub fn with_drain<D>(drain: D) -> (DtraceDuplicate<D>, ProbeRegistration)
+where
+    D: Drain,
+{
+    DtraceBuilder::new().build_with_drain(drain)
+}
+
+/// Combine the [`Dtrace`] drain with another drain, which may fail.
+///
+/// The drain returned by [`with_drain`] fails whenever `drain` does, and so must itself be fused
+/// or have its errors ignored before it can be used by a [`slog::Logger`], which panics or
+/// discards the error without regard to DTrace. The [`FallibleDuplicate`] drain returned here
+/// instead handles the errors of `drain` itself, according to `policy`, and never fails.
+/// Messages are always forwarded to DTrace first, so a failure of `drain`, such as an error
+/// writing to a file or socket, doesn't hide them from DTrace. The default policy is
+/// [`DrainErrorPolicy::Ignore`].
+///
+/// See [`with_drain`] for details on the returned [`ProbeRegistration`].
+///
+/// ```
+/// use slog_dtrace::DrainErrorPolicy;
+///
+/// let file = slog_term::PlainSyncDecorator::new(std::io::sink());
+/// let drain = slog_term::FullFormat::new(file).build();
+/// let (drain, _registration) = slog_dtrace::with_fallible_drain(drain, DrainErrorPolicy::Report);
+/// let log = slog::Logger::root(drain, slog::o!());
+/// slog::info!(log, "to the file and DTrace");
+/// ```
+pub fn with_fallible_drain<D>(
+    drain: D,
+    policy: DrainErrorPolicy,
+) -> (FallibleDuplicate<D>, ProbeRegistration)
+where
+    D: Drain,
+{
+    DtraceBuilder::new().build_with_fallible_drain(drain, policy)
+}
+
+/// Combine the [`Dtrace`] drain with any number of other drains.
+///
+/// This duplicates all log messages to each of `drains` and a new `Dtrace` drain, without nesting
+/// [`DtraceDuplicate`]s. Each drain must already handle its own errors, for example by calling
+/// [`Drain::fuse`] or [`Drain::ignore_res`]. See [`with_drain`] for details on the returned
+/// [`ProbeRegistration`].
+///
+/// ```
+/// use slog::Drain;
+///
+/// let stderr = slog_term::FullFormat::new(slog_term::PlainSyncDecorator::new(std::io::stderr()))
+///     .build()
+///     .fuse();
+/// let (drain, _registration) = slog_dtrace::with_drains(vec![
+///     Box::new(stderr),
+///     Box::new(slog::Discard),
+/// ]);
+/// let log = slog::Logger::root(drain.fuse(), slog::o!());
+/// slog::info!(log, "to stderr and DTrace");
+/// ```
+pub fn with_drains(drains: Vec<BoxedDrain>) -> (DtraceDuplicate<Fanout>, ProbeRegistration) {
+    DtraceBuilder::new().build_with_drains(drains)
+}
+
+/// A boxed drain that may be combined with others in a [`Fanout`].
+pub type BoxedDrain =
+    Box<dyn slog::SendSyncRefUnwindSafeDrain<Ok = (), Err = slog::Never> + std::panic::UnwindSafe>;
+
+/// A [`slog::Drain`] that forwards all log messages to each of a list of drains.
+///
+/// See [`with_drains`].
+pub struct Fanout {
+    drains: Vec<BoxedDrain>,
+}
+
+impl Fanout {
+    /// Create a drain forwarding all messages to each of `drains`, in order.
+    pub fn new(drains: Vec<BoxedDrain>) -> Self {
+        Self { drains }
+    }
+}
+
+impl std::fmt::Debug for Fanout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fanout")
+            .field("drains", &self.drains.len())
+            .finish()
+    }
+}
+
+impl Drain for Fanout {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        for drain in &self.drains {
+            drain.log(record, values)?;
+        }
+        Ok(())
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        self.drains.iter().any(|drain| drain.is_enabled(level))
+    }
+}
+
+/// A [`slog::Drain`] that forwards all log messages to another drain and to DTrace.
+///
+/// This is like [`slog::Duplicate`], except that warnings about slow messages are logged to the
+/// wrapped drain rather than to DTrace, see [`DtraceBuilder::warn_slow_messages`]. It fails
+/// whenever the wrapped drain does, once the message has been forwarded to DTrace as well.
+///
+/// See [`with_drain`].
+#[derive(Debug)]
+pub struct DtraceDuplicate<D, P = Slog> {
+    drain: D,
+    dtrace: Dtrace<slog::Discard, P>,
+}
+
+impl<D, P> DtraceDuplicate<D, P> {
+    /// Return the wrapped drain.
+    pub fn drain(&self) -> &D {
+        &self.drain
+    }
+
+    /// Return the `Dtrace` drain.
+    pub fn dtrace(&self) -> &Dtrace<slog::Discard, P> {
+        &self.dtrace
+    }
+}
+
+impl<D, P> Drain for DtraceDuplicate<D, P>
+where
+    D: Drain,
+    P: Provider,
+{
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let result = self.drain.log(record, values);
+        self.dtrace.forward(record, values);
+        self.dtrace.log_slow_warnings(&self.drain);
+        result
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        self.drain.is_enabled(level) || Drain::is_enabled(&self.dtrace, level)
+    }
+}
+
+/// How a [`FallibleDuplicate`] handles errors of the drain it wraps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DrainErrorPolicy {
+    /// Discard the error.
+    #[default]
+    Ignore,
+
+    /// Forward a message describing the error to DTrace, at the `Error` level.
+    ///
+    /// The message has the key `error`, with the error formatted with [`Debug`](std::fmt::Debug),
+    /// since [`Drain::Err`] need not implement `Display`. It is subject to the same filters as
+    /// any other message.
+    Report,
+
+    /// Panic, as the drain would if it were [fused](Drain::fuse).
+    ///
+    /// The message has already been forwarded to DTrace when this happens.
+    Panic,
+}
+
+/// A [`slog::Drain`] that forwards all log messages to DTrace and to a drain which may fail.
+///
+/// See [`with_fallible_drain`].
+#[derive(Debug)]
+pub struct FallibleDuplicate<D, P = Slog> {
+    drain: D,
+    dtrace: Dtrace<slog::Discard, P>,
+    policy: DrainErrorPolicy,
+}
+
+impl<D, P> FallibleDuplicate<D, P> {
+    /// Return the wrapped drain.
+    pub fn drain(&self) -> &D {
+        &self.drain
+    }
+
+    /// Return the `Dtrace` drain.
+    pub fn dtrace(&self) -> &Dtrace<slog::Discard, P> {
+        &self.dtrace
+    }
+}
+
+impl<D, P> Drain for FallibleDuplicate<D, P>
+where
+    D: Drain,
+    D::Err: std::fmt::Debug,
+    P: Provider,
+{
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.dtrace.forward(record, values);
+        if let Err(e) = self.drain.log(record, values) {
+            match self.policy {
+                DrainErrorPolicy::Ignore => {}
+                DrainErrorPolicy::Report => self.dtrace.forward(
+                    &slog::record!(
+                        slog::Level::Error,
+                        "",
+                        &format_args!("the drain wrapped by slog-dtrace failed to log a message"),
+                        slog::b!("error" => ?e)
+                    ),
+                    &slog::OwnedKVList::from(slog::o!()),
+                ),
+                DrainErrorPolicy::Panic => {
+                    panic!("the drain wrapped by slog-dtrace failed: {:?}", e)
+                }
+            }
+        }
+        self.dtrace.log_slow_warnings(&self.drain);
+        Ok(())
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        self.drain.is_enabled(level) || Drain::is_enabled(&self.dtrace, level)
+    }
+}
+
+/// A [`slog::Drain`] that forwards each log message to one of two drains, depending on its level.
+///
+/// Messages at `threshold` or more severe go to the `severe` drain, and all others to the `other`
+/// drain. This is usually built with [`DtraceBuilder::build_routed`], to emit messages at
+/// different levels through different providers, but either drain may itself be a
+/// `LevelRouter`, to route more than two ranges of levels.
+#[derive(Debug)]
+pub struct LevelRouter<A, B> {
+    threshold: slog::Level,
+    severe: A,
+    other: B,
+}
+
+/// A [`LevelRouter`] between `Dtrace` drains with different providers, see
+/// [`DtraceBuilder::build_routed`].
+pub type RoutedDtrace<P, Q> = LevelRouter<Dtrace<slog::Discard, P>, Dtrace<slog::Discard, Q>>;
+
+impl<A, B> LevelRouter<A, B> {
+    /// Create a drain forwarding messages at `threshold` or more severe to `severe`, and all
+    /// others to `other`.
+    pub fn new(threshold: slog::Level, severe: A, other: B) -> Self {
+        Self {
+            threshold,
+            severe,
+            other,
+        }
+    }
+}
+
+impl<A, B> Drain for LevelRouter<A, B>
+where
+    A: Drain<Ok = (), Err = slog::Never>,
+    B: Drain<Ok = (), Err = slog::Never>,
+{
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.threshold) {
+            self.severe.log(record, values)
+        } else {
+            self.other.log(record, values)
+        }
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        if level.is_at_least(self.threshold) {
+            self.severe.is_enabled(level)
+        } else {
+            self.other.is_enabled(level)
+        }
+    }
+}
+
+/// A [`slog::Drain`] that forwards each log message to one of several drains, depending on the
+/// module from which it was issued.
+///
+/// DTrace enables probes by provider, so allowing operators to trace, say, `http:::info` apart
+/// from `db:::info` needs a provider for each module. `usdt` defines providers when its macros
+/// are expanded, and can't create them at runtime, so they can't be derived from module paths as
+/// new modules appear. Instead, define a provider for each module of interest with [`provider!`],
+/// and route the modules to drains using them with this type. Since the providers are fixed when
+/// the program is compiled, their number is bounded, and all are registered up front, with no
+/// cost when a module first logs.
+///
+/// Each route is a prefix of a module path, matched as by [`ModuleFilter`]. A message goes to the
+/// drain with the longest prefix matching its module, or to the fallback drain if none does.
+///
+/// ```
+/// use slog_dtrace::{DtraceBuilder, ModuleRouter};
+///
+/// slog_dtrace::provider!(Http, http_probes, "http");
+/// slog_dtrace::provider!(Db, db_probes, "db");
+///
+/// let (http, _) = DtraceBuilder::new().provider::<Http>().build();
+/// let (db, _) = DtraceBuilder::new().provider::<Db>().build();
+/// let (other, _) = DtraceBuilder::new().build();
+/// let router = ModuleRouter::new(Box::new(other))
+///     .route("my_app::http", Box::new(http))
+///     .route("my_app::db", Box::new(db));
+/// let log = slog::Logger::root(router, slog::o!());
+/// ```
+pub struct ModuleRouter {
+    // Sorted by decreasing length of the prefix, so that the first match is the longest.
+    routes: Vec<(String, BoxedDrain)>,
+    fallback: BoxedDrain,
+}
+
+impl ModuleRouter {
+    /// Create a drain forwarding all messages to `fallback`, until routes are added.
+    pub fn new(fallback: BoxedDrain) -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback,
+        }
+    }
+
+    /// Forward messages from `module`, and all of its submodules, to `drain`.
+    ///
+    /// This replaces any drain already routed from the same module.
+    pub fn route(mut self, module: &str, drain: BoxedDrain) -> Self {
+        self.routes.retain(|(prefix, _)| prefix != module);
+        let i = self
+            .routes
+            .partition_point(|(prefix, _)| prefix.len() >= module.len());
+        self.routes.insert(i, (module.to_string(), drain));
+        self
+    }
+
+    // Return the drain to which messages from `module` are routed.
+    fn drain(&self, module: &str) -> &BoxedDrain {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| module_has_prefix(module, prefix))
+            .map_or(&self.fallback, |(_, drain)| drain)
+    }
+}
+
+impl std::fmt::Debug for ModuleRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let routes: Vec<_> = self.routes.iter().map(|(prefix, _)| prefix).collect();
+        f.debug_struct("ModuleRouter")
+            .field("routes", &routes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drain for ModuleRouter {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.drain(record.module()).log(record, values)
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        self.fallback.is_enabled(level)
+            || self.routes.iter().any(|(_, drain)| drain.is_enabled(level))
+    }
+}
+
+/// The format in which messages are emitted to DTrace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Messages are emitted as JSON strings via the per-level probes, e.g., `info`.
+    ///
+    /// These may be printed with `copyinstr(arg0)`, or inspected with the `json()` subroutine.
+    #[default]
+    Json,
+
+    /// Messages are encoded with MessagePack, and emitted via the `<level>_bytes` probes.
+    ///
+    /// These probes take a pointer to the encoded message as `arg0` and its length as `arg1`, so
+    /// that it may be copied out with `tracemem(copyin(arg0, arg1), 1024, arg1)`, for example,
+    /// and decoded elsewhere. This is more compact than JSON, but numeric keys set with
+    /// [`DtraceBuilder::numeric_key`] are not passed as separate arguments, and any limit set with
+    /// [`DtraceBuilder::max_message_bytes`] still applies to the JSON encoding.
+    MessagePack,
+
+    /// Messages are formatted as a line of tab-separated values, and emitted via the
+    /// `<level>_bytes` probes.
+    ///
+    /// This suits consumers which process DTrace output with line-oriented tools such as `awk`.
+    /// The line may be printed with `printf("%s\n", copyinstr(arg0, arg1))`, for example. See
+    /// [`Message::to_tsv`] for its columns. As for [`Format::MessagePack`], numeric keys are not
+    /// passed as separate arguments, and any size limit still applies to the JSON encoding.
+    Tsv,
+
+    /// Messages are emitted as JSON without the result-like wrapper, via the `<level>_bytes`
+    /// probes.
+    ///
+    /// `usdt` serializes each probe argument of a Rust type as JSON wrapped in an object with the
+    /// key `"ok"`, or `"err"` if serialization failed, since it has no other way to report the
+    /// failure to DTrace. This can't be configured, so the probes taking a [`Message`], as with
+    /// [`Format::Json`], always emit it wrapped. In this format, the message is instead serialized
+    /// by this crate, with [`Message::to_bare_json`], and emitted as a buffer: `arg0` points to
+    /// the JSON, which is not NUL-terminated, and `arg1` is its length. Its fields may then be
+    /// addressed directly, for example:
+    ///
+    /// ```text
+    /// dtrace -n 'slog*:::warn_bytes { printf("%s\n", json(copyinstr(arg0, arg1), "message")); }'
+    /// ```
+    ///
+    /// A message which can't be serialized is emitted as `{"err": ...}`, as in the wrapper.
+    /// [`Message::from_probe_line`] parses messages in either form. Numeric keys are not passed
+    /// as separate arguments.
+    BareJson,
+
+    /// Messages are emitted as JSON, prefixed with their length, via the `<level>_bytes` probes.
+    ///
+    /// Each buffer is a frame of a 4-byte length, as an unsigned big-endian integer, followed by
+    /// that many bytes of UTF-8 encoded JSON, exactly as returned by [`Message::to_probe_json`],
+    /// without a terminating NUL byte:
+    ///
+    /// ```text
+    /// +--------+--------+--------+--------+----------------------------+
+    /// |        length (u32, big-endian)   |  JSON (`length` bytes)     |
+    /// +--------+--------+--------+--------+----------------------------+
+    /// ```
+    ///
+    /// As for [`Format::MessagePack`], `arg0` points to the frame and `arg1` is its length, so
+    /// that it may be copied out exactly, with `tracemem(copyin(arg0, arg1), 4096, arg1)` for
+    /// example, rather than relying on `copyinstr()` and line-based splitting of the output.
+    /// Frames written back to back may be split and decoded with [`Message::from_framed_json`].
+    /// Numeric keys are not passed as separate arguments, and any size limit applies to the JSON
+    /// payload.
+    FramedJson,
+
+    /// The fields of messages at all levels are emitted as separate arguments of the `fields`
+    /// probe.
+    ///
+    /// This suits the hottest paths, where even the `json()` subroutine is too slow: the level
+    /// and line are integers, which may be used in predicates directly, and the strings need only
+    /// be copied in. The probe is equivalent to this D definition:
+    ///
+    /// ```d
+    /// provider slog {
+    ///     probe fields(uint8_t level, char *msg, char *module, char *file, uint32_t line,
+    ///         char *kv);
+    /// };
+    /// ```
+    ///
+    /// Here `level` is [`Message::level_num`], from 1 for `Critical` to 6 for `Trace`, and `kv`
+    /// is [`Message::kv`] as a JSON object. The other fields of [`Message`] are not emitted. For
+    /// example, to print the location of each error or more severe message:
+    ///
+    /// ```text
+    /// dtrace -n 'slog*:::fields /arg0 <= 2/ { printf("%s:%d %s\n", copyinstr(arg3), arg4, copyinstr(arg1)); }'
+    /// ```
+    ///
+    /// `usdt` can't pass a C struct as a probe argument, so the fields are emitted as arguments,
+    /// rather than as members of `args[0]`. Numeric keys are not passed as separate arguments,
+    /// and [`DtraceBuilder::single_probe`] is ignored.
+    Fields,
+}
+
+/// How repeated keys in the key-value pairs of a message are handled.
+///
+/// A key may appear more than once in a message, for example when a record and its logger both
+/// provide it. Note that the values of a record are visited before those of its logger, and those
+/// of a logger before those of its parent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Keep the last value for each key.
+    ///
+    /// This is the default. It is the value seen by most JSON parsers when reading the output of
+    /// `slog-json`, which emits every value, so DTrace and such logs agree.
+    #[default]
+    KeepLast,
+
+    /// Keep the first value for each key.
+    KeepFirst,
+
+    /// Keep all values for a repeated key, in order, as a JSON array.
+    ///
+    /// Keys which appear only once keep their single value.
+    Collect,
+}
+
+/// A `KvValueFormatter` transforms the value of each key-value pair before it is added to a message.
+///
+/// The formatter is given the key and the JSON value it would otherwise have, after any
+/// [redaction](DtraceBuilder::redact_keys), and returns the value to emit. This allows a program to
+/// apply its own conventions, such as normalizing durations, truncating large values, or
+/// canonicalizing identifiers. Note that values logged with `Display` or `Debug` formatting, such
+/// as `%id` or `?duration`, arrive as strings. See [`DtraceBuilder::kv_formatter`].
+///
+/// This is implemented for closures taking the key and value, and the default,
+/// [`DefaultKvValueFormatter`], returns each value unchanged.
+pub trait KvValueFormatter: Send + Sync + std::panic::RefUnwindSafe {
+    /// Return the value to emit for `key`, given its value as JSON.
+    fn format(&self, key: &str, value: serde_json::Value) -> serde_json::Value;
+}
+
+impl<F> KvValueFormatter for F
+where
+    F: Fn(&str, serde_json::Value) -> serde_json::Value + Send + Sync + std::panic::RefUnwindSafe,
+{
+    fn format(&self, key: &str, value: serde_json::Value) -> serde_json::Value {
+        self(key, value)
+    }
+}
+
+/// The default [`KvValueFormatter`], which returns each value unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultKvValueFormatter;
+
+impl KvValueFormatter for DefaultKvValueFormatter {
+    fn format(&self, _: &str, value: serde_json::Value) -> serde_json::Value {
+        value
+    }
+}
+
+/// A filter on the module from which messages are forwarded to DTrace.
+///
+/// Each entry is a prefix of a module path, such as `my_crate::http`, which matches that module and
+/// all of its submodules, but not, for example, `my_crate::https`. See
+/// [`DtraceBuilder::module_filter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleFilter {
+    /// Forward only messages from modules matching one of these prefixes.
+    Allow(Vec<String>),
+
+    /// Forward messages from all modules except those matching one of these prefixes.
+    Deny(Vec<String>),
+}
+
+impl ModuleFilter {
+    /// Return `true` if messages from `module` pass this filter.
+    pub fn allows(&self, module: &str) -> bool {
+        let matches = |prefixes: &[String]| {
+            prefixes
+                .iter()
+                .any(|prefix| module_has_prefix(module, prefix))
+        };
+        match self {
+            ModuleFilter::Allow(prefixes) => matches(prefixes),
+            ModuleFilter::Deny(prefixes) => !matches(prefixes),
+        }
+    }
+}
+
+// Return true if `module` is the module `prefix`, or one of its submodules.
+fn module_has_prefix(module: &str, prefix: &str) -> bool {
+    module
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+// Options controlling how messages are constructed, set via the `DtraceBuilder`.
+#[derive(Debug, Clone, Default)]
+struct Config {
+    nested_keys: bool,
+    max_message_bytes: Option<usize>,
+    max_serialized_bytes: Option<usize>,
+    max_kv_pairs: Option<usize>,
+    max_value_bytes: Option<usize>,
+    numeric_key: Option<String>,
+    redact_keys: Arc<[String]>,
+    file_prefixes: Arc<[String]>,
+    levels: Option<Vec<slog::Level>>,
+    module_filter: Option<ModuleFilter>,
+    thread_names: bool,
+    tags: bool,
+    message_hashes: bool,
+    recent_messages: usize,
+    verbose: VerboseGate,
+    coalesce: Option<std::time::Duration>,
+    stats_interval: Option<std::time::Duration>,
+    slow_messages: Option<Arc<SlowMessages>>,
+    slow_message_logger: Option<slog::Logger>,
+    // Whether not to warn when probes can't be registered, see
+    // `DtraceBuilder::warn_on_registration_failure`.
+    quiet_registration_failure: bool,
+    backtrace_levels: Vec<slog::Level>,
+    startup_probe: bool,
+    process_token: Option<u64>,
+    hostname: Option<String>,
+    format: Format,
+    epoch: Option<std::time::Instant>,
+    last_message: Option<Arc<LastMessage>>,
+    batch: Option<Batch>,
+    // The sampling rate of each level, indexed as in `LevelCounters`.
+    sample_rates: [Option<u64>; 6],
+    duplicate_keys: DuplicateKeyPolicy,
+    compact_field_names: bool,
+    key_scopes: bool,
+    single_probe: bool,
+    probe_names: ProbeNames,
+    timestamp_format: TimestampFormat,
+    timestamp_precision: TimestampPrecision,
+    sorted_keys: bool,
+    printf_safe: bool,
+    omit_timestamps: bool,
+    clock: Clock,
+    clock_source: Option<String>,
+    kv_formatter: ValueFormatter,
+    context: Option<Arc<serde_json::Value>>,
+    labels: Option<Arc<JsonMap>>,
+    loggers: Option<Arc<LoggerKv>>,
+    transform: Option<Transform>,
+    kv_filter: Option<KvFilter>,
+    required_keys: Arc<[String]>,
+    request_keys: Option<RequestKeys>,
+}
+
+// The keys whose values are passed to the `request` probe, see `DtraceBuilder::request_probe`.
+#[derive(Debug, Clone)]
+struct RequestKeys {
+    latency: String,
+    bytes: String,
+}
+
+impl RequestKeys {
+    // Return the arguments to the `request` probe for a message.
+    fn args(&self, msg: Message) -> (Message, u64, u64) {
+        let value = |key: &str| {
+            msg.kv
+                .get(key)
+                .and_then(|value| value.as_u64().or_else(|| value.as_i64().map(|v| v as u64)))
+                .unwrap_or(0)
+        };
+        let (latency, bytes) = (value(&self.latency), value(&self.bytes));
+        (msg, latency, bytes)
+    }
+}
+
+// The source of the timestamp of each message, see `DtraceBuilder::clock`.
+#[derive(Clone)]
+struct Clock(Arc<dyn Fn() -> DateTime<Utc> + Send + Sync + std::panic::RefUnwindSafe>);
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self(Arc::new(Utc::now))
+    }
+}
+
+impl std::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Clock").finish_non_exhaustive()
+    }
+}
+
+// The IDs of the key-value pairs of loggers which have been emitted, see
+// `DtraceBuilder::logger_kv_once`.
+#[derive(Debug, Default)]
+struct LoggerKv {
+    emitted: std::sync::Mutex<std::collections::HashSet<u64>>,
+}
+
+impl LoggerKv {
+    // The number of IDs remembered, beyond which all are forgotten.
+    const MAX_IDS: usize = 4096;
+
+    // Return the ID of the key-value pairs of `values`, emitting them via the `logger` probe if
+    // they haven't been already.
+    fn id<P>(&self, record: &slog::Record<'_>, values: &slog::OwnedKVList, config: &Config) -> u64
+    where
+        P: Provider,
+    {
+        let mut serializer = Serializer::with_config(config);
+        serializer.scope = LOGGER_SCOPE;
+        let result = values.serialize(record, &mut serializer);
+        serializer.record_result(result);
+        let kv = serializer.finish();
+        let mut hash = Fnv1a::default();
+        hash.write(serde_json::to_string(&kv).unwrap_or_default().as_bytes());
+        let id = hash.0;
+
+        let mut emitted = self.emitted.lock().unwrap_or_else(|e| e.into_inner());
+        if !emitted.contains(&id) {
+            let fired = std::cell::Cell::new(false);
+            P::fire_logger(|| {
+                fired.set(true);
+                (id, kv.clone())
+            });
+            if fired.get() {
+                if emitted.len() >= Self::MAX_IDS {
+                    emitted.clear();
+                }
+                emitted.insert(id);
+            }
+        }
+        id
+    }
+}
+
+// The function applied to each message, see `DtraceBuilder::transform`.
+#[derive(Clone)]
+struct Transform(Arc<dyn Fn(&mut Message) + Send + Sync + std::panic::RefUnwindSafe>);
+
+impl std::fmt::Debug for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transform").finish_non_exhaustive()
+    }
+}
+
+// The predicate selecting messages by their key-value pairs, see `DtraceBuilder::kv_filter`.
+#[derive(Clone)]
+struct KvFilter(Arc<dyn Fn(&JsonMap) -> bool + Send + Sync + std::panic::RefUnwindSafe>);
+
+impl std::fmt::Debug for KvFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvFilter").finish_non_exhaustive()
+    }
+}
+
+// How messages are batched by the `AsyncDtrace` drain, see `DtraceBuilder::batch`.
+#[derive(Debug, Clone, Copy)]
+struct Batch {
+    max_messages: usize,
+    window: std::time::Duration,
+}
+
+// The formatter applied to each value, see `DtraceBuilder::kv_formatter`.
+#[derive(Clone)]
+struct ValueFormatter(Arc<dyn KvValueFormatter>);
+
+impl Default for ValueFormatter {
+    fn default() -> Self {
+        Self(Arc::new(DefaultKvValueFormatter))
+    }
+}
+
+impl std::fmt::Debug for ValueFormatter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValueFormatter").finish_non_exhaustive()
+    }
+}
+
+// The state of a drain coalescing identical consecutive messages, see `DtraceBuilder::coalesce`.
+#[derive(Debug)]
+struct Coalescer {
+    window: std::time::Duration,
+    last: std::sync::Mutex<Option<Held>>,
+}
+
+// The last message handled by a `Coalescer`, and the identical messages held back since then.
+#[derive(Debug)]
+struct Held {
+    level: slog::Level,
+    value: Option<u64>,
+    msg: Message,
+    // The number of messages held back, and the time at which the last was emitted.
+    count: u64,
+    emitted: std::time::Instant,
+}
+
+// What to do with a message passed to a `Coalescer`.
+#[allow(clippy::large_enum_variant)]
+enum Coalesced {
+    // Hold back the message, which repeats the previous one.
+    Held,
+    // Emit the message, after any repeats held back before it.
+    Emit(Option<Held>),
+}
+
+impl Coalescer {
+    fn new(window: std::time::Duration) -> Self {
+        Self {
+            window,
+            last: std::sync::Mutex::new(None),
+        }
+    }
+
+    // Record a message at `level`, returning whether it should be emitted.
+    //
+    // If the message is emitted in place of those held back, its `repeat_count` is set.
+    fn coalesce(&self, level: slog::Level, value: Option<u64>, msg: &mut Message) -> Coalesced {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let now = std::time::Instant::now();
+        match last.as_mut() {
+            Some(held) if held.repeats(msg) => {
+                held.count += 1;
+                if now.duration_since(held.emitted) < self.window {
+                    held.msg = msg.clone();
+                    return Coalesced::Held;
+                }
+                msg.repeat_count = Some(held.count);
+                held.count = 0;
+                held.emitted = now;
+                Coalesced::Emit(None)
+            }
+            _ => {
+                let next = Held {
+                    level,
+                    value,
+                    msg: msg.clone(),
+                    count: 0,
+                    emitted: now,
+                };
+                let flushed = last
+                    .replace(next)
+                    .filter(|held| held.count > 0)
+                    .map(|mut held| {
+                        held.msg.repeat_count = Some(held.count);
+                        held
+                    });
+                Coalesced::Emit(flushed)
+            }
+        }
+    }
+}
+
+impl Held {
+    // Return `true` if `msg` is identical to the held message, ignoring when it was issued.
+    fn repeats(&self, msg: &Message) -> bool {
+        self.msg.level_num == msg.level_num
+            && self.msg.location == msg.location
+            && self.msg.message == msg.message
+            && self.msg.kv == msg.kv
+    }
+}
+
+// The time of the last message from a drain, see `DtraceBuilder::message_deltas`.
+#[derive(Debug)]
+struct LastMessage {
+    epoch: std::time::Instant,
+    // Nanoseconds since `epoch`, plus one, or zero before the first message.
+    last: AtomicU64,
+}
+
+impl Default for LastMessage {
+    fn default() -> Self {
+        Self {
+            epoch: std::time::Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LastMessage {
+    // Record a message now, and return the time since the previous one, if any.
+    fn delta(&self) -> Option<u64> {
+        let now = u64::try_from(self.epoch.elapsed().as_nanos())
+            .unwrap_or(u64::MAX - 1)
+            .saturating_add(1);
+        let last = self.last.swap(now, Ordering::Relaxed);
+        (last != 0).then(|| now.saturating_sub(last))
+    }
+}
+
+impl Config {
+    // Return the key whose value is passed to the `<level>_value` probes, if it is set, not
+    // redacted, and those probes are used.
+    fn numeric_key(&self) -> Option<&str> {
+        self.numeric_key.as_deref().filter(|key| {
+            !self.single_probe
+                && self.probe_names == ProbeNames::Levels
+                && !is_redacted(&self.redact_keys, key)
+        })
+    }
+
+    // Return the rate at which messages at `level` are sampled, if they are.
+    fn sample_rate(&self, level: slog::Level) -> Option<u64> {
+        self.sample_rates[LevelCounters::index(level)]
+    }
+
+    // Record a message at `level` from `module`, and return true if it should be forwarded to
+    // DTrace.
+    fn forwards(&self, counters: &LevelCounters, level: slog::Level, module: &str) -> bool {
+        let count = counters.record_total(level);
+        if let Some(levels) = &self.levels {
+            if !levels.contains(&level) {
+                return false;
+            }
+        }
+        if let Some(filter) = &self.module_filter {
+            if !filter.allows(module) {
+                return false;
+            }
+        }
+        if !self.verbose.is_verbose() {
+            return false;
+        }
+        match self.sample_rate(level) {
+            Some(rate) if count % rate != 0 => {
+                counters.record_sampled_out(level);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+// Remove the first of `prefixes` matching the path `file`, see `DtraceBuilder::strip_file_prefixes`.
+fn strip_file_prefix(prefixes: &[String], file: String) -> String {
+    prefixes
+        .iter()
+        .find_map(|prefix| {
+            std::path::Path::new(&file)
+                .strip_prefix(prefix)
+                .ok()
+                .and_then(|path| path.to_str())
+                .map(String::from)
+        })
+        .unwrap_or(file)
+}
+
+// Return true if `key` matches any of the lowercase `patterns`, see `DtraceBuilder::redact_keys`.
+fn is_redacted(patterns: &[String], key: &str) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+    let key = key.to_lowercase();
+    patterns.iter().any(|pattern| {
+        if let Some(suffix) = pattern.strip_prefix('*') {
+            key.ends_with(suffix)
+        } else if let Some(prefix) = pattern.strip_suffix('*') {
+            key.starts_with(prefix)
+        } else {
+            key == *pattern
+        }
+    })
+}
+
+// Create a message to emit to DTrace
+fn create_dtrace_message(
+    record: &slog::Record,
+    values: &slog::OwnedKVList,
+    config: &Config,
+) -> Message {
+    let location = Location::from_record(record);
+    let start = config
+        .slow_messages
+        .as_ref()
+        .map(|_| std::time::Instant::now());
+    let mut serializer = Serializer::with_config(config);
+    serializer.scope = RECORD_SCOPE;
+    let result = record.kv().serialize(record, &mut serializer);
+    serializer.record_result(result);
+    if config.loggers.is_none() {
+        serializer.scope = LOGGER_SCOPE;
+        let result = values.serialize(record, &mut serializer);
+        serializer.record_result(result);
+    }
+    if let (Some(slow), Some(start)) = (&config.slow_messages, start) {
+        slow.check(record, start.elapsed());
+    }
+    let scopes = serializer.scopes.take();
+    let mut msg = start_message(
+        config,
+        location,
+        record.column(),
+        record.level(),
+        record.msg().to_string(),
+        serializer.finish(),
+    );
+    msg.scopes = scopes;
+    msg.tag = record_tag(config, record);
+    finish_message(config, msg)
+}
+
+// Return the tag of a record, if it has one and tags are included, see `Message::tag`.
+fn record_tag(config: &Config, record: &slog::Record<'_>) -> Option<String> {
+    Some(record.tag())
+        .filter(|tag| config.tags && !tag.is_empty())
+        .map(String::from)
+}
+
+// The scopes of key-value pairs from a record and its loggers, see `Message::scopes`.
+const RECORD_SCOPE: &str = "record";
+const LOGGER_SCOPE: &str = "logger";
+
+// Create a message from its parts, adding the other fields requested in `config`.
+#[cfg(any(feature = "tracing", feature = "log"))]
+fn new_message(
+    config: &Config,
+    location: Location,
+    level: slog::Level,
+    message: String,
+    kv: JsonMap,
+    scopes: Option<Scopes>,
+) -> Message {
+    let mut msg = start_message(config, location, 0, level, message, kv);
+    msg.scopes = scopes;
+    finish_message(config, msg)
+}
+
+// Create a message from its parts, capturing the time and current thread, but without applying
+// any size limit. `column` is that of the logging statement, or zero if unknown.
+fn start_message(
+    config: &Config,
+    location: Location,
+    column: u32,
+    level: slog::Level,
+    message: String,
+    kv: JsonMap,
+) -> Message {
+    let (trace_id, span_id) = otel_ids();
+    let location = Location {
+        file: strip_file_prefix(&config.file_prefixes, location.file),
+        ..location
+    };
+    let msg_hash = config.message_hashes.then(|| site_hash(&location, column));
+    let kv = match &config.labels {
+        Some(labels) => {
+            let mut merged = JsonMap::clone(labels);
+            merged.extend(kv);
+            merged
+        }
+        None => kv,
+    };
+    Message {
+        schema_version: SCHEMA_VERSION,
+        location,
+        timestamp: (!config.omit_timestamps).then(|| (config.clock.0)()),
+        clock_source: if config.omit_timestamps {
+            None
+        } else {
+            config.clock_source.clone()
+        },
+        monotonic_ns: config
+            .epoch
+            .map(|epoch| u64::try_from(epoch.elapsed().as_nanos()).unwrap_or(u64::MAX)),
+        delta_ns: config.last_message.as_ref().and_then(|last| last.delta()),
+        level: level.as_str().to_string(),
+        level_num: level.as_usize() as u8,
+        severity: syslog_severity(level),
+        message,
+        kv,
+        pid: std::process::id(),
+        process_token: config.process_token,
+        hostname: config.hostname.clone(),
+        trace_id,
+        span_id,
+        source_crate: None,
+        source_version: None,
+        context: config.context.as_deref().cloned(),
+        logger_id: None,
+        msg_hash,
+        tag: None,
+        thread_id: current_thread_id(),
+        thread_name: if config.thread_names {
+            std::thread::current().name().map(String::from)
+        } else {
+            None
+        },
+        backtrace: if config.backtrace_levels.contains(&level) {
+            capture_backtrace()
+        } else {
+            None
+        },
+        sample_rate: config.sample_rate(level),
+        truncated: false,
+        printf_escaped: false,
+        original_len: None,
+        repeat_count: None,
+        scopes: None,
+        compact: config.compact_field_names,
+        timestamp_format: config.timestamp_format,
+        timestamp_precision: config.timestamp_precision,
+        sorted_keys: config.sorted_keys,
+    }
+}
+
+// Append `value` to a line of tab-separated values, escaping it as described by `Message::to_tsv`.
+fn push_tsv_escaped(line: &mut String, value: &str, is_key: bool) {
+    for c in value.chars() {
+        match c {
+            '\\' => line.push_str("\\\\"),
+            '\t' => line.push_str("\\t"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '=' if is_key => line.push_str("\\="),
+            c => line.push(c),
+        }
+    }
+}
+
+// Capture a backtrace of the current thread, if enabled by the environment.
+fn capture_backtrace() -> Option<String> {
+    let backtrace = std::backtrace::Backtrace::capture();
+    match backtrace.status() {
+        std::backtrace::BacktraceStatus::Captured => Some(backtrace.to_string()),
+        _ => None,
+    }
+}
+
+// Apply any size limit in `config` to a message created with `start_message`.
+fn finish_message(config: &Config, mut msg: Message) -> Message {
+    msg.source_crate = take_source_key(&mut msg, SOURCE_CRATE_KEY);
+    msg.source_version = take_source_key(&mut msg, SOURCE_VERSION_KEY);
+    if let Some(function) = take_source_key(&mut msg, FUNCTION_KEY) {
+        msg.location.function = Some(function);
+    }
+    if let Some(Transform(transform)) = &config.transform {
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| transform(&mut msg)));
+    }
+    if let Some(max) = config.max_message_bytes {
+        truncate_message(&mut msg, max);
+    }
+    if config.printf_safe {
+        map_message_strings(&mut msg, |s| s.replace('%', "%%"));
+        msg.printf_escaped = true;
+    }
+    let max = config
+        .max_serialized_bytes
+        .unwrap_or(DEFAULT_MAX_SERIALIZED_BYTES);
+    if max != usize::MAX {
+        let len = emitted_len(&msg);
+        if len > max {
+            msg.message = String::from(OVERSIZED_MESSAGE);
+            msg.kv.clear();
+            msg.scopes = None;
+            msg.truncated = true;
+            msg.original_len = Some(len);
+        }
+    }
+    msg
+}
+
+// Replace each string of `msg` with the result of `f`, see `DtraceBuilder::printf_safe`.
+fn map_message_strings(msg: &mut Message, f: fn(&str) -> String) {
+    let map = |s: &mut String| *s = f(s);
+    map(&mut msg.location.module);
+    map(&mut msg.location.file);
+    map(&mut msg.message);
+    [
+        &mut msg.clock_source,
+        &mut msg.tag,
+        &mut msg.hostname,
+        &mut msg.trace_id,
+        &mut msg.span_id,
+        &mut msg.source_crate,
+        &mut msg.source_version,
+        &mut msg.thread_name,
+        &mut msg.backtrace,
+    ]
+    .into_iter()
+    .flatten()
+    .for_each(map);
+    msg.kv = map_object_strings(std::mem::take(&mut msg.kv), f);
+    if let Some(context) = msg.context.take() {
+        msg.context = Some(map_value_strings(context, f));
+    }
+    if let Some(scopes) = msg.scopes.take() {
+        let scopes = scopes
+            .into_iter()
+            .map(|(key, scopes)| (f(&key), scopes.iter().map(|scope| f(scope)).collect()))
+            .collect();
+        msg.scopes = Some(scopes);
+    }
+}
+
+// Replace each key and string in `map` with the result of `f`, recursively.
+fn map_object_strings(map: JsonMap, f: fn(&str) -> String) -> JsonMap {
+    map.into_iter()
+        .map(|(key, value)| (f(&key), map_value_strings(value, f)))
+        .collect()
+}
+
+// Replace each string in `value` with the result of `f`, recursively.
+fn map_value_strings(value: serde_json::Value, f: fn(&str) -> String) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => f(&s).into(),
+        serde_json::Value::Array(values) => values
+            .into_iter()
+            .map(|value| map_value_strings(value, f))
+            .collect(),
+        serde_json::Value::Object(map) => map_object_strings(map, f).into(),
+        value => value,
+    }
+}
+
+// The text of a message replaced because it exceeds `DtraceBuilder::max_serialized_bytes`.
+const OVERSIZED_MESSAGE: &str = "message dropped: serialized size exceeds the maximum";
+
+// Remove one of the keys set by the `source!` macro from a message, and return its value.
+fn take_source_key(msg: &mut Message, key: &str) -> Option<String> {
+    let value = msg.kv.remove(key)?;
+    if let Some(scopes) = &mut msg.scopes {
+        scopes.remove(key);
+    }
+    match value {
+        serde_json::Value::String(s) => Some(s),
+        value => Some(value.to_string()),
+    }
+}
+
+// Return the IDs of the active OpenTelemetry trace and span, if there is one.
+#[cfg(feature = "otel")]
+fn otel_ids() -> (Option<String>, Option<String>) {
+    use opentelemetry::trace::TraceContextExt;
+    let context = opentelemetry::Context::current();
+    let span = context.span();
+    let span_context = span.span_context();
+    if span_context.is_valid() {
+        (
+            Some(span_context.trace_id().to_string()),
+            Some(span_context.span_id().to_string()),
+        )
+    } else {
+        (None, None)
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+fn otel_ids() -> (Option<String>, Option<String>) {
+    (None, None)
+}
+
+// Return the identifier of the current thread, assigning one if needed.
+fn current_thread_id() -> u64 {
+    static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    THREAD_ID.with(|id| *id)
+}
+
+// Generate a random token used to identify a process.
+fn new_process_token() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+// Return the name of this host, if it can be determined.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // Safety: the buffer is valid for writes of its length, and is not guaranteed to be
+    // NUL-terminated if the name is truncated, which is handled below.
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+// The length of the string emitted to DTrace for a message, including the `{"ok":...}` wrapper
+// added by `usdt`.
+fn emitted_len(msg: &Message) -> usize {
+    const WRAPPER_LEN: usize = r#"{"ok":}"#.len();
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, msg).map_or(0, |_| counter.0) + WRAPPER_LEN
+}
+
+// A writer which only counts the bytes written to it, to measure messages without allocating.
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Encode a message with MessagePack, reusing this thread's buffer.
+//
+// The buffer is returned for reuse when the probe has fired, see `__private::ProbeBuffer`.
+fn encode_message(msg: &Message) -> Vec<u8> {
+    let mut buf = __private::take_buffer();
+    if rmp_serde::encode::write_named(&mut buf, msg).is_err() {
+        buf.clear();
+    }
+    buf
+}
+
+// Encode a message as a frame of JSON prefixed with its length, reusing this thread's buffer.
+//
+// See `Format::FramedJson` for the layout.
+fn frame_message(msg: &Message) -> Vec<u8> {
+    let mut buf = __private::take_buffer();
+    buf.extend_from_slice(&[0; FRAME_HEADER_LEN]);
+    buf.extend_from_slice(msg.to_probe_json().as_bytes());
+    let len = u32::try_from(buf.len() - FRAME_HEADER_LEN).unwrap_or(u32::MAX);
+    buf[..FRAME_HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+    buf
+}
+
+// The length of the header of a frame, see `Format::FramedJson`.
+const FRAME_HEADER_LEN: usize = 4;
+
+// The length of a character once escaped in a JSON string.
+fn escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+// Truncate a message so that its emitted length, plus a NUL byte, is at most `max` bytes.
+//
+// Key-value pairs are dropped largest-first until the message without its text fits, and then as
+// much of the text as possible is kept. This is best-effort: if the remaining fields alone exceed
+// the limit, the message is left too long.
+fn truncate_message(msg: &mut Message, max: usize) {
+    let budget = max.saturating_sub(1);
+    let original_len = emitted_len(msg);
+    if original_len <= budget {
+        return;
+    }
+    msg.truncated = true;
+    msg.original_len = Some(original_len);
+    let message = std::mem::take(&mut msg.message);
+
+    let mut sizes: Vec<(String, usize)> = msg
+        .kv
+        .iter()
+        .map(|(key, value)| (key.clone(), key.len() + value.to_string().len()))
+        .collect();
+    sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    let mut sizes = sizes.into_iter();
+    while emitted_len(msg) > budget {
+        match sizes.next() {
+            Some((key, _)) => {
+                msg.kv.remove(&key);
+                if let Some(scopes) = &mut msg.scopes {
+                    scopes.remove(&key);
+                }
+            }
+            None => break,
+        }
+    }
+
+    let mut available = budget.saturating_sub(emitted_len(msg));
+    let end = message
+        .char_indices()
+        .find_map(|(i, c)| match available.checked_sub(escaped_len(c)) {
+            Some(rest) => {
+                available = rest;
+                None
+            }
+            None => Some(i),
+        })
+        .unwrap_or(message.len());
+    msg.message = message[..end].to_string();
+}
+
+impl<D, P> Dtrace<D, P>
+where
+    P: Provider,
+{
+    // Forward a message to DTrace, without logging any warnings about slow messages.
+    fn forward(&self, record: &slog::Record<'_>, values: &slog::OwnedKVList) {
+        if self.noop || !self.is_registered() {
+            return;
+        }
+        if let Some(stats) = &self.stats {
+            stats.fire::<P>(&self.counters);
+        }
+        if !self
+            .config
+            .forwards(&self.counters, record.level(), record.module())
+        {
+            return;
+        }
+        let value = match self.config.format {
+            Format::Json => self
+                .config
+                .numeric_key()
+                .and_then(|key| find_numeric_value(record, values, key)),
+            Format::MessagePack
+            | Format::Tsv
+            | Format::BareJson
+            | Format::FramedJson
+            | Format::Fields => None,
+        };
+        if !has_keys(record, values, &self.config.required_keys) {
+            return;
+        }
+        let create = || {
+            let mut msg = create_dtrace_message(record, values, &self.config);
+            if let Some(loggers) = &self.config.loggers {
+                msg.logger_id = Some(loggers.id::<P>(record, values, &self.config));
+            }
+            msg
+        };
+        // Messages retained in memory, compared with the previous one, or filtered by their
+        // key-value pairs, are constructed regardless of whether any probe is enabled.
+        let mut retained =
+            (self.recent.is_some() || self.coalescer.is_some() || self.config.kv_filter.is_some())
+                .then(create);
+        if let (Some(filter), Some(msg)) = (&self.config.kv_filter, &retained) {
+            if !(filter.0)(&msg.kv) {
+                return;
+            }
+        }
+        if let (Some(recent), Some(msg)) = (&self.recent, &retained) {
+            recent.push(msg.clone());
+        }
+        if let (Some(coalescer), Some(msg)) = (&self.coalescer, &mut retained) {
+            match coalescer.coalesce(record.level(), value, msg) {
+                Coalesced::Held => {
+                    self.counters.record_coalesced(record.level());
+                    return;
+                }
+                Coalesced::Emit(None) => {}
+                Coalesced::Emit(Some(held)) => {
+                    let f = || held.msg.clone();
+                    fire_probe::<P, _>(&self.config, &self.counters, held.level, held.value, f);
+                }
+            }
+        }
+        let f = || retained.clone().unwrap_or_else(create);
+        fire_probe::<P, _>(&self.config, &self.counters, record.level(), value, f);
+        fire_request::<P, _>(&self.config, f);
+        let tag = record.tag();
+        if !tag.is_empty() {
+            P::fire_tagged(|| (tag.to_string(), f()));
+        }
+    }
+}
+
+impl<D, P> Drain for Dtrace<D, P>
+where
+    D: Drain<Ok = (), Err = slog::Never>,
+    P: Provider,
+{
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.forward(record, values);
+        self.log_slow_warnings(self);
+        Ok(())
+    }
+}
+
+// Fire the probe for a message at `level`, in the configured format.
+//
+// If `value` is provided, the message is emitted via the `<level>_value` probes. If a single probe
+// is configured, the message is always emitted via the `message` probe.
+fn fire_probe<P, F>(
+    config: &Config,
+    counters: &LevelCounters,
+    level: slog::Level,
+    value: Option<u64>,
+    f: F,
+) where
+    P: Provider,
+    F: FnOnce() -> Message + Clone,
+{
+    let f = || {
+        counters.record_fired(level);
+        let msg = f();
+        if msg.truncated {
+            counters.record_truncated(level);
+        }
+        msg
+    };
+    match (config.format, value) {
+        (Format::Fields, _) => P::fire_fields(f),
+        _ if config.single_probe => P::fire_message(f),
+        _ if config.probe_names == ProbeNames::Numeric => P::fire_numbered(level, f),
+        (Format::MessagePack, _) => P::fire_bytes(level, || encode_message(&f())),
+        (Format::Tsv, _) => P::fire_bytes(level, || f().to_tsv().into_bytes()),
+        (Format::BareJson, _) => P::fire_bytes(level, || f().to_bare_json().into_bytes()),
+        (Format::FramedJson, _) => P::fire_bytes(level, || frame_message(&f())),
+        (Format::Json, Some(value)) => P::fire_value(level, || (f(), value)),
+        (Format::Json, None) => P::fire(level, f),
+    }
+}
+
+// Fire the `request` probe for a message, if it is enabled by the configuration.
+fn fire_request<P, F>(config: &Config, f: F)
+where
+    P: Provider,
+    F: FnOnce() -> Message + Clone,
+{
+    if let Some(keys) = &config.request_keys {
+        P::fire_request(|| keys.args(f()));
+    }
+}
+
+// The value emitted in place of those of redacted keys.
+const REDACTED: &str = "***";
+
+// The key under which errors serializing key-value pairs are recorded, see `Message::kv`.
+const ERROR_KEY: &str = "__error";
+
+// The key of the number of pairs dropped, see `DtraceBuilder::max_kv_pairs`.
+const KV_OVERFLOW: &str = "kv_overflow";
+
+// The suffix of the key of the original length of a value, see `DtraceBuilder::max_value_bytes`.
+const VALUE_LEN_SUFFIX: &str = "__len";
+
+// The text ending a truncated value.
+const ELLIPSIS: &str = "…";
+
+/// Serializes slog's key-value pairs into the JSON object of [`Message::kv`].
+///
+/// This is the serializer used by the [`Dtrace`] drain, and may be used on its own to convert
+/// key-value pairs exactly as they would be emitted, for example to write them elsewhere. It
+/// implements [`slog::Serializer`], so that any [`slog::KV`] may be serialized into it, and keys
+/// may also be added directly with [`Serializer::emit`]. A serializer created with
+/// [`DtraceBuilder::serializer`] applies the options of that builder, such as
+/// [`DtraceBuilder::nested_keys`] or [`DtraceBuilder::redact_keys`], while one created with
+/// [`Serializer::new`] uses the defaults.
+///
+/// ```
+/// use slog::{o, record, Level, KV};
+/// use slog_dtrace::{DtraceBuilder, Serializer};
+///
+/// let mut serializer = DtraceBuilder::new().nested_keys(true).serializer();
+/// let kv = o!("req.method" => "GET", "req.path" => "/", "status" => 200);
+/// serializer.serialize_kv(&record!(Level::Info, "", &format_args!(""), slog::b!()), &kv);
+/// serializer.emit("took_ms", 12).unwrap();
+///
+/// let map = serializer.finish();
+/// assert_eq!(map["req"]["method"], "GET");
+/// assert_eq!(map["status"], 200);
+/// assert_eq!(map["took_ms"], 12);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Serializer {
+    map: crate::JsonMap,
+    nested_keys: bool,
+    duplicate_keys: DuplicateKeyPolicy,
+    redact_keys: Arc<[String]>,
+    formatter: ValueFormatter,
+    // The scope of the pairs being serialized, and those of each key, if enabled.
+    scope: &'static str,
+    scopes: Option<Scopes>,
+    // Keys whose values have been collected into an array, see `DuplicateKeyPolicy::Collect`.
+    collected: std::collections::HashSet<String>,
+    // The maximum number of pairs to keep, the number kept, and the number dropped beyond it.
+    max_pairs: Option<usize>,
+    // The maximum length of string values, see `DtraceBuilder::max_value_bytes`.
+    max_value_bytes: Option<usize>,
+    kept: usize,
+    overflow: usize,
+    // The first error returned while serializing the pairs, see `Serializer::record_result`.
+    error: Option<String>,
+}
+
+impl Default for Serializer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serializer {
+    /// Create a serializer with the default configuration.
+    pub fn new() -> Self {
+        Self::with_config(&Config::default())
+    }
+
+    pub(crate) fn with_config(config: &Config) -> Self {
+        Self {
+            map: JsonMap::default(),
+            nested_keys: config.nested_keys,
+            duplicate_keys: config.duplicate_keys,
+            redact_keys: Arc::clone(&config.redact_keys),
+            formatter: config.kv_formatter.clone(),
+            scope: "",
+            scopes: config.key_scopes.then(Scopes::new),
+            collected: std::collections::HashSet::new(),
+            max_pairs: config.max_kv_pairs,
+            max_value_bytes: config.max_value_bytes,
+            kept: 0,
+            overflow: 0,
+            error: None,
+        }
+    }
+
+    // Record the result of serializing a set of key-value pairs.
+    //
+    // A `slog::Value` which fails stops the serialization of the pairs after it in the same set,
+    // but those serialized already are kept, and the first error is added to the map.
+    fn record_result(&mut self, result: slog::Result) {
+        if let Err(e) = result {
+            self.error.get_or_insert_with(|| e.to_string());
+        }
+    }
+
+    /// Serialize the key-value pairs of `kv`, in the context of `record`.
+    ///
+    /// A value which fails to serialize stops the serialization of the pairs after it, but those
+    /// serialized already are kept, and the first error is recorded in the finished map under the
+    /// key `__error`.
+    pub fn serialize_kv<K>(&mut self, record: &slog::Record<'_>, kv: &K)
+    where
+        K: slog::KV + ?Sized,
+    {
+        let result = kv.serialize(record, self);
+        self.record_result(result);
+    }
+
+    /// Return the serialized key-value pairs.
+    ///
+    /// If pairs were dropped beyond [`DtraceBuilder::max_kv_pairs`], their number is added under
+    /// the key `kv_overflow`.
+    pub fn finish(self) -> JsonMap {
+        let mut map = self.map;
+        if self.overflow > 0 {
+            map.insert(String::from(KV_OVERFLOW), self.overflow.into());
+        }
+        if let Some(error) = self.error {
+            map.insert(String::from(ERROR_KEY), error.into());
+        }
+        map
+    }
+
+    /// Add the pair of `key` and `value`.
+    ///
+    /// The pair is subject to the same handling as those serialized from a [`slog::KV`], such as
+    /// redaction, formatting, and the policy for duplicate keys.
+    pub fn emit<T>(&mut self, key: &str, value: T) -> slog::Result
+    where
+        T: Into<serde_json::Value>,
+    {
+        if self.max_pairs.is_some_and(|max| self.kept >= max) {
+            self.overflow += 1;
+            return Ok(());
+        }
+        self.kept += 1;
+        let key = key.to_string();
+        if let Some(scopes) = &mut self.scopes {
+            scopes
+                .entry(key.clone())
+                .or_default()
+                .push(self.scope.to_string());
+        }
+        let value = if is_redacted(&self.redact_keys, &key) {
+            serde_json::Value::from(REDACTED)
+        } else {
+            value.into()
+        };
+        let value = self.formatter.0.format(&key, value);
+        match self
+            .max_value_bytes
+            .and_then(|max| truncate_value(&value, max))
+        {
+            Some((truncated, len)) => {
+                let len_key = format!("{key}{VALUE_LEN_SUFFIX}");
+                self.insert(key, truncated.into());
+                self.insert(len_key, len.into());
+            }
+            None => self.insert(key, value),
+        }
+        Ok(())
+    }
+
+    // Insert a serialized pair into the map, nesting it if enabled.
+    fn insert(&mut self, key: String, value: serde_json::Value) {
+        if self.nested_keys && key.contains('.') {
+            if let Some((map, last)) = nested_entry(&mut self.map, &key) {
+                insert_value(
+                    map,
+                    last.to_string(),
+                    value,
+                    self.duplicate_keys,
+                    &mut self.collected,
+                    &key,
+                );
+                return;
+            }
+        }
+        insert_value(
+            &mut self.map,
+            key.clone(),
+            value,
+            self.duplicate_keys,
+            &mut self.collected,
+            &key,
+        );
+    }
+}
+
+// Return a string `value` longer than `max` bytes shortened to at most `max` bytes, ending with an
+// ellipsis, along with its original length, or `None` if it needn't be shortened.
+fn truncate_value(value: &serde_json::Value, max: usize) -> Option<(String, usize)> {
+    let value = value.as_str().filter(|value| value.len() > max)?;
+    let mut end = max.saturating_sub(ELLIPSIS.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some((format!("{}{ELLIPSIS}", &value[..end]), value.len()))
+}
+
+// Insert a value into `map`, resolving any existing value for `key` according to `policy`.
+//
+// `full_key` identifies the entry among all those in the message, including nested keys.
+fn insert_value(
+    map: &mut JsonMap,
+    key: String,
+    value: serde_json::Value,
+    policy: DuplicateKeyPolicy,
+    collected: &mut std::collections::HashSet<String>,
+    full_key: &str,
+) {
+    use serde_json::map::Entry;
+    let mut entry = match map.entry(key) {
+        Entry::Vacant(entry) => {
+            entry.insert(value);
+            return;
+        }
+        Entry::Occupied(entry) => entry,
+    };
+    match policy {
+        DuplicateKeyPolicy::KeepLast => {
+            entry.insert(value);
+        }
+        DuplicateKeyPolicy::KeepFirst => {}
+        DuplicateKeyPolicy::Collect => {
+            let existing = entry.get_mut();
+            if collected.contains(full_key) {
+                if let serde_json::Value::Array(values) = existing {
+                    values.push(value);
+                    return;
+                }
+            }
+            let first = existing.take();
+            *existing = serde_json::Value::Array(vec![first, value]);
+            collected.insert(full_key.to_string());
+        }
+    }
+}
+
+// Find the object in which the last component of a dotted key should be inserted, creating any
+// intermediate objects. Returns `None` if some component names an existing non-object value.
+fn nested_entry<'a, 'k>(map: &'a mut JsonMap, key: &'k str) -> Option<(&'a mut JsonMap, &'k str)> {
+    let (parents, last) = key.rsplit_once('.')?;
+    if parents.split('.').any(|part| part.is_empty()) || last.is_empty() {
+        return None;
+    }
+    let mut current = &*map;
+    for part in parents.split('.') {
+        match current.get(part) {
+            Some(serde_json::Value::Object(inner)) => current = inner,
+            Some(_) => return None,
+            None => break,
+        }
+    }
+    let mut current = map;
+    for part in parents.split('.') {
+        current = current
+            .entry(part)
+            .or_insert_with(|| serde_json::Value::Object(JsonMap::default()))
+            .as_object_mut()?;
+    }
+    Some((current, last))
+}
+
+macro_rules! impl_emit {
+    ($method:ident, $ty:ty) => {
+        fn $method(&mut self, key: slog::Key, value: $ty) -> slog::Result {
+            self.emit(key, value).unwrap();
+            Ok(())
+        }
+    };
+}
+
+impl slog::Serializer for Serializer {
+    fn emit_arguments(&mut self, key: slog::Key, values: &std::fmt::Arguments<'_>) -> slog::Result {
+        self.emit(key, format!("{}", values))
+    }
+
+    impl_emit!(emit_u8, u8);
+    impl_emit!(emit_u16, u16);
+    impl_emit!(emit_u32, u32);
+    impl_emit!(emit_u64, u64);
+    impl_emit!(emit_i8, i8);
+    impl_emit!(emit_i16, i16);
+    impl_emit!(emit_i32, i32);
+    impl_emit!(emit_i64, i64);
+    impl_emit!(emit_isize, isize);
+    impl_emit!(emit_usize, usize);
+    impl_emit!(emit_bool, bool);
+    impl_emit!(emit_str, &str);
+
+    fn emit_char(&mut self, key: slog::Key, value: char) -> slog::Result {
+        self.emit(key, value.to_string())
+    }
+
+    // JSON numbers can't represent all 128-bit integers exactly, so these are emitted as decimal
+    // strings.
+    fn emit_u128(&mut self, key: slog::Key, value: u128) -> slog::Result {
+        self.emit(key, value.to_string())
+    }
+
+    fn emit_i128(&mut self, key: slog::Key, value: i128) -> slog::Result {
+        self.emit(key, value.to_string())
+    }
+
+    fn emit_f32(&mut self, key: slog::Key, value: f32) -> slog::Result {
+        self.emit(key, float_value(f64::from(value)))
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, value: f64) -> slog::Result {
+        self.emit(key, float_value(value))
+    }
+
+    fn emit_unit(&mut self, key: slog::Key) -> slog::Result {
+        self.emit(key, ())
+    }
+
+    fn emit_bytes(&mut self, key: slog::Key, bytes: &[u8], _: slog::BytesKind) -> slog::Result {
+        let mut map = JsonMap::default();
+        map.insert(String::from(BASE64_KEY), base64_encode(bytes).into());
+        self.emit(key, map)
+    }
+
+    fn emit_none(&mut self, key: slog::Key) -> slog::Result {
+        self.emit(key, serde_json::Value::Null)
+    }
+
+    fn emit_error(
+        &mut self,
+        key: slog::Key,
+        error: &(dyn std::error::Error + 'static),
+    ) -> slog::Result {
+        self.emit(key, error_chain(error))
+    }
+
+    fn emit_serde(&mut self, key: slog::Key, value: &dyn slog::SerdeValue) -> slog::Result {
+        match serde_json::to_value(value.as_serde()) {
+            Ok(value) => self.emit(key, value),
+            Err(e) => self.emit(key, serde_json::json!({ ERROR_KEY: e.to_string() })),
+        }
+    }
+}
+
+// Convert an error to a JSON array of its message, followed by those of each of its sources.
+fn error_chain(error: &(dyn std::error::Error + 'static)) -> serde_json::Value {
+    std::iter::successors(Some(error), |e| e.source())
+        .map(|e| serde_json::Value::from(e.to_string()))
+        .collect()
+}
+
+// Convert a float to JSON, using strings for NaN and infinities, which JSON can't represent.
+fn float_value(value: f64) -> serde_json::Value {
+    if value.is_nan() {
+        serde_json::Value::from("NaN")
+    } else if value == f64::INFINITY {
+        serde_json::Value::from("Infinity")
+    } else if value == f64::NEG_INFINITY {
+        serde_json::Value::from("-Infinity")
+    } else {
+        serde_json::Value::from(value)
+    }
+}
+
+/// A key-value value which emits a timestamp as an RFC 3339 string, in UTC.
+///
+/// `chrono`'s date types don't implement [`slog::Value`], so they're usually logged with their
+/// `Display` or `Debug` formats, which differ from each other and from [`Message::timestamp`].
+/// Wrapping a timestamp in this emits it in the same format as [`Message::timestamp`] is by
+/// default, for example `2022-01-01T12:00:00.500Z`, so that consumers can parse every timestamp
+/// alike. It is emitted as a string, so other drains receive the same format.
+///
+/// ```
+/// use slog::{info, o, Logger};
+/// use slog_dtrace::Timestamp;
+///
+/// let log = Logger::root(slog::Discard, o!());
+/// let deadline = chrono::Utc::now() + chrono::Duration::seconds(30);
+/// info!(log, "request queued"; "deadline" => Timestamp(deadline));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Timestamp<Tz: chrono::TimeZone>(pub DateTime<Tz>);
+
+impl<Tz: chrono::TimeZone> slog::Value for Timestamp<Tz> {
+    fn serialize(
+        &self,
+        _: &slog::Record<'_>,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        let timestamp = self.0.with_timezone(&Utc);
+        serializer.emit_str(
+            key,
+            &timestamp.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        )
+    }
+}
+
+/// The key which marks the synthetic messages emitted by [`Dtrace::emit_test_message`].
+pub const TEST_KEY: &str = "__test";
+
+/// The key used to mark byte values in [`Message::kv`].
+///
+/// Byte slices are emitted as an object with this single key, whose value is the bytes encoded as
+/// standard, padded base64. For example, `&[0u8, 1, 2][..]` is emitted as `{"__b64": "AAEC"}`,
+/// which distinguishes it from an ordinary string.
+pub const BASE64_KEY: &str = "__b64";
+
+// Encode bytes as standard, padded base64.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Find the value of an integer key-value pair, as passed to the `<level>_value` probes.
+//
+// As with `Message::kv`, values from parent loggers take precedence over those of the record.
+fn find_numeric_value(record: &slog::Record, values: &slog::OwnedKVList, key: &str) -> Option<u64> {
+    let mut finder = KeyFinder { key, value: None };
+    record
+        .kv()
+        .serialize(record, &mut finder)
+        .and_then(|_| values.serialize(record, &mut finder))
+        .ok()?;
+    finder.value
+}
+
+// Return true if the record or its loggers have a pair for each of `keys`, without serializing
+// their values.
+fn has_keys(record: &slog::Record, values: &slog::OwnedKVList, keys: &[String]) -> bool {
+    if keys.is_empty() {
+        return true;
+    }
+    let mut finder = KeysFinder {
+        keys,
+        found: vec![false; keys.len()],
+    };
+    let _ = record
+        .kv()
+        .serialize(record, &mut finder)
+        .and_then(|_| values.serialize(record, &mut finder));
+    finder.found.iter().all(|found| *found)
+}
+
+// Type used to find which of a set of keys are present, ignoring their values.
+struct KeysFinder<'a> {
+    keys: &'a [String],
+    found: Vec<bool>,
+}
+
+impl KeysFinder<'_> {
+    fn find(&mut self, key: slog::Key) -> slog::Result {
+        if let Some(i) = self.keys.iter().position(|k| *k == key) {
+            self.found[i] = true;
+        }
+        Ok(())
+    }
+}
+
+impl slog::Serializer for KeysFinder<'_> {
+    // Values of every other type are passed here by default, without being formatted.
+    fn emit_arguments(&mut self, key: slog::Key, _: &std::fmt::Arguments<'_>) -> slog::Result {
+        self.find(key)
+    }
+
+    // The default implementation fails for values without a fallback, which would end the search.
+    fn emit_serde(&mut self, key: slog::Key, _: &dyn slog::SerdeValue) -> slog::Result {
+        self.find(key)
+    }
+}
+
+// Type used to find the integer value of a single key, ignoring all others.
+struct KeyFinder<'a> {
+    key: &'a str,
+    value: Option<u64>,
+}
+
+macro_rules! impl_find {
+    ($method:ident, $ty:ty) => {
+        fn $method(&mut self, key: slog::Key, value: $ty) -> slog::Result {
+            if key == self.key {
+                // Negative values can't be passed as an unsigned argument, so are treated as absent.
+                self.value = u64::try_from(value).ok();
+            }
+            Ok(())
+        }
+    };
+}
+
+impl slog::Serializer for KeyFinder<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, _: &std::fmt::Arguments<'_>) -> slog::Result {
+        if key == self.key {
+            self.value = None;
+        }
+        Ok(())
+    }
+
+    // The default implementation fails for values without a fallback, which would end the search.
+    fn emit_serde(&mut self, key: slog::Key, _: &dyn slog::SerdeValue) -> slog::Result {
+        if key == self.key {
+            self.value = None;
+        }
+        Ok(())
+    }
+
+    impl_find!(emit_u8, u8);
+    impl_find!(emit_u16, u16);
+    impl_find!(emit_u32, u32);
+    impl_find!(emit_u64, u64);
+    impl_find!(emit_i8, i8);
+    impl_find!(emit_i16, i16);
+    impl_find!(emit_i32, i32);
+    impl_find!(emit_i64, i64);
+    impl_find!(emit_isize, isize);
+    impl_find!(emit_usize, usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{b, o, record};
+
+    #[test]
+    fn test_non_finite_floats() {
+        let values = slog::OwnedKVList::from(o!("inf" => f32::INFINITY));
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("nan" => f64::NAN, "neg-inf" => f64::NEG_INFINITY, "finite" => 1.5)
+            ),
+            &values,
+            &Config::default(),
+        );
+        assert_eq!(msg.kv["nan"], serde_json::Value::from("NaN"));
+        assert_eq!(msg.kv["inf"], serde_json::Value::from("Infinity"));
+        assert_eq!(msg.kv["neg-inf"], serde_json::Value::from("-Infinity"));
+        assert_eq!(msg.kv["finite"], serde_json::Value::from(1.5));
+    }
+
+    #[test]
+    fn test_message_from_probe_line() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => "value")
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        let line = format!("{{\"ok\":{}}}\n", serde_json::to_string(&msg).unwrap());
+        assert_eq!(Message::from_probe_line(&line).unwrap(), msg);
+        assert!(matches!(
+            Message::from_probe_line(&line[..line.len() / 2]),
+            Err(ParseError::Truncated)
+        ));
+        assert!(matches!(
+            Message::from_probe_line(r#"{"err":"oops"}"#),
+            Err(ParseError::Serialization(e)) if e == "oops"
+        ));
+        assert!(matches!(
+            Message::from_probe_line("not json"),
+            Err(ParseError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_level_counters() {
+        let (drain, _) = DtraceBuilder::new().min_level(slog::Level::Info).build();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        slog::warn!(log, "a warning");
+        slog::debug!(log, "a debug message");
+        assert_eq!(counters.total(slog::Level::Warning), 1);
+        assert_eq!(counters.total(slog::Level::Debug), 1);
+        assert_eq!(counters.total(slog::Level::Info), 0);
+        assert_eq!(counters.fired(slog::Level::Debug), 0);
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let (drain, _) = DtraceBuilder::new().sample_rate(4).build();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        for _ in 0..10 {
+            slog::info!(log, "a message");
+        }
+        assert_eq!(counters.total(slog::Level::Info), 10);
+        assert_eq!(counters.sampled_out(slog::Level::Info), 7);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_verbose_gate() {
+        let builder = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .verbose(false)
+            .sample_rate(2);
+        let gate = builder.verbose_gate();
+        let (drain, _) = builder.build();
+        let drain = Arc::new(drain);
+        let log = slog::Logger::root(Arc::clone(&drain), o!());
+        assert!(!drain.is_verbose());
+        for i in 0..4 {
+            slog::info!(log, "a message"; "i" => i);
+        }
+        assert!(TestSink::take().is_empty());
+        assert_eq!(drain.counters().total(slog::Level::Info), 4);
+
+        gate.set_verbose(true);
+        assert!(drain.is_verbose());
+        for i in 4..8 {
+            slog::info!(log, "a message"; "i" => i);
+        }
+        let messages: Vec<_> = TestSink::take()
+            .into_iter()
+            .map(|c| c.message.kv["i"].clone())
+            .collect();
+        assert_eq!(messages, [4, 6]);
+
+        drain.set_verbose(false);
+        assert!(!gate.is_verbose());
+        slog::info!(log, "a message");
+        assert!(TestSink::take().is_empty());
+    }
+
+    #[test]
+    fn test_timestamp_value() {
+        let utc = DateTime::parse_from_rfc3339("2022-01-01T12:00:00.5Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let offset = utc.with_timezone(&chrono::FixedOffset::east_opt(3600).unwrap());
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("utc" => Timestamp(utc), "offset" => Timestamp(offset))
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert_eq!(msg.kv["utc"], "2022-01-01T12:00:00.500Z");
+        assert_eq!(msg.kv["offset"], msg.kv["utc"]);
+        assert_eq!(msg.kv["utc"], serde_json::to_value(utc).unwrap());
+    }
+
+    #[test]
+    fn test_standalone_serializer() {
+        let builder = DtraceBuilder::new()
+            .redact_keys(&["secret"])
+            .max_kv_pairs(3);
+        let check = |record: &slog::Record<'_>| {
+            let mut serializer = builder.serializer();
+            serializer.serialize_kv(record, &record.kv());
+            serializer.emit("b", true).unwrap();
+            serializer.emit("c", "dropped").unwrap();
+            assert_eq!(
+                serde_json::Value::Object(serializer.finish()),
+                serde_json::json!({"a": 1, "secret": REDACTED, "b": true, KV_OVERFLOW: 1})
+            );
+
+            // The serializer used by the drain produces the same pairs.
+            let msg =
+                create_dtrace_message(record, &slog::OwnedKVList::from(o!()), &builder.config);
+            let mut serializer = builder.serializer();
+            serializer.serialize_kv(record, &record.kv());
+            assert_eq!(serializer.finish(), msg.kv);
+        };
+        check(&record!(
+            slog::Level::Info,
+            "",
+            &format_args!("a message"),
+            b!("a" => 1, "secret" => "hunter2")
+        ));
+        assert!(Serializer::new().finish().is_empty());
+    }
+
+    #[test]
+    fn test_max_value_bytes() {
+        let config = DtraceBuilder::new().max_value_bytes(64).config;
+        let query = "x".repeat(4096);
+        let accented = "é".repeat(100);
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("query" => &query, "accented" => &accented, "short" => "ok", "n" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.kv["query"], format!("{}{ELLIPSIS}", &query[..61]));
+        assert_eq!(msg.kv["query__len"], 4096);
+        let accented = msg.kv["accented"].as_str().unwrap();
+        assert!(accented.len() <= 64);
+        assert!(accented.ends_with(ELLIPSIS));
+        assert_eq!(msg.kv["accented__len"], 200);
+        assert_eq!(msg.kv["short"], "ok");
+        assert_eq!(msg.kv["n"], 1);
+        assert!(!msg.kv.contains_key("short__len"));
+        assert!(!msg.truncated);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_stats_probe() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .max_message_bytes(512)
+            .sample_rates([(slog::Level::Debug, 2)])
+            .coalesce(std::time::Duration::from_secs(3600))
+            .stats_interval(std::time::Duration::ZERO)
+            .build();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain, o!());
+        TestSink::clear();
+        for _ in 0..2 {
+            slog::info!(log, "{}", "x".repeat(1000));
+        }
+        slog::debug!(log, "first");
+        slog::debug!(log, "second");
+        // The repeat held back is emitted before the first debug message, and is truncated too.
+        assert_eq!(counters.truncated(slog::Level::Info), 2);
+        assert_eq!(counters.coalesced(slog::Level::Info), 1);
+        assert_eq!(counters.sampled_out(slog::Level::Debug), 1);
+        let expected = Degradation {
+            truncated: 2,
+            dropped: 0,
+            sampled_out: 1,
+            coalesced: 1,
+        };
+        assert_eq!(counters.degradation(), expected);
+
+        // The probe fires before each message is handled, and so doesn't count the last, nor
+        // itself.
+        let fired = counters.fired(slog::Level::Info);
+        slog::info!(log, "another");
+        let stats = TestSink::take_stats();
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats[4], expected);
+        assert_eq!(counters.fired(slog::Level::Info), fired + 1);
+        assert_eq!(
+            serde_json::to_value(stats[4]).unwrap(),
+            serde_json::json!({"truncated": 2, "dropped": 0, "sampled_out": 1, "coalesced": 1})
+        );
+    }
+
+    #[test]
+    fn test_stats_timer() {
+        let timer = StatsTimer::new(std::time::Duration::from_secs(3600));
+        assert!(!timer.due());
+        let timer = StatsTimer::new(std::time::Duration::ZERO);
+        assert!(timer.due());
+        assert!(timer.due());
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_coalesce() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .coalesce(std::time::Duration::from_secs(3600))
+            .build();
+        let log = slog::Logger::root(drain, o!());
+        let emitted = || -> Vec<_> {
+            TestSink::take()
+                .into_iter()
+                .map(|c| (c.message.message, c.message.repeat_count))
+                .collect()
+        };
+        for _ in 0..5 {
+            slog::warn!(log, "flapping"; "state" => "down");
+        }
+        assert_eq!(emitted(), [(String::from("flapping"), None)]);
+
+        // A differing message flushes the held count first.
+        slog::warn!(log, "flapping"; "state" => "up");
+        assert_eq!(
+            emitted(),
+            [
+                (String::from("flapping"), Some(4)),
+                (String::from("flapping"), None)
+            ]
+        );
+        slog::info!(log, "other");
+        assert_eq!(emitted(), [(String::from("other"), None)]);
+
+        // Once the window has passed, the next repeat is emitted with the count.
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .coalesce(std::time::Duration::ZERO)
+            .build();
+        let log = slog::Logger::root(drain, o!());
+        for _ in 0..3 {
+            slog::warn!(log, "flapping");
+        }
+        assert_eq!(
+            emitted(),
+            [
+                (String::from("flapping"), None),
+                (String::from("flapping"), Some(1)),
+                (String::from("flapping"), Some(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample_rates() {
+        let (drain, _) = DtraceBuilder::new()
+            .sample_rate(2)
+            .sample_rates([(slog::Level::Info, 10), (slog::Level::Debug, 1000)])
+            .build();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        for _ in 0..20 {
+            slog::error!(log, "a message");
+            slog::info!(log, "a message");
+            slog::debug!(log, "a message");
+        }
+        assert_eq!(counters.sampled_out(slog::Level::Error), 0);
+        assert_eq!(counters.sampled_out(slog::Level::Info), 18);
+        assert_eq!(counters.sampled_out(slog::Level::Debug), 19);
+
+        let config = DtraceBuilder::new()
+            .sample_rates([(slog::Level::Info, 10)])
+            .config;
+        let msg = |level| {
+            let location = Location::new("", "", 0);
+            start_message(
+                &config,
+                location,
+                0,
+                level,
+                String::new(),
+                JsonMap::default(),
+            )
+        };
+        assert_eq!(msg(slog::Level::Info).sample_rate, Some(10));
+        assert_eq!(msg(slog::Level::Error).sample_rate, None);
+    }
+
+    #[test]
+    fn test_compact_field_names() {
+        let config = Config {
+            compact_field_names: true,
+            process_token: Some(1),
+            ..Default::default()
+        };
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["msg"], "a message");
+        assert_eq!(json["lvl"], "INFO");
+        assert_eq!(json["tok"], 1);
+        assert!(json["l"]["ln"].is_u64());
+        assert!(json.get("message").is_none());
+
+        let line = format!("{{\"ok\":{}}}", json);
+        let parsed = Message::from_probe_line(&line).unwrap();
+        assert_eq!(
+            parsed,
+            Message {
+                compact: false,
+                ..msg.clone()
+            }
+        );
+
+        let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+        let decoded: Message = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            Message {
+                compact: false,
+                ..msg
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_chain() {
+        #[derive(Debug)]
+        struct Error(&'static str, Option<Box<Error>>);
+
+        impl std::fmt::Display for Error {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.0)
+            }
+        }
+
+        impl std::error::Error for Error {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                self.1.as_deref().map(|e| e as _)
+            }
+        }
+
+        let error = Error(
+            "failed to connect",
+            Some(Box::new(Error("timed out", None))),
+        );
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Error,
+                "",
+                &format_args!("a message"),
+                b!("error" => #error)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert_eq!(
+            msg.kv["error"],
+            serde_json::json!(["failed to connect", "timed out"])
+        );
+    }
+
+    #[test]
+    fn test_hostname() {
+        let config = DtraceBuilder::new().hostname(true).config;
+        let hostname = config.hostname.clone();
+        assert_eq!(hostname.is_some(), cfg!(unix));
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            json.get("hostname").and_then(|h| h.as_str()),
+            hostname.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_clock() {
+        let timestamp = DateTime::parse_from_rfc3339("2021-10-19T17:55:55.260393409Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let config = DtraceBuilder::new().clock(move || timestamp).config;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap()["timestamp"],
+            "2021-10-19T17:55:55.260393409Z"
+        );
+    }
+
+    #[test]
+    fn test_fanout() {
+        let first = DtraceBuilder::new().build().0;
+        let second = DtraceBuilder::new().build().0;
+        let counters = [first.counters(), second.counters()];
+        let (drain, _) = with_drains(vec![Box::new(first), Box::new(second)]);
+        let log = slog::Logger::root(drain.fuse(), o!());
+        slog::info!(log, "a message");
+        for counters in counters {
+            assert_eq!(counters.total(slog::Level::Info), 1);
+        }
+    }
+
+    #[test]
+    fn test_module_filter() {
+        let allow = ModuleFilter::Allow(vec![String::from("app::http")]);
+        assert!(allow.allows("app::http"));
+        assert!(allow.allows("app::http::server"));
+        assert!(!allow.allows("app::https"));
+        assert!(!allow.allows("app"));
+
+        let deny = ModuleFilter::Deny(vec![String::from("app::http")]);
+        assert!(!deny.allows("app::http::server"));
+        assert!(deny.allows("app::db"));
+
+        let (drain, _) = DtraceBuilder::new()
+            .module_filter(ModuleFilter::Allow(vec![String::from("not_this_crate")]))
+            .build();
+        let config = drain.config.clone();
+        let counters = drain.counters();
+        assert!(!config.forwards(&counters, slog::Level::Info, module_path!()));
+        assert!(config.forwards(&counters, slog::Level::Info, "not_this_crate::a"));
+    }
+
+    #[test]
+    fn test_serde_value() {
+        #[derive(Clone, Serialize)]
+        struct Config {
+            name: &'static str,
+            port: u16,
+        }
+
+        let config = Config {
+            name: "server",
+            port: 80,
+        };
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("config" => slog::Serde(config))
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &super::Config::default(),
+        );
+        assert_eq!(
+            msg.kv["config"],
+            serde_json::json!({"name": "server", "port": 80})
+        );
+    }
+
+    #[test]
+    fn test_serialization_errors() {
+        // A value which always fails to serialize.
+        struct Failing;
+
+        impl slog::Value for Failing {
+            fn serialize(
+                &self,
+                _: &slog::Record<'_>,
+                _: slog::Key,
+                _: &mut dyn slog::Serializer,
+            ) -> slog::Result {
+                Err(slog::Error::Other)
+            }
+        }
+
+        // A map with non-string keys, which can't be represented in JSON.
+        let map = std::collections::BTreeMap::from([((1, 2), 3)]);
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("before" => 1, "map" => slog::Serde(map), "after" => 2)
+            ),
+            &slog::OwnedKVList::from(o!("parent" => "value", "bad" => Failing)),
+            &Config::default(),
+        );
+        assert_eq!(msg.kv["before"], 1);
+        assert_eq!(msg.kv["after"], 2);
+        assert!(msg.kv["map"][ERROR_KEY].is_string());
+        assert!(!msg.kv.contains_key("bad"));
+        assert!(msg.kv[ERROR_KEY].is_string());
+    }
+
+    #[test]
+    fn test_source() {
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(source!()),
+            &DtraceBuilder::new().key_scopes(true).config,
+        );
+        assert_eq!(msg.source_crate.as_deref(), Some(env!("CARGO_PKG_NAME")));
+        assert_eq!(
+            msg.source_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert!(msg.kv.is_empty());
+        assert_eq!(msg.scopes, Some(Scopes::new()));
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["source_crate"], "slog-dtrace");
+    }
+
+    #[test]
+    fn test_function() {
+        let config = DtraceBuilder::new().key_scopes(true).config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!(function!(), "key" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let name = "slog_dtrace::tests::test_function";
+        assert_eq!(msg.location.function.as_deref(), Some(name));
+        assert_eq!(msg.kv.len(), 1);
+        assert!(!msg.scopes.as_ref().unwrap().contains_key(FUNCTION_KEY));
+
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["location"]["function"], name);
+        let mut compact = msg.clone();
+        compact.compact = true;
+        let json = compact.to_probe_json();
+        assert!(json.contains(&format!(r#""fn":"{}""#, name)));
+        let parsed = Message::from_probe_line(&json).unwrap();
+        assert_eq!(parsed.location.function.as_deref(), Some(name));
+
+        // Closures are attributed to the function enclosing them.
+        let function = || function!().1;
+        assert_eq!(function(), "slog_dtrace::tests::test_function");
+
+        // The function is omitted when unset.
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert!(msg.location.function.is_none());
+        assert!(!msg.to_probe_json().contains("function"));
+    }
+
+    #[test]
+    fn test_request_probe_args() {
+        let config = DtraceBuilder::new()
+            .request_probe("latency_ns", "bytes")
+            .config;
+        let keys = config.request_keys.as_ref().unwrap();
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a response"),
+                b!("latency_ns" => 1500, "bytes" => 512u64)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let (_, latency, bytes) = keys.args(msg);
+        assert_eq!((latency, bytes), (1500, 512));
+
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a response"),
+                b!("latency_ns" => "slow")
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let (_, latency, bytes) = keys.args(msg);
+        assert_eq!((latency, bytes), (0, 0));
+    }
+
+    #[test]
+    fn test_async_drain() {
+        let (drain, _) = DtraceBuilder::new().build_async(16);
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!("key" => "value"));
+        for _ in 0..4 {
+            slog::info!(log, "a message");
+        }
+        drop(log);
+        assert_eq!(counters.total(slog::Level::Info), 4);
+        assert_eq!(counters.dropped(slog::Level::Info), 0);
+    }
+
+    #[test]
+    fn test_async_flush() {
+        let (drain, _) = DtraceBuilder::new().build_async(16);
+        let handle = drain.flush_handle();
+        let log = slog::Logger::root(drain.fuse(), o!("key" => "value"));
+        for _ in 0..4 {
+            slog::info!(log, "a message");
+        }
+        assert!(handle.flush(std::time::Duration::from_secs(10)));
+        assert!(handle.flush(std::time::Duration::ZERO));
+    }
+
+    #[test]
+    fn test_async_batch() {
+        let (drain, _) = DtraceBuilder::new()
+            .batch(4, std::time::Duration::from_millis(10))
+            .build_async(16);
+        let handle = drain.flush_handle();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        for _ in 0..10 {
+            slog::info!(log, "a message");
+        }
+        assert!(handle.flush(std::time::Duration::from_secs(10)));
+        drop(log);
+        assert_eq!(counters.total(slog::Level::Info), 10);
+        assert_eq!(counters.dropped(slog::Level::Info), 0);
+    }
+
+    #[test]
+    fn test_redact_keys() {
+        let config = DtraceBuilder::new()
+            .redact_keys(&["password", "*_token", "secret*"])
+            .numeric_key("auth_token")
+            .config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!(
+                    "Password" => "hunter2",
+                    "passwords" => 2,
+                    "auth_token" => 1234,
+                    "secret.key" => "abc",
+                    "user" => "me"
+                )
+            ),
+            &slog::OwnedKVList::from(o!("SESSION_TOKEN" => "xyz")),
+            &config,
+        );
+        assert_eq!(msg.kv["Password"], REDACTED);
+        assert_eq!(msg.kv["passwords"], 2);
+        assert_eq!(msg.kv["auth_token"], REDACTED);
+        assert_eq!(msg.kv["SESSION_TOKEN"], REDACTED);
+        assert_eq!(msg.kv["secret.key"], REDACTED);
+        assert_eq!(msg.kv["user"], "me");
+        assert_eq!(config.numeric_key(), None);
+    }
+
+    #[test]
+    fn test_key_scopes() {
+        let config = DtraceBuilder::new().key_scopes(true).config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => 1, "other" => 2)
+            ),
+            &slog::OwnedKVList::from(o!("key" => 3, "parent" => 4)),
+            &config,
+        );
+        let scopes = msg.scopes.unwrap();
+        assert_eq!(scopes["key"], [RECORD_SCOPE, LOGGER_SCOPE]);
+        assert_eq!(scopes["other"], [RECORD_SCOPE]);
+        assert_eq!(scopes["parent"], [LOGGER_SCOPE]);
+    }
+
+    #[test]
+    fn test_timestamp_format() {
+        let timestamp = DateTime::parse_from_rfc3339("2021-10-19T17:55:55.260393409Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let config = DtraceBuilder::new()
+            .clock(move || timestamp)
+            .timestamp_format(TimestampFormat::EpochNanos)
+            .config;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["timestamp"], 1634666155260393409i64);
+
+        let line = format!("{{\"ok\":{}}}", json);
+        let parsed = Message::from_probe_line(&line).unwrap();
+        assert_eq!(parsed.timestamp, Some(timestamp));
+    }
+
+    #[test]
+    fn test_timestamp_precision() {
+        let timestamp = DateTime::parse_from_rfc3339("2021-10-19T17:55:55.260393409Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for (precision, expected) in [
+            (TimestampPrecision::Seconds, "2021-10-19T17:55:55Z"),
+            (TimestampPrecision::Millis, "2021-10-19T17:55:55.260Z"),
+            (TimestampPrecision::Micros, "2021-10-19T17:55:55.260393Z"),
+            (TimestampPrecision::Nanos, "2021-10-19T17:55:55.260393409Z"),
+        ] {
+            let config = DtraceBuilder::new()
+                .clock(move || timestamp)
+                .timestamp_precision(precision)
+                .config;
+            let msg = create_dtrace_message(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                &slog::OwnedKVList::from(o!()),
+                &config,
+            );
+            let json = serde_json::to_value(&msg).unwrap();
+            assert_eq!(json["timestamp"], expected, "{:?}", precision);
+            assert!(msg.to_tsv().starts_with(&format!("{}\t", expected)));
+
+            // The parsed timestamp is truncated to the precision.
+            let parsed = Message::from_probe_line(&msg.to_probe_json()).unwrap();
+            let expected = DateTime::parse_from_rfc3339(expected).unwrap();
+            assert_eq!(parsed.timestamp, Some(expected.with_timezone(&Utc)));
+        }
+
+        // The precision doesn't apply to epoch timestamps.
+        let config = DtraceBuilder::new()
+            .clock(move || timestamp)
+            .timestamp_format(TimestampFormat::EpochNanos)
+            .timestamp_precision(TimestampPrecision::Seconds)
+            .config;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["timestamp"], 1634666155260393409i64);
+    }
+
+    // Counts the allocations made by each thread, to check that buffers are reused.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Return the number of allocations made by the current thread while running `f`.
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(|n| n.get());
+        f();
+        ALLOCATIONS.with(|n| n.get()) - before
+    }
+
+    #[test]
+    fn test_buffer_reuse() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => "value", "list" => 3)
+            ),
+            &slog::OwnedKVList::from(o!("parent" => true)),
+            &Config::default(),
+        );
+        assert_eq!(allocations(|| assert!(emitted_len(&msg) > 0)), 0);
+
+        let encode = || drop(__private::ProbeBuffer::new(encode_message(&msg)));
+        encode();
+        let reused = allocations(encode);
+        let fresh = allocations(|| drop(rmp_serde::to_vec_named(&msg).unwrap()));
+        assert!(
+            reused < fresh,
+            "expected fewer than {} allocations, found {}",
+            fresh,
+            reused
+        );
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_log_levels() {
+        use log::Log;
+
+        let (logger, _) = DtraceBuilder::new().build_logger();
+        let counters = logger.counters();
+        for level in [log::Level::Error, log::Level::Warn, log::Level::Trace] {
+            logger.log(
+                &log::Record::builder()
+                    .level(level)
+                    .args(format_args!("a message"))
+                    .build(),
+            );
+        }
+        assert_eq!(counters.total(slog::Level::Error), 1);
+        assert_eq!(counters.total(slog::Level::Warning), 1);
+        assert_eq!(counters.total(slog::Level::Trace), 1);
+        assert_eq!(counters.total(slog::Level::Critical), 0);
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_otel_ids() {
+        use opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+
+        let message = || {
+            create_dtrace_message(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                &slog::OwnedKVList::from(o!()),
+                &Config::default(),
+            )
+        };
+        let msg = message();
+        assert_eq!((msg.trace_id, msg.span_id), (None, None));
+
+        let span_context = SpanContext::new(
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap(),
+            SpanId::from_hex("b7ad6b7169203331").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let _guard = opentelemetry::Context::current()
+            .with_remote_span_context(span_context)
+            .attach();
+        let msg = message();
+        assert_eq!(
+            msg.trace_id.as_deref(),
+            Some("0af7651916cd43dd8448eb211c80319c")
+        );
+        assert_eq!(msg.span_id.as_deref(), Some("b7ad6b7169203331"));
+    }
+
+    #[test]
+    fn test_max_kv_pairs() {
+        let config = DtraceBuilder::new().max_kv_pairs(3).config;
+        let message = |config: &Config| {
+            create_dtrace_message(
+                &record!(
+                    slog::Level::Info,
+                    "",
+                    &format_args!("a message"),
+                    b!("b" => 1, "a" => 2)
+                ),
+                &slog::OwnedKVList::from(o!("d" => 3, "c" => 4, "e" => 5)),
+                config,
+            )
+        };
+        let msg = message(&config);
+        assert_eq!(
+            serde_json::Value::Object(msg.kv),
+            serde_json::json!({"a": 2, "b": 1, "e": 5, "kv_overflow": 2})
+        );
+        assert_eq!(message(&config).kv, message(&config).kv);
+        assert!(!message(&Config::default()).kv.contains_key(KV_OVERFLOW));
+    }
+
+    #[test]
+    fn test_to_probe_json() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => "value")
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        let json = msg.to_probe_json();
+        assert_eq!(
+            json,
+            format!("{{\"ok\":{}}}", serde_json::to_string(&msg).unwrap())
+        );
+        assert_eq!(json.len(), emitted_len(&msg));
+        assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_message_deltas() {
+        let message = |config: &Config| {
+            create_dtrace_message(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                &slog::OwnedKVList::from(o!()),
+                config,
+            )
+        };
+        let config = DtraceBuilder::new().message_deltas(true).config;
+        assert_eq!(message(&config).delta_ns, None);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let delta = message(&config).delta_ns.unwrap();
+        assert!(delta >= 10_000_000);
+        assert_eq!(message(&Config::default()).delta_ns, None);
+    }
+
+    #[test]
+    fn test_128_bit_integers() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("max" => u128::MAX, "min" => i128::MIN, "small" => 1u128)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert_eq!(msg.kv["max"], "340282366920938463463374607431768211455");
+        assert_eq!(msg.kv["min"], "-170141183460469231731687303715884105728");
+        assert_eq!(msg.kv["small"], "1");
+    }
+
+    #[test]
+    fn test_omit_timestamps() {
+        let config = DtraceBuilder::new().timestamps(false).config;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.timestamp, None);
+        let json = msg.to_probe_json();
+        assert!(!json.contains("timestamp"));
+        let parsed = Message::from_probe_line(&json).unwrap();
+        assert_eq!(parsed, msg);
+        assert!(rmp_serde::from_slice::<Message>(&rmp_serde::to_vec_named(&msg).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_clock_source() {
+        let message = |builder: DtraceBuilder| {
+            create_dtrace_message(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                &slog::OwnedKVList::from(o!()),
+                &builder.config,
+            )
+        };
+        let epoch = || DateTime::from_timestamp(0, 0).unwrap();
+
+        // The default realtime clock isn't named.
+        let msg = message(DtraceBuilder::new());
+        assert_eq!(msg.clock_source, None);
+        assert!(!msg.to_probe_json().contains("clock_source"));
+
+        let msg = message(DtraceBuilder::new().clock(epoch));
+        assert_eq!(msg.clock_source.as_deref(), Some("custom"));
+        for builder in [
+            DtraceBuilder::new().clock(epoch).clock_source("monotonic"),
+            DtraceBuilder::new().clock_source("monotonic").clock(epoch),
+        ] {
+            let msg = message(builder);
+            assert_eq!(msg.clock_source.as_deref(), Some("monotonic"));
+            let json = msg.to_probe_json();
+            assert!(json.contains(r#""clock_source":"monotonic""#));
+            assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+        }
+        let msg = message(DtraceBuilder::new().clock(epoch).compact_field_names(true));
+        assert!(msg.to_probe_json().contains(r#""cs":"custom""#));
+
+        let msg = message(DtraceBuilder::new().clock(epoch).timestamps(false));
+        assert_eq!(msg.clock_source, None);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_kv_filter() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .require_keys(&["status"])
+            .kv_filter(|kv| kv["status"].as_u64().is_some_and(|status| status >= 500))
+            .build();
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain, o!("service" => "api"));
+        TestSink::clear();
+        slog::info!(log, "ok"; "status" => 200);
+        slog::info!(log, "failed"; "status" => 503);
+        // The filter would panic without the key, so this also checks that it isn't called.
+        slog::info!(log, "no status");
+        let captured: Vec<_> = TestSink::take()
+            .into_iter()
+            .map(|c| c.message.message)
+            .collect();
+        assert_eq!(captured, ["failed"]);
+        assert_eq!(counters.total(slog::Level::Info), 3);
+    }
+
+    #[cfg(all(feature = "test-sink", feature = "tracing"))]
+    #[test]
+    fn test_span_probes() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, _) = DtraceBuilder::new().provider::<TestSink>().build_layer();
+        TestSink::clear();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", key = "value");
+            span.in_scope(|| tracing::info!("handling"));
+            span.in_scope(|| {});
+        });
+        let spans = TestSink::take_spans();
+        let probes: Vec<_> = spans.iter().map(|span| span.probe).collect();
+        assert_eq!(
+            probes,
+            ["span_enter", "span_exit", "span_enter", "span_exit"]
+        );
+        assert!(spans.iter().all(|span| span.id == spans[0].id));
+        assert!(spans.iter().all(|span| span.name == "request"));
+        assert!(spans.iter().all(|span| span.fields["key"] == "value"));
+        assert_eq!(TestSink::take().len(), 1);
+    }
+
+    #[test]
+    fn test_printf_safe() {
+        let config = DtraceBuilder::new().printf_safe(true).config;
+        let mut kv = JsonMap::new();
+        kv.insert(
+            String::from("100%"),
+            serde_json::json!(["%d", {"%x": "%%"}]),
+        );
+        let location = || Location::new("app", "src/main.rs", 1);
+        let text = || String::from("a literal %s");
+        let msg = start_message(&config, location(), 0, slog::Level::Info, text(), kv);
+        let msg = finish_message(&config, msg);
+        assert!(msg.printf_escaped);
+        assert_eq!(msg.message, "a literal %%s");
+        assert_eq!(msg.kv["100%%"], serde_json::json!(["%%d", {"%%x": "%%%%"}]));
+
+        let line = msg.to_probe_json();
+        assert!(line.contains(r#""printf_escaped":true"#));
+        let parsed = Message::from_probe_line(&line).unwrap();
+        assert!(!parsed.printf_escaped);
+        assert_eq!(parsed.message, "a literal %s");
+        assert_eq!(parsed.kv["100%"], serde_json::json!(["%d", {"%x": "%%"}]));
+
+        let config = DtraceBuilder::new().config;
+        let msg = start_message(
+            &config,
+            location(),
+            0,
+            slog::Level::Info,
+            text(),
+            JsonMap::new(),
+        );
+        let msg = finish_message(&config, msg);
+        assert!(!msg.printf_escaped);
+        assert!(!msg.to_probe_json().contains("printf_escaped"));
+        assert_eq!(msg.message, "a literal %s");
+    }
+
+    #[test]
+    fn test_has_keys() {
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        let values = slog::OwnedKVList::from(o!("logger" => "a", "serde" => slog::Serde(vec![1])));
+        let check = |record: &slog::Record| {
+            assert!(has_keys(record, &values, &[]));
+            assert!(has_keys(record, &values, &keys(&["record", "logger"])));
+            assert!(has_keys(record, &values, &keys(&["display", "serde"])));
+            assert!(!has_keys(record, &values, &keys(&["record", "missing"])));
+        };
+        check(&record!(
+            slog::Level::Info,
+            "",
+            &format_args!("a message"),
+            b!("record" => 1, "display" => %"text")
+        ));
+    }
+
+    #[test]
+    fn test_kv_formatter() {
+        let config = DtraceBuilder::new()
+            .kv_formatter(|key: &str, value: serde_json::Value| match value {
+                serde_json::Value::String(s) if key.ends_with("_id") => s.to_lowercase().into(),
+                serde_json::Value::String(s) if s.len() > 8 => format!("{}...", &s[..8]).into(),
+                value => value,
+            })
+            .config;
+        let message = |config: &Config| {
+            create_dtrace_message(
+                &record!(
+                    slog::Level::Info,
+                    "",
+                    &format_args!("a message"),
+                    b!(
+                        "request_id" => "ABC-123",
+                        "body" => "a very long body",
+                        "elapsed" => ?std::time::Duration::from_millis(1500),
+                        "count" => 3
+                    )
+                ),
+                &slog::OwnedKVList::from(o!()),
+                config,
+            )
+        };
+        let msg = message(&config);
+        assert_eq!(msg.kv["request_id"], "abc-123");
+        assert_eq!(msg.kv["body"], "a very l...");
+        assert_eq!(msg.kv["elapsed"], "1.5s");
+        assert_eq!(msg.kv["count"], 3);
+
+        let msg = message(&Config::default());
+        assert_eq!(msg.kv["request_id"], "ABC-123");
+        assert_eq!(msg.kv["body"], "a very long body");
+    }
+
+    #[test]
+    fn test_strip_file_prefixes() {
+        let config = DtraceBuilder::new()
+            .strip_file_prefixes(&["/build/other", "/home/builder/src/app/"])
+            .config;
+        let message = |file: &str| {
+            let location = Location::new("m", file, 1);
+            let msg = start_message(
+                &config,
+                location,
+                0,
+                slog::Level::Info,
+                String::new(),
+                JsonMap::default(),
+            );
+            msg.location.file
+        };
+        assert_eq!(message("/home/builder/src/app/src/main.rs"), "src/main.rs");
+        assert_eq!(
+            message("/home/builder/src/application/main.rs"),
+            "/home/builder/src/application/main.rs"
+        );
+        assert_eq!(message("src/main.rs"), "src/main.rs");
+    }
+
+    #[test]
+    fn test_transform() {
+        let config = DtraceBuilder::new()
+            .transform(|msg| {
+                msg.kv.insert(String::from("region"), "us-west-1".into());
+                msg.kv.remove("secret");
+                if msg.message == "panic" {
+                    panic!("transform panicked");
+                }
+                msg.location.module = String::from("renamed");
+            })
+            .config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("secret" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.kv["region"], "us-west-1");
+        assert!(!msg.kv.contains_key("secret"));
+        assert_eq!(msg.location.module, "renamed");
+
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("panic"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.kv["region"], "us-west-1");
+        assert_ne!(msg.location.module, "renamed");
+    }
+
+    #[test]
+    fn test_max_serialized_bytes() {
+        let huge = "x".repeat(10 * 1024 * 1024);
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Warning,
+                "",
+                &format_args!("a message"),
+                b!("huge" => &huge, "small" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert_eq!(msg.message, OVERSIZED_MESSAGE);
+        assert!(msg.kv.is_empty());
+        assert!(msg.truncated);
+        assert!(msg.original_len.unwrap() > huge.len());
+        assert_eq!(msg.level, "WARNING");
+        assert!(msg.to_probe_json().len() < 1024);
+
+        let config = DtraceBuilder::new().max_serialized_bytes(usize::MAX).config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Warning,
+                "",
+                &format_args!("a message"),
+                b!("huge" => &huge)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.kv["huge"], huge.as_str());
+        assert!(!msg.truncated);
+    }
+
+    #[test]
+    fn test_argv_hash() {
+        let hash = |args: &[&str]| argv_hash(args.iter().map(std::ffi::OsString::from));
+        assert_eq!(hash(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(&["prog", "-v"]), hash(&["prog", "-v"]));
+        assert_ne!(hash(&["ab", "c"]), hash(&["a", "bc"]));
+        assert_ne!(hash(&["prog"]), hash(&["prog", ""]));
+    }
+
+    #[test]
+    fn test_sorted_keys() {
+        let mut kv = JsonMap::default();
+        kv.insert(String::from("zebra"), 1.into());
+        kv.insert(
+            String::from("nested"),
+            serde_json::json!({"b": [{"y": 1, "x": 2}], "a": null}),
+        );
+        kv.insert(String::from("alpha"), "first".into());
+        let mut msg = Message::new(Location::new("m", "f", 1), slog::Level::Info, "a message");
+        msg.kv = kv;
+        msg.thread_id = 1;
+        msg.pid = 2;
+        msg.timestamp = None;
+        msg.sorted_keys = true;
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            concat!(
+                r#"{"schema_version":2,"location":{"module":"m","file":"f","line":1},"#,
+                r#""level":"INFO","level_num":4,"#,
+                r#""severity":6,"#,
+                r#""message":"a message","kv":{"alpha":"first","nested":{"a":null,"#,
+                r#""b":[{"x":2,"y":1}]},"zebra":1},"thread_id":1,"pid":2}"#,
+            )
+        );
+
+        let config = DtraceBuilder::new().sorted_keys(true).config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("b" => 1, "a" => 2)
+            ),
+            &slog::OwnedKVList::from(o!("c" => 3)),
+            &config,
+        );
+        assert!(msg.sorted_keys);
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""kv":{"a":2,"b":1,"c":3}"#));
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_warn_slow_messages() {
+        // A value which is slow to serialize.
+        struct Slow;
+        impl slog::Value for Slow {
+            fn serialize(
+                &self,
+                _: &slog::Record<'_>,
+                key: slog::Key,
+                serializer: &mut dyn slog::Serializer,
+            ) -> slog::Result {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                serializer.emit_str(key, "slow")
+            }
+        }
+
+        // A drain keeping the messages it receives.
+        #[derive(Default)]
+        struct Keep(std::sync::Mutex<Vec<Message>>);
+        impl Drain for Keep {
+            type Ok = ();
+            type Err = slog::Never;
+            fn log(
+                &self,
+                record: &slog::Record<'_>,
+                values: &slog::OwnedKVList,
+            ) -> Result<(), slog::Never> {
+                let msg = create_dtrace_message(record, values, &Config::default());
+                self.0.lock().unwrap().push(msg);
+                Ok(())
+            }
+        }
+
+        const WARNING: &str = "a message was slow to serialize for DTrace";
+        let threshold = std::time::Duration::from_millis(5);
+        let log_messages = |log: &slog::Logger| {
+            TestSink::clear();
+            for _ in 0..2 {
+                slog::info!(log, "a message"; "slow" => Slow);
+            }
+            slog::info!(log, "a message"; "fast" => 1);
+            TestSink::take()
+        };
+
+        // The warning is logged to the drain wrapped by a `FallibleDuplicate`. The slow site is
+        // warned about once, and the fast one not at all.
+        let keep = Arc::new(Keep::default());
+        let drain = FallibleDuplicate {
+            drain: Arc::clone(&keep),
+            dtrace: DtraceBuilder::new()
+                .provider::<TestSink>()
+                .warn_slow_messages(threshold)
+                .build()
+                .0,
+            policy: DrainErrorPolicy::Ignore,
+        };
+        let captured = log_messages(&slog::Logger::root(drain, o!()));
+        assert!(captured.iter().all(|c| c.message.message != WARNING));
+        let kept = keep.0.lock().unwrap();
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[0].location.line, kept[2].location.line);
+        // The warning follows the slow message.
+        let warning = &kept[1];
+        assert_eq!(warning.message, WARNING);
+        assert_eq!(warning.level, "WARNING");
+        assert_eq!(warning.kv["module"], module_path!());
+        assert_eq!(warning.kv["line"], kept[0].location.line);
+        assert!(warning.kv["elapsed_us"].as_u64().unwrap() >= 20_000);
+        assert_eq!(warning.kv["threshold_us"], 5_000);
+        drop(kept);
+
+        // And to the drain wrapped by a `DtraceDuplicate`, which logs to it before DTrace.
+        let keep = Arc::new(Keep::default());
+        let drain = DtraceDuplicate {
+            drain: Arc::clone(&keep),
+            dtrace: DtraceBuilder::new()
+                .provider::<TestSink>()
+                .warn_slow_messages(threshold)
+                .build()
+                .0,
+        };
+        let captured = log_messages(&slog::Logger::root(drain, o!()));
+        assert!(captured.iter().all(|c| c.message.message != WARNING));
+        let kept = keep.0.lock().unwrap();
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[1].message, WARNING);
+        drop(kept);
+
+        // A `Dtrace` drain on its own forwards the warning to DTrace.
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .warn_slow_messages(threshold)
+            .build();
+        let captured = log_messages(&slog::Logger::root(drain, o!()));
+        let warnings: Vec<_> = captured
+            .iter()
+            .filter(|c| c.message.message == WARNING)
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].probe, "warn");
+
+        // Unless another logger is given.
+        let keep = Arc::new(Keep::default());
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .warn_slow_messages(threshold)
+            .slow_message_logger(slog::Logger::root(Arc::clone(&keep), o!()))
+            .build();
+        let captured = log_messages(&slog::Logger::root(drain, o!()));
+        assert!(captured.iter().all(|c| c.message.message != WARNING));
+        let kept = keep.0.lock().unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].message, WARNING);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_fallible_drain() {
+        // A drain which always fails.
+        struct Failing;
+        impl Drain for Failing {
+            type Ok = ();
+            type Err = std::io::Error;
+            fn log(
+                &self,
+                _: &slog::Record<'_>,
+                _: &slog::OwnedKVList,
+            ) -> Result<(), std::io::Error> {
+                Err(std::io::Error::other("disk full"))
+            }
+        }
+
+        let log = |policy| {
+            let drain = FallibleDuplicate {
+                drain: Failing,
+                dtrace: DtraceBuilder::new().provider::<TestSink>().build().0,
+                policy,
+            };
+            let log = slog::Logger::root(drain, o!());
+            TestSink::clear();
+            let result = std::panic::catch_unwind(|| slog::info!(log, "a message"));
+            (result.is_ok(), TestSink::take())
+        };
+
+        let (ok, captured) = log(DrainErrorPolicy::Ignore);
+        assert!(ok);
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].message.message, "a message");
+
+        let (ok, captured) = log(DrainErrorPolicy::Report);
+        assert!(ok);
+        assert_eq!(captured.len(), 2);
+        assert_eq!(captured[1].probe, "error");
+        assert!(captured[1].message.kv["error"]
+            .as_str()
+            .unwrap()
+            .contains("disk full"));
+
+        // The message reaches DTrace before the panic.
+        let (ok, captured) = log(DrainErrorPolicy::Panic);
+        assert!(!ok);
+        assert_eq!(captured.len(), 1);
+    }
+
+    #[test]
+    fn test_registration_failure_warning() {
+        // A drain keeping the messages it receives.
+        #[derive(Default)]
+        struct Keep(std::sync::Mutex<Vec<(slog::Level, String)>>);
+        impl Drain for Keep {
+            type Ok = ();
+            type Err = slog::Never;
+            fn log(
+                &self,
+                record: &slog::Record<'_>,
+                _: &slog::OwnedKVList,
+            ) -> Result<(), slog::Never> {
+                let mut kept = self.0.lock().unwrap();
+                kept.push((record.level(), record.msg().to_string()));
+                Ok(())
+            }
+        }
+
+        let failed =
+            || ProbeRegistration::Failed(RegistrationError::Kernel(String::from("simulated")));
+        let keep = Arc::new(Keep::default());
+        let (drain, registration) = duplicate_with_drain(
+            Arc::clone(&keep),
+            DtraceBuilder::new().build_lazy(),
+            failed(),
+        );
+        assert!(!registration.is_success());
+        assert!(!drain.dtrace().is_noop());
+        let kept = keep.0.lock().unwrap().clone();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].0, slog::Level::Warning);
+        assert!(kept[0].1.contains("not forwarded to DTrace"));
+
+        // The warning is logged once, when building, and not for each message.
+        let log = slog::Logger::root(drain.fuse(), o!());
+        slog::info!(log, "a message");
+        assert_eq!(keep.0.lock().unwrap().len(), 2);
+
+        // It can be disabled, and isn't logged when DTrace is unsupported.
+        for (builder, registration) in [
+            (
+                DtraceBuilder::new().warn_on_registration_failure(false),
+                failed(),
+            ),
+            (
+                DtraceBuilder::new(),
+                ProbeRegistration::Failed(RegistrationError::Unsupported),
+            ),
+            (DtraceBuilder::new(), ProbeRegistration::Success),
+        ] {
+            let keep = Arc::new(Keep::default());
+            let _ = duplicate_with_drain(Arc::clone(&keep), builder.build_lazy(), registration);
+            assert!(keep.0.lock().unwrap().is_empty());
+        }
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_any_enabled() {
+        // Every probe of the `TestSink` is enabled.
+        let (drain, _) = DtraceBuilder::new().provider::<TestSink>().build();
+        assert!(drain.any_enabled());
+        assert!(drain.is_enabled());
+        drain.set_verbose(false);
+        assert!(!drain.any_enabled());
+        assert!(!drain.is_enabled());
+        let drain = DtraceBuilder::new().provider::<TestSink>().build_lazy();
+        assert!(!drain.any_enabled());
+        assert!(!Dtrace::noop().any_enabled());
+
+        // Nothing is tracing the test.
+        let (drain, _) = Dtrace::new();
+        assert!(!drain.any_enabled());
+        assert!(!drain.is_enabled());
+    }
+
+    #[test]
+    fn test_level_router() {
+        // A drain counting the messages it receives.
+        #[derive(Default)]
+        struct Counter(std::sync::atomic::AtomicUsize);
+        impl Drain for Counter {
+            type Ok = ();
+            type Err = slog::Never;
+            fn log(&self, _: &slog::Record<'_>, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let (severe, other) = (Arc::new(Counter::default()), Arc::new(Counter::default()));
+        let router = LevelRouter::new(slog::Level::Error, Arc::clone(&severe), Arc::clone(&other));
+        let log = slog::Logger::root(router, o!());
+        slog::crit!(log, "a message");
+        slog::error!(log, "a message");
+        slog::warn!(log, "a message");
+        slog::info!(log, "a message");
+        slog::debug!(log, "a message");
+        assert_eq!(severe.0.load(Ordering::Relaxed), 2);
+        assert_eq!(other.0.load(Ordering::Relaxed), 3);
+
+        let values = slog::OwnedKVList::from(o!());
+        let (drain, registration) = DtraceBuilder::new().build_routed::<Slog>(slog::Level::Error);
+        assert!(registration.is_success());
+        drain
+            .log(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                &values,
+            )
+            .unwrap();
+        assert_eq!(drain.severe.counters().total(slog::Level::Info), 0);
+        assert_eq!(drain.other.counters().total(slog::Level::Info), 1);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_labels() {
+        let labels = std::collections::HashMap::from([("az", "us-west-1"), ("service", "nexus")]);
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .labels(labels)
+            .build();
+        let log = slog::Logger::root(drain, o!("component" => "db"));
+        TestSink::clear();
+        slog::info!(log, "first");
+        slog::warn!(log, "second"; "key" => 1);
+        // Pairs of the message take precedence over labels.
+        slog::error!(log, "third"; "service" => "crucible");
+        let captured = TestSink::take();
+        assert_eq!(captured.len(), 3);
+        for (c, service) in captured.iter().zip(["nexus", "nexus", "crucible"]) {
+            assert_eq!(c.message.kv["az"], "us-west-1");
+            assert_eq!(c.message.kv["service"], service);
+            assert_eq!(c.message.kv["component"], "db");
+        }
+        assert_eq!(captured[1].message.kv["key"], 1);
+
+        let config = DtraceBuilder::new()
+            .labels(Vec::<(String, String)>::new())
+            .config;
+        assert!(config.labels.is_none());
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_module_router() {
+        let build = || DtraceBuilder::new().provider::<TestSink>().build().0;
+        let (crate_drain, tests_drain, fallback) = (build(), build(), build());
+        let counters = [
+            crate_drain.counters(),
+            tests_drain.counters(),
+            fallback.counters(),
+        ];
+        let router = ModuleRouter::new(Box::new(fallback))
+            .route("slog_dtrace", Box::new(crate_drain))
+            .route("slog_dtrace::tests", Box::new(tests_drain))
+            .route("slog_dtrace::test", Box::new(build()));
+        let log = slog::Logger::root(router, o!());
+        slog::info!(log, "from the tests module");
+        let totals: Vec<_> = counters
+            .iter()
+            .map(|c| c.total(slog::Level::Info))
+            .collect();
+        assert_eq!(totals, [0, 1, 0]);
+
+        let fallback = build();
+        let counters = fallback.counters();
+        // A route matches whole path components, so `slog` doesn't match `slog_dtrace`.
+        let router = ModuleRouter::new(Box::new(fallback)).route("slog", Box::new(build()));
+        let log = slog::Logger::root(router, o!());
+        slog::info!(log, "from the tests module");
+        assert_eq!(counters.total(slog::Level::Info), 1);
+    }
+
+    #[test]
+    fn test_context() {
+        #[derive(Serialize)]
+        struct Deployment {
+            region: &'static str,
+            zones: Vec<u8>,
+        }
+
+        let config = DtraceBuilder::new()
+            .context(&Deployment {
+                region: "us-west-1",
+                zones: vec![1, 2],
+            })
+            .config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        let context = serde_json::json!({"region": "us-west-1", "zones": [1, 2]});
+        assert_eq!(msg.context.as_ref(), Some(&context));
+        assert_eq!(msg.kv.len(), 1);
+        let json = msg.to_probe_json();
+        assert!(json.contains(r#""context":{"region":"us-west-1","zones":[1,2]}"#));
+        assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+
+        // Maps with non-string keys can't be represented as JSON.
+        let map = std::collections::HashMap::from([(vec![1], 1)]);
+        let config = DtraceBuilder::new().context(&map).config;
+        assert!(config.context.unwrap()[ERROR_KEY].is_string());
+    }
+
+    #[test]
+    fn test_probe_spec() {
+        use slog::Level;
+        let names: Vec<_> = slog::LOG_LEVEL_NAMES
+            .iter()
+            .skip(1)
+            .map(|name| probe_name(name.parse().unwrap()))
+            .collect();
+        assert_eq!(
+            names,
+            ["critical", "error", "warn", "info", "debug", "trace"]
+        );
+        assert_eq!(
+            probe_spec("slog", &[Level::Warning, Level::Error]),
+            "slog*:::warn,slog*:::error"
+        );
+        assert_eq!(
+            probe_spec(
+                "nexus",
+                &[Level::Trace, Level::Debug, Level::Info, Level::Critical]
+            ),
+            "nexus*:::trace,nexus*:::debug,nexus*:::info,nexus*:::critical"
+        );
+        assert_eq!(probe_spec("slog", &[]), "");
+    }
+
+    #[test]
+    fn test_numeric_probe_names() {
+        use slog::Level;
+        for level in [
+            Level::Critical,
+            Level::Error,
+            Level::Warning,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            assert_eq!(ProbeNames::Levels.name(level), probe_name(level));
+            assert_eq!(
+                ProbeNames::Numeric.name(level),
+                format!("level{}", level.as_usize())
+            );
+        }
+        assert_eq!(
+            ProbeNames::Numeric.spec("slog", &[Level::Warning, Level::Trace]),
+            "slog*:::level3,slog*:::level6"
+        );
+        assert_eq!(
+            ProbeNames::Levels.spec("slog", &[Level::Warning]),
+            probe_spec("slog", &[Level::Warning])
+        );
+    }
+
+    #[test]
+    fn test_find_numeric_value() {
+        let values = slog::OwnedKVList::from(o!("serde" => slog::Serde(vec![1])));
+        let find = |record: &slog::Record| find_numeric_value(record, &values, "n");
+        let value = find(&record!(
+            slog::Level::Info,
+            "",
+            &format_args!("a message"),
+            b!("before" => slog::Serde("a"), "n" => 7u8)
+        ));
+        assert_eq!(value, Some(7));
+
+        // A serde value is not an integer, and negative integers can't be emitted as one.
+        let value = find(&record!(
+            slog::Level::Info,
+            "",
+            &format_args!("a message"),
+            b!("n" => slog::Serde(7))
+        ));
+        assert_eq!(value, None);
+        let value = find(&record!(
+            slog::Level::Info,
+            "",
+            &format_args!("a message"),
+            b!("n" => -1i64)
+        ));
+        assert_eq!(value, None);
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_numeric_probes() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .probe_names(ProbeNames::Numeric)
+            .numeric_key("n")
+            .build();
+        let log = slog::Logger::root(drain, o!());
+        TestSink::clear();
+        slog::warn!(log, "a warning"; "n" => 1);
+        slog::info!(log, "some info");
+        let captured = TestSink::take();
+        let probes: Vec<_> = captured.iter().map(|c| c.probe).collect();
+        assert_eq!(probes, ["level3", "level4"]);
+        assert_eq!(captured[0].message.message, "a warning");
+        assert_eq!(captured[0].message.kv["n"], 1);
+    }
+
+    #[cfg(all(feature = "test-sink", feature = "tracing"))]
+    #[test]
+    fn test_numeric_probes_tracing() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .numeric_key("n")
+            .build_layer();
+        TestSink::clear();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(n = 7i64, "positive");
+            tracing::info!(n = -1i64, "negative");
+        });
+        let probes: Vec<_> = TestSink::take().iter().map(|c| c.probe).collect();
+        assert_eq!(probes, ["info_value", "info"]);
+    }
+
+    #[cfg(all(feature = "test-sink", feature = "log"))]
+    #[test]
+    fn test_numeric_probes_log() {
+        use log::Log;
+
+        let (logger, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .numeric_key("n")
+            .build_logger();
+        TestSink::clear();
+        for n in [7i64, -1] {
+            let kv = [("n", n)];
+            logger.log(
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("a message"))
+                    .key_values(&kv)
+                    .build(),
+            );
+        }
+        let probes: Vec<_> = TestSink::take().iter().map(|c| c.probe).collect();
+        assert_eq!(probes, ["info_value", "info"]);
+    }
+
+    #[test]
+    fn test_level_enabled() {
+        use slog::Level;
+        assert!(!Dtrace::noop().level_enabled(Level::Error));
+        assert!(!Dtrace::new_lazy().level_enabled(Level::Error));
+
+        // Nothing is tracing the test.
+        let (drain, _) = Dtrace::new();
+        assert!(!drain.level_enabled(Level::Trace));
+        assert!(!drain.level_enabled(Level::Critical));
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_level_enabled_gates() {
+        use slog::Level;
+        // Every probe of the `TestSink` is enabled.
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .min_level(Level::Warning)
+            .build();
+        assert!(!drain.level_enabled(Level::Info));
+        assert!(!drain.level_enabled(Level::Trace));
+        assert!(drain.level_enabled(Level::Warning));
+        assert!(drain.level_enabled(Level::Critical));
+        drain.set_verbose(false);
+        assert!(!drain.level_enabled(Level::Critical));
+    }
+
+    #[test]
+    fn test_capture_backtrace() {
+        let config = DtraceBuilder::new()
+            .capture_backtrace_for(&[slog::Level::Error, slog::Level::Critical])
+            .config;
+        let enabled = std::backtrace::Backtrace::capture().status()
+            == std::backtrace::BacktraceStatus::Captured;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Error, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.backtrace.is_some(), enabled);
+        if let Some(backtrace) = &msg.backtrace {
+            assert!(backtrace.contains("test_capture_backtrace"));
+            let json = msg.to_probe_json();
+            assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+        }
+
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert!(msg.backtrace.is_none());
+    }
+
+    #[test]
+    fn test_framed_json() {
+        let msg = Message::new(
+            Location::new("app", "src/main.rs", 7),
+            slog::Level::Info,
+            "line one\nline two",
+        );
+        let frame = msg.to_framed_json();
+        let json = msg.to_probe_json();
+        assert_eq!(frame[..4], (json.len() as u32).to_be_bytes());
+        assert_eq!(&frame[4..], json.as_bytes());
+        assert_eq!(frame, frame_message(&msg));
+
+        let mut buf = frame.clone();
+        buf.extend_from_slice(&frame);
+        let (parsed, len) = Message::from_framed_json(&buf).unwrap();
+        assert_eq!(len, frame.len());
+        assert_eq!(parsed.message, msg.message);
+        let (_, rest) = Message::from_framed_json(&buf[len..]).unwrap();
+        assert_eq!(rest, frame.len());
+
+        for end in [0, 3, frame.len() - 1] {
+            assert!(matches!(
+                Message::from_framed_json(&frame[..end]),
+                Err(ParseError::Truncated)
+            ));
+        }
+        let mut invalid = frame.clone();
+        invalid[5] = 0xff;
+        assert!(matches!(
+            Message::from_framed_json(&invalid),
+            Err(ParseError::Json(_))
+        ));
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_framed_json_format() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .format(Format::FramedJson)
+            .build();
+        let log = slog::Logger::root(drain, o!());
+        TestSink::clear();
+        slog::warn!(log, "a\nwarning"; "key" => "value");
+        let captured = TestSink::take();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].probe, "warn_bytes");
+        assert_eq!(captured[0].message.message, "a\nwarning");
+        assert_eq!(captured[0].message.kv["key"], "value");
+    }
+
+    #[test]
+    fn test_bare_json() {
+        let msg = Message::new(
+            Location::new("app", "src/main.rs", 7),
+            slog::Level::Warning,
+            "a message",
+        );
+        let bare = msg.to_bare_json();
+        let wrapped = msg.to_probe_json();
+        assert_eq!(wrapped, format!("{{\"ok\":{bare}}}"));
+        let value: serde_json::Value = serde_json::from_str(&bare).unwrap();
+        assert_eq!(value["message"], "a message");
+
+        // Both forms parse to the same message.
+        let from_bare = Message::from_probe_line(&bare).unwrap();
+        let from_wrapped = Message::from_probe_line(&wrapped).unwrap();
+        assert_eq!(from_bare, from_wrapped);
+        assert_eq!(from_bare.message, "a message");
+        assert_eq!(from_bare.level_num, 3);
+
+        assert!(matches!(
+            Message::from_probe_line(&bare[..bare.len() - 10]),
+            Err(ParseError::Truncated)
+        ));
+        assert!(matches!(
+            Message::from_probe_line(r#"{"err":"failed"}"#),
+            Err(ParseError::Serialization(e)) if e == "failed"
+        ));
+        assert!(matches!(
+            Message::from_probe_line(r#"{"message":"no other fields"}"#),
+            Err(ParseError::Json(_))
+        ));
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_bare_json_format() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .format(Format::BareJson)
+            .build();
+        let log = slog::Logger::root(drain, o!());
+        TestSink::clear();
+        slog::info!(log, "some info"; "key" => "value");
+        let captured = TestSink::take();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].probe, "info_bytes");
+        assert_eq!(captured[0].message.message, "some info");
+        assert_eq!(captured[0].message.kv["key"], "value");
+    }
+
+    #[test]
+    fn test_tsv() {
+        let config = DtraceBuilder::new()
+            .format(Format::Tsv)
+            .timestamp_format(TimestampFormat::EpochNanos)
+            .clock(|| DateTime::from_timestamp(1, 5).unwrap())
+            .sorted_keys(true)
+            .config;
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Warning,
+                "",
+                &format_args!("line one\nline\ttwo"),
+                b!("path" => "C:\\tmp", "a=b" => 1, "ok" => true)
+            ),
+            &slog::OwnedKVList::from(o!("nested" => slog::Serde(vec![1, 2]))),
+            &config,
+        );
+        let line = msg.to_tsv();
+        let columns: Vec<_> = line.split('\t').collect();
+        assert_eq!(
+            columns,
+            [
+                "1000000005",
+                "WARNING",
+                &format!("{}:{}", module_path!(), msg.location.line),
+                "line one\\nline\\ttwo",
+                "a\\=b=1",
+                "nested=[1,2]",
+                "ok=true",
+                "path=C:\\\\tmp",
+            ]
+        );
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn test_logger_kv_once() {
+        let config = DtraceBuilder::new().logger_kv_once(true).config;
+        let loggers = config.loggers.clone().unwrap();
+        let values = slog::OwnedKVList::from(o!("parent" => "value"));
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("key" => 1)
+            ),
+            &values,
+            &config,
+        );
+        assert_eq!(msg.kv.len(), 1);
+        assert_eq!(msg.kv["key"], 1);
+
+        let id = |values: &slog::OwnedKVList| {
+            loggers.id::<Slog>(
+                &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+                values,
+                &config,
+            )
+        };
+        assert_eq!(id(&values), id(&values));
+        assert_eq!(
+            id(&values),
+            id(&slog::OwnedKVList::from(o!("parent" => "value")))
+        );
+        assert_ne!(
+            id(&values),
+            id(&slog::OwnedKVList::from(o!("parent" => "other")))
+        );
+
+        // Pairs are only remembered once the probe has fired, which it can't without DTrace.
+        if !is_supported() {
+            assert!(loggers.emitted.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_message_hashes() {
+        let config = DtraceBuilder::new().message_hashes(true).config;
+        let message = |n: u32| {
+            create_dtrace_message(
+                &record!(
+                    slog::Level::Info,
+                    "",
+                    &format_args!("request {}", n),
+                    b!("n" => n)
+                ),
+                &slog::OwnedKVList::from(o!()),
+                &config,
+            )
+        };
+        let (first, second) = (message(1), message(2));
+        assert!(first.msg_hash.is_some());
+        assert_eq!(first.msg_hash, second.msg_hash);
+        let other = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("request {}", 1),
+                b!("n" => 1)
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_ne!(first.msg_hash, other.msg_hash);
+
+        // Statements on the same line differ by their column.
+        let at_column = |column: u32| {
+            let location = slog::RecordLocation {
+                file: file!(),
+                line: 1,
+                column,
+                function: "",
+                module: module_path!(),
+            };
+            let statics = slog::RecordStatic {
+                location: &location,
+                tag: "",
+                level: slog::Level::Info,
+            };
+            create_dtrace_message(
+                &slog::Record::new(&statics, &format_args!("a message"), b!()),
+                &slog::OwnedKVList::from(o!()),
+                &config,
+            )
+        };
+        let (left, right) = (at_column(5), at_column(40));
+        assert_eq!(left.location, right.location);
+        assert_ne!(left.msg_hash, right.msg_hash);
+        assert_eq!(
+            left.msg_hash,
+            Some(site_hash(&Location::new(module_path!(), file!(), 1), 5))
+        );
+        let json = first.to_probe_json();
+        assert_eq!(Message::from_probe_line(&json).unwrap(), first);
+
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert!(msg.msg_hash.is_none());
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_level_probes() {
+        let (drain, _) = DtraceBuilder::new().provider::<TestSink>().build();
+        let log = slog::Logger::root(drain, o!("key" => "value"));
+        slog::crit!(log, "critical");
+        slog::error!(log, "error");
+        slog::warn!(log, "warn");
+        slog::info!(log, "info");
+        slog::debug!(log, "debug");
+        // `trace!` is compiled out by slog's default maximum level.
+        let captured = TestSink::take();
+        let probes: Vec<_> = captured.iter().map(|c| c.probe).collect();
+        assert_eq!(probes, ["critical", "error", "warn", "info", "debug"]);
+        for c in &captured {
+            assert_eq!(c.probe, probe_name(c.message.level.parse().unwrap()));
+            assert_eq!(c.message.message, c.probe);
+            assert_eq!(c.message.kv["key"], "value");
+        }
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_fields_format() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .format(Format::Fields)
+            .single_probe(true)
+            .build();
+        let log = slog::Logger::root(drain, o!("key" => "value"));
+        slog::error!(log, "error");
+        slog::info!(log, "info");
+        let captured = TestSink::take();
+        let probes: Vec<_> = captured.iter().map(|c| c.probe).collect();
+        assert_eq!(probes, ["fields", "fields"]);
+        assert_eq!(captured[0].message.level_num, 2);
+        assert_eq!(captured[0].message.message, "error");
+        assert_eq!(captured[1].message.level_num, 4);
+        assert_eq!(captured[1].message.kv["key"], "value");
+    }
+
+    #[test]
+    fn test_recent_messages() {
+        let (drain, _) = DtraceBuilder::new().recent_messages(4).build();
+        let recent = drain.recent_messages().unwrap();
+        assert_eq!(recent.capacity(), 4);
+        let drain = Arc::new(drain);
+        let log = slog::Logger::root(Arc::clone(&drain), o!());
+        for i in 0..10 {
+            slog::info!(log, "message"; "i" => i);
+        }
+        let messages: Vec<_> = drain
+            .recent()
+            .into_iter()
+            .map(|m| m.kv["i"].clone())
+            .collect();
+        assert_eq!(messages, [6, 7, 8, 9]);
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        slog::info!(log, "message");
+                    }
+                });
+            }
+        });
+        assert_eq!(recent.messages().len(), 4);
+        recent.clear();
+        assert!(drain.recent().is_empty());
+
+        let (drain, _) = Dtrace::new();
+        assert!(drain.recent_messages().is_none());
+        assert!(drain.recent().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_by_env() {
+        assert!(!disabled_by_env(None));
+        assert!(!disabled_by_env(Some("".into())));
+        assert!(!disabled_by_env(Some("0".into())));
+        assert!(disabled_by_env(Some("1".into())));
+        assert!(disabled_by_env(Some("yes".into())));
+    }
+
+    #[cfg(feature = "test-sink")]
+    #[test]
+    fn test_emit_test_message() {
+        let (drain, _) = DtraceBuilder::new()
+            .provider::<TestSink>()
+            .min_level(slog::Level::Error)
+            .build();
+        drain.emit_test_message(slog::Level::Info, "ping");
+        let line = line!() - 1;
+        let captured = TestSink::take();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].probe, "info");
+        let msg = &captured[0].message;
+        assert_eq!(msg.message, "ping");
+        assert_eq!(msg.kv[TEST_KEY], true);
+        assert_eq!(msg.location.file, file!());
+        assert_eq!(msg.location.line, line);
+        assert_eq!(drain.counters().fired(slog::Level::Info), 1);
+
+        let drain = DtraceBuilder::new().provider::<TestSink>().build_lazy();
+        drain.emit_test_message(slog::Level::Info, "ping");
+        assert!(TestSink::take().is_empty());
+    }
+
+    #[test]
+    fn test_tags() {
+        let config = DtraceBuilder::new().tags(true).config;
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "audit", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert_eq!(msg.tag.as_deref(), Some("audit"));
+        let json = msg.to_probe_json();
+        assert!(json.contains(r#""message":"a message","tag":"audit""#));
+        assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &config,
+        );
+        assert!(msg.tag.is_none());
+        assert!(!msg.to_probe_json().contains("tag"));
+
+        let msg = create_dtrace_message(
+            &record!(slog::Level::Info, "audit", &format_args!("a message"), b!()),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert!(msg.tag.is_none());
+    }
+
+    #[test]
+    fn test_register_once() {
+        for _ in 0..1000 {
+            let (drain, registration) = Dtrace::new();
+            assert!(registration.is_success());
+            assert!(drain.is_registered());
+            let log = slog::Logger::root(drain, o!());
+            slog::info!(log, "a message");
+        }
+        assert!(*PROCESS_REGISTERED.lock().unwrap());
+
+        let drain = DtraceBuilder::new().build_lazy();
+        assert!(!drain.is_registered());
+        assert!(drain.register_probes_now().is_success());
+        assert!(drain.is_registered());
+    }
+
+    #[test]
+    fn test_level_num() {
+        for level in [
+            slog::Level::Critical,
+            slog::Level::Warning,
+            slog::Level::Trace,
+        ] {
+            let msg = Message::new(Location::new("m", "f", 1), level, "a message");
+            assert_eq!(usize::from(msg.level_num), level.as_usize());
+            let json = msg.to_probe_json();
+            assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+        }
+
+        let line = r#"{"ok":{"location":{"module":"m","file":"f","line":1},"level":"ERROR","message":"a message","kv":{}}}"#;
+        let msg = Message::from_probe_line(line).unwrap();
+        assert_eq!(msg.level, "ERROR");
+        assert_eq!(msg.level_num, 2);
+        assert_eq!(msg.severity, 3);
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let msg = Message::new(Location::new("m", "f", 1), slog::Level::Info, "a message");
+        assert_eq!(msg.schema_version, SCHEMA_VERSION);
+        let json = msg.to_probe_json();
+        assert!(json.starts_with(&format!(r#"{{"ok":{{"schema_version":{SCHEMA_VERSION},"#)));
+        assert_eq!(Message::from_probe_line(&json).unwrap(), msg);
+
+        let mut compact = msg.clone();
+        compact.compact = true;
+        let json = serde_json::to_value(&compact).unwrap();
+        assert_eq!(json["sv"], SCHEMA_VERSION);
+
+        let line = r#"{"ok":{"location":{"module":"m","file":"f","line":1},"level":"INFO","message":"a message","kv":{}}}"#;
+        assert_eq!(Message::from_probe_line(line).unwrap().schema_version, 1);
+    }
+
+    #[test]
+    fn test_syslog_severity() {
+        use slog::Level;
+        let severities = [
+            (Level::Critical, 2),
+            (Level::Error, 3),
+            (Level::Warning, 4),
+            (Level::Info, 6),
+            (Level::Debug, 7),
+            (Level::Trace, 7),
+        ];
+        for (level, severity) in severities {
+            assert_eq!(syslog_severity(level), severity);
+            let msg = Message::new(Location::new("m", "f", 1), level, "a message");
+            assert_eq!(msg.severity, severity);
+            let json = serde_json::to_value(&msg).unwrap();
+            assert_eq!(json["severity"], severity);
+        }
+    }
+
+    #[test]
+    fn test_escaped_strings() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("two\nlines"),
+                b!("newline" => "a\nb", "quote" => "a\"b", "nul" => "a\0b", "char" => '\n')
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        assert_eq!(msg.kv["char"], "\n");
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains(|c: char| c.is_control()));
+        let line = json.lines().next().unwrap();
+        let parsed: Message = serde_json::from_str(line).unwrap();
+        assert_eq!(parsed.message, "two\nlines");
+        assert_eq!(parsed.kv["newline"], "a\nb");
+        assert_eq!(parsed.kv["quote"], "a\"b");
+        assert_eq!(parsed.kv["nul"], "a\0b");
+    }
+
+    #[test]
+    fn test_noop_drain() {
+        let drain = Dtrace::noop();
+        assert!(drain.is_noop());
+        let counters = drain.counters();
+        let log = slog::Logger::root(drain.fuse(), o!());
+        slog::info!(log, "a message");
+        assert_eq!(counters.total(slog::Level::Info), 0);
+
+        let (drain, _) = with_drain(slog::Discard);
+        assert_eq!(drain.dtrace().is_noop(), !is_supported());
+    }
+
+    #[test]
+    fn test_lazy_registration() {
+        let drain = Arc::new(Dtrace::new_lazy());
+        assert!(!drain.is_registered());
+        let counters = drain.counters();
+        let log = slog::Logger::root(Arc::clone(&drain).fuse(), o!());
+        slog::info!(log, "before registering");
+        assert_eq!(counters.total(slog::Level::Info), 0);
+
+        assert!(drain.register_probes_now().is_success());
+        assert!(drain.is_registered());
+        slog::info!(log, "after registering");
+        assert_eq!(counters.total(slog::Level::Info), 1);
+        assert!(drain.register_probes_now().is_success());
+    }
+
+    #[test]
+    fn test_failed_registration() {
+        let (drain, registration) = DtraceBuilder::new().build_registered(
+            ProbeRegistration::Failed(RegistrationError::Kernel(String::from("simulated"))),
+        );
+        assert!(!registration.is_success());
+        assert!(!drain.is_registered());
+        assert!(!drain.level_enabled(slog::Level::Info));
+        let counters = drain.counters();
+        let drain = Arc::new(drain);
+        let log = slog::Logger::root(Arc::clone(&drain).fuse(), o!());
+        slog::info!(log, "before registering");
+        assert_eq!(counters.total(slog::Level::Info), 0);
+
+        // Registering is retried, rather than assumed to have succeeded.
+        assert!(drain.register_probes_now().is_success());
+        assert!(drain.is_registered());
+        slog::info!(log, "after registering");
+        assert_eq!(counters.total(slog::Level::Info), 1);
+    }
+
+    #[test]
+    fn test_bytes_as_base64() {
+        let msg = create_dtrace_message(
+            &record!(
+                slog::Level::Info,
+                "",
+                &format_args!("a message"),
+                b!("id" => &[0u8, 1, 2, 0xff][..], "short" => &[0xfbu8][..])
+            ),
+            &slog::OwnedKVList::from(o!()),
+            &Config::default(),
+        );
+        let line = format!("{{\"ok\":{}}}", serde_json::to_string(&msg).unwrap());
+        let msg = Message::from_probe_line(&line).unwrap();
+        assert_eq!(
+            msg.kv["id"][BASE64_KEY],
+            serde_json::Value::from("AAEC/w==")
+        );
+        assert_eq!(msg.kv["short"][BASE64_KEY], serde_json::Value::from("+w=="));
+    }
+
+    #[test]
+    fn test_duplicate_key_policy() {
+        let kv = |policy| {
+            let config = Config {
+                duplicate_keys: policy,
+                ..Default::default()
+            };
+            create_dtrace_message(
+                &record!(
+                    slog::Level::Info,
+                    "",
+                    &format_args!("a message"),
+                    b!("key" => 1, "other" => 0)
+                ),
+                &slog::OwnedKVList::from(o!("key" => 2)),
+                &config,
+            )
+            .kv
+        };
+        assert_eq!(kv(DuplicateKeyPolicy::KeepLast)["key"], 2);
+        assert_eq!(kv(DuplicateKeyPolicy::KeepFirst)["key"], 1);
+        let collected = kv(DuplicateKeyPolicy::Collect);
+        assert_eq!(collected["key"], serde_json::json!([1, 2]));
+        assert_eq!(collected["other"], 0);
     }
 }
@@ -0,0 +1,155 @@
+//! Forward records from the `log` crate to the same DTrace probes as the [`Dtrace`](crate::Dtrace)
+//! drain.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    fire_probe, fire_request, float_value, new_message, Config, DtraceBuilder, Format,
+    LevelCounters, Location, ProbeRegistration, Provider, Serializer, Slog, RECORD_SCOPE,
+};
+use log::kv::{Key, Value, VisitSource};
+use std::sync::Arc;
+
+/// A [`log::Log`] implementation that forwards all records to DTrace.
+///
+/// Records are emitted as a [`Message`](crate::Message) through the same probes as the
+/// [`Dtrace`](crate::Dtrace) drain, so that libraries using the `log` crate can be traced
+/// alongside those using `slog`. The key-value pairs of each record are added to the `kv` map.
+/// `log` has no equivalent of `slog`'s `Critical` level, so the `critical` probe never fires for
+/// records from this logger; the other levels map onto the probes of the same name.
+///
+/// A `DtraceLogger` is configured with the same [`DtraceBuilder`] as the drain, and installed
+/// with [`log::set_boxed_logger`], for example.
+#[derive(Debug)]
+pub struct DtraceLogger<P = Slog> {
+    config: Config,
+    counters: Arc<LevelCounters>,
+    _provider: std::marker::PhantomData<fn() -> P>,
+}
+
+impl DtraceLogger {
+    /// Create a new logger, emitting records to DTrace.
+    ///
+    /// See [`Dtrace::new`](crate::Dtrace::new) for details on the returned [`ProbeRegistration`].
+    pub fn new() -> (Self, ProbeRegistration) {
+        DtraceBuilder::new().build_logger()
+    }
+}
+
+impl<P> DtraceLogger<P> {
+    /// Return the counts of records handled by this logger, see [`LevelCounters`].
+    pub fn counters(&self) -> Arc<LevelCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    pub(crate) fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            counters: Arc::default(),
+            _provider: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<P> log::Log for DtraceLogger<P>
+where
+    P: Provider,
+{
+    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let level = slog_level(record.level());
+        let module = record.module_path().unwrap_or_default();
+        if !self.config.forwards(&self.counters, level, module) {
+            return;
+        }
+        let value = match (self.config.format, self.config.numeric_key()) {
+            (Format::Json, Some(key)) => {
+                record
+                    .key_values()
+                    .get(Key::from_str(key))
+                    // Negative values can't be passed as the unsigned probe argument, and
+                    // `to_u64` returns `None` for them.
+                    .and_then(|value| value.to_u64())
+            }
+            _ => None,
+        };
+        let f = || {
+            let location = Location {
+                module: module.to_string(),
+                file: record.file().unwrap_or_default().to_string(),
+                line: record.line().unwrap_or_default(),
+                function: None,
+            };
+            let mut serializer = Serializer::with_config(&self.config);
+            serializer.scope = RECORD_SCOPE;
+            let result = record
+                .key_values()
+                .visit(&mut PairVisitor(&mut serializer))
+                .map_err(|e| slog::Error::Io(std::io::Error::other(e.to_string())));
+            let scopes = serializer.scopes.take();
+            serializer.record_result(result);
+            let kv = serializer.finish();
+            new_message(
+                &self.config,
+                location,
+                level,
+                record.args().to_string(),
+                kv,
+                scopes,
+            )
+        };
+        fire_probe::<P, _>(&self.config, &self.counters, level, value, f);
+        fire_request::<P, _>(&self.config, f);
+    }
+
+    fn flush(&self) {}
+}
+
+// Map a `log` level to the `slog` level with the same probe.
+fn slog_level(level: log::Level) -> slog::Level {
+    match level {
+        log::Level::Trace => slog::Level::Trace,
+        log::Level::Debug => slog::Level::Debug,
+        log::Level::Info => slog::Level::Info,
+        log::Level::Warn => slog::Level::Warning,
+        log::Level::Error => slog::Level::Error,
+    }
+}
+
+// Type used to add the key-value pairs of a `log` record to a `Serializer`.
+struct PairVisitor<'a>(&'a mut Serializer);
+
+impl<'kvs> VisitSource<'kvs> for PairVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            float_value(value)
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else if let Some(value) = value.to_borrowed_str() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        let _ = self.0.emit(key.as_str(), value);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! A ring buffer of the most recent messages handled by a [`Dtrace`](crate::Dtrace) drain.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The most recent messages handled by a [`Dtrace`](crate::Dtrace) drain, whether or not DTrace
+/// was attached to see them.
+///
+/// This acts as a flight recorder, configured with
+/// [`DtraceBuilder::recent_messages`](crate::DtraceBuilder::recent_messages). It holds at most
+/// [`capacity`](RecentMessages::capacity) messages, overwriting the oldest once full, and so its
+/// memory is bounded by that number of messages, each of which is limited in size by
+/// [`DtraceBuilder::max_serialized_bytes`](crate::DtraceBuilder::max_serialized_bytes).
+///
+/// Each message is stored in its own slot, so threads logging at once only contend for a lock if
+/// they write to the same slot, which needs as many messages to be logged in between as there are
+/// slots. A handle may be kept with [`Dtrace::recent_messages`](crate::Dtrace::recent_messages)
+/// before the drain is moved into a logger, to dump the messages from a panic hook, for example:
+///
+/// ```
+/// use slog::{error, o, Logger};
+/// use slog_dtrace::DtraceBuilder;
+///
+/// let (drain, _) = DtraceBuilder::new().recent_messages(64).build();
+/// let recent = drain.recent_messages().unwrap();
+/// let log = Logger::root(drain, o!());
+///
+/// let hook = std::panic::take_hook();
+/// std::panic::set_hook(Box::new(move |info| {
+///     for msg in recent.messages() {
+///         eprintln!("{}", msg.to_probe_json());
+///     }
+///     hook(info);
+/// }));
+/// # let _ = std::panic::take_hook();
+///
+/// error!(log, "about to fail");
+/// ```
+#[derive(Debug)]
+pub struct RecentMessages {
+    // The sequence number of the next message.
+    next: AtomicU64,
+    // The latest message written to each slot, with its sequence number.
+    slots: Box<[Mutex<Option<Slot>>]>,
+}
+
+// A retained message, and its sequence number.
+type Slot = (u64, Message);
+
+impl RecentMessages {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            next: AtomicU64::new(0),
+            slots: (0..capacity).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    /// Return the maximum number of messages retained.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Return the retained messages, from the oldest to the newest.
+    ///
+    /// Messages are ordered by when they were handed to the buffer, which for messages logged at
+    /// once by several threads may differ slightly from the order of their timestamps.
+    pub fn messages(&self) -> Vec<Message> {
+        let mut messages: Vec<_> = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.lock().unwrap_or_else(|e| e.into_inner()).clone())
+            .collect();
+        messages.sort_unstable_by_key(|(seq, _)| *seq);
+        messages.into_iter().map(|(_, msg)| msg).collect()
+    }
+
+    /// Discard the retained messages.
+    pub fn clear(&self) {
+        for slot in self.slots.iter() {
+            slot.lock().unwrap_or_else(|e| e.into_inner()).take();
+        }
+    }
+
+    pub(crate) fn push(&self, msg: Message) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = (seq % self.slots.len() as u64) as usize;
+        let mut slot = self.slots[index].lock().unwrap_or_else(|e| e.into_inner());
+        // A thread which took its sequence number earlier may be slower to reach the slot, in
+        // which case its message is already older than the one there.
+        if slot.as_ref().is_none_or(|(current, _)| *current < seq) {
+            *slot = Some((seq, msg));
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! A [`Provider`](crate::Provider) which captures messages in memory, for testing.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Degradation, JsonMap, Message, ProbeNames, Provider};
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<Captured>> = const { RefCell::new(Vec::new()) };
+    static STATS: RefCell<Vec<Degradation>> = const { RefCell::new(Vec::new()) };
+    static SPANS: RefCell<Vec<CapturedSpan>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A [`Provider`] which captures messages in memory, rather than firing DTrace probes.
+///
+/// This allows tests to check which messages a drain would emit to DTrace, without running
+/// `dtrace(1)` or needing a platform which supports it. Select it with
+/// [`DtraceBuilder::provider`](crate::DtraceBuilder::provider), log as usual, and then collect the
+/// messages with [`TestSink::take`]. Unlike a real provider, every probe is treated as enabled.
+///
+/// Messages are captured separately for each thread, so that tests running in parallel don't see
+/// each other's messages. The [`Dtrace`](crate::Dtrace) drain, `DtraceLayer` and `DtraceLogger`
+/// fire probes on the logging thread, but [`AsyncDtrace`](crate::AsyncDtrace) fires them on its
+/// worker thread, where they can't be taken.
+///
+/// ```
+/// use slog::{error, o, Logger};
+/// use slog_dtrace::{DtraceBuilder, TestSink};
+///
+/// let (drain, _) = DtraceBuilder::new().provider::<TestSink>().build();
+/// let log = Logger::root(drain, o!());
+/// error!(log, "it broke"; "key" => "value");
+///
+/// let captured = TestSink::take();
+/// assert_eq!(captured.len(), 1);
+/// assert_eq!(captured[0].probe, "error");
+/// assert_eq!(captured[0].message.kv["key"], "value");
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TestSink;
+
+/// A message captured by a [`TestSink`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Captured {
+    /// The name of the probe which would have fired, e.g., `warn` or `warn_value`.
+    pub probe: &'static str,
+
+    /// The message emitted by the probe.
+    ///
+    /// For the `_bytes` probes, this is decoded from MessagePack or JSON, so messages
+    /// emitted with [`Format::Tsv`](crate::Format::Tsv) are not captured. Each message of the
+    /// `batch` probe is captured separately.
+    pub message: Message,
+}
+
+/// The arguments of a `span_enter` or `span_exit` probe captured by a [`TestSink`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CapturedSpan {
+    /// The name of the probe which would have fired, `span_enter` or `span_exit`.
+    pub probe: &'static str,
+
+    /// The ID of the span.
+    pub id: u64,
+
+    /// The name of the span.
+    pub name: String,
+
+    /// The fields recorded for the span.
+    pub fields: JsonMap,
+}
+
+impl TestSink {
+    /// Return the messages captured on this thread, in the order they were emitted.
+    ///
+    /// This removes them, so that each call returns only the messages since the last.
+    pub fn take() -> Vec<Captured> {
+        CAPTURED.with(|captured| captured.take())
+    }
+
+    /// Return the arguments of the `stats` probe captured on this thread, in the order they were
+    /// emitted.
+    ///
+    /// These are kept apart from the messages, which [`TestSink::take`] returns. This removes
+    /// them, so that each call returns only those since the last.
+    pub fn take_stats() -> Vec<Degradation> {
+        STATS.with(|stats| stats.take())
+    }
+
+    /// Return the arguments of the `span_enter` and `span_exit` probes captured on this thread, in
+    /// the order they were emitted.
+    ///
+    /// Like those of the `stats` probe, these are kept apart from the messages. This removes them,
+    /// so that each call returns only those since the last.
+    pub fn take_spans() -> Vec<CapturedSpan> {
+        SPANS.with(|spans| spans.take())
+    }
+
+    /// Discard the messages and the `stats`, `span_enter` and `span_exit` probe arguments captured
+    /// on this thread.
+    pub fn clear() {
+        CAPTURED.with(|captured| captured.borrow_mut().clear());
+        STATS.with(|stats| stats.borrow_mut().clear());
+        SPANS.with(|spans| spans.borrow_mut().clear());
+    }
+
+    fn capture(probe: &'static str, message: Message) {
+        CAPTURED.with(|captured| captured.borrow_mut().push(Captured { probe, message }));
+    }
+
+    fn capture_span(probe: &'static str, (id, name, fields): (u64, String, JsonMap)) {
+        SPANS.with(|spans| {
+            spans.borrow_mut().push(CapturedSpan {
+                probe,
+                id,
+                name,
+                fields,
+            })
+        });
+    }
+}
+
+impl Provider for TestSink {
+    fn fire<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Message + Clone,
+    {
+        Self::capture(probe_names(level).0, f());
+    }
+
+    fn fire_numbered<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Message + Clone,
+    {
+        Self::capture(ProbeNames::Numeric.name(level), f());
+    }
+
+    fn fire_value<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> (Message, u64) + Clone,
+    {
+        Self::capture(probe_names(level).1, f().0);
+    }
+
+    fn fire_bytes<F>(level: slog::Level, f: F)
+    where
+        F: FnOnce() -> Vec<u8> + Clone,
+    {
+        // Messages which fail to encode are emitted as an empty buffer, and those formatted as
+        // TSV are neither MessagePack nor JSON, so neither is captured.
+        let buf = f();
+        let message = rmp_serde::from_slice(&buf)
+            .ok()
+            .or_else(|| {
+                Message::from_framed_json(&buf)
+                    .ok()
+                    .map(|(message, _)| message)
+            })
+            .or_else(|| {
+                let line = std::str::from_utf8(&buf).ok()?;
+                Message::from_probe_line(line).ok()
+            });
+        if let Some(message) = message {
+            Self::capture(probe_names(level).2, message);
+        }
+    }
+
+    fn fire_tagged<F>(f: F)
+    where
+        F: FnOnce() -> (String, Message) + Clone,
+    {
+        Self::capture("tagged", f().1);
+    }
+
+    fn fire_message<F>(f: F)
+    where
+        F: FnOnce() -> Message + Clone,
+    {
+        Self::capture("message", f());
+    }
+
+    fn fire_batch<F>(f: F)
+    where
+        F: FnOnce() -> Vec<Message> + Clone,
+    {
+        for message in f() {
+            Self::capture("batch", message);
+        }
+    }
+
+    fn fire_request<F>(f: F)
+    where
+        F: FnOnce() -> (Message, u64, u64) + Clone,
+    {
+        Self::capture("request", f().0);
+    }
+
+    // The `startup` and `logger` probes carry no message, and so are never captured.
+    fn fire_startup<F>(_: F)
+    where
+        F: FnOnce() -> (u64, String, u64) + Clone,
+    {
+    }
+
+    fn fire_logger<F>(_: F)
+    where
+        F: FnOnce() -> (u64, JsonMap) + Clone,
+    {
+    }
+
+    fn fire_fields<F>(f: F)
+    where
+        F: FnOnce() -> Message + Clone,
+    {
+        Self::capture("fields", f());
+    }
+
+    fn fire_stats<F>(f: F)
+    where
+        F: FnOnce() -> Degradation + Clone,
+    {
+        STATS.with(|stats| stats.borrow_mut().push(f()));
+    }
+
+    fn gate_enabled() -> bool {
+        true
+    }
+
+    fn level_gate_enabled(_: slog::Level) -> bool {
+        true
+    }
+
+    fn fire_span_enter<F>(f: F)
+    where
+        F: FnOnce() -> (u64, String, JsonMap) + Clone,
+    {
+        Self::capture_span("span_enter", f());
+    }
+
+    fn fire_span_exit<F>(f: F)
+    where
+        F: FnOnce() -> (u64, String, JsonMap) + Clone,
+    {
+        Self::capture_span("span_exit", f());
+    }
+}
+
+// Return the names of the plain, `_value` and `_bytes` probes for `level`.
+fn probe_names(level: slog::Level) -> (&'static str, &'static str, &'static str) {
+    use slog::Level;
+    match level {
+        Level::Trace => ("trace", "trace_value", "trace_bytes"),
+        Level::Debug => ("debug", "debug_value", "debug_bytes"),
+        Level::Info => ("info", "info_value", "info_bytes"),
+        Level::Warning => ("warn", "warn_value", "warn_bytes"),
+        Level::Error => ("error", "error_value", "error_bytes"),
+        Level::Critical => ("critical", "critical_value", "critical_bytes"),
+    }
+}
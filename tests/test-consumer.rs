@@ -0,0 +1,92 @@
+//! Check that a `Consumer` parses the output of a process printing messages.
+//!
+//! These run `sh` in place of `dtrace(1)`, printing the lines DTrace would.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(all(feature = "consumer", unix))]
+
+use slog_dtrace::{Consumer, Location, Message, ParseError};
+use tokio::process::Command;
+
+// Return a command printing `output`.
+fn printing(output: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(r#"printf '%s' "$0""#).arg(output);
+    command
+}
+
+fn message(text: &str) -> String {
+    Message::new(Location::new("m", "f", 1), slog::Level::Warning, text).to_probe_json()
+}
+
+#[tokio::test]
+async fn test_consumer_parses_lines() {
+    let output = format!(
+        "{}\n\n{}\n{}\n{}",
+        message("first"),
+        r#"{"err":"failed"}"#,
+        &message("cut short")[..20],
+        // The last line has no newline.
+        message("last"),
+    );
+    let mut consumer = Consumer::spawn(printing(&output)).unwrap();
+
+    let msg = consumer.next_message().await.unwrap().unwrap();
+    assert_eq!(msg.message, "first");
+    assert_eq!(msg.level, "WARNING");
+    assert!(matches!(
+        consumer.next_message().await,
+        Some(Err(ParseError::Serialization(e))) if e == "failed"
+    ));
+    assert!(matches!(
+        consumer.next_message().await,
+        Some(Err(ParseError::Truncated))
+    ));
+    let msg = consumer.next_message().await.unwrap().unwrap();
+    assert_eq!(msg.message, "last");
+    assert!(consumer.next_message().await.is_none());
+    assert!(consumer.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn test_consumer_stop() {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(r#"printf '%s\n' "$0"; exec sleep 60"#)
+        .arg(message("before stopping"));
+    let mut consumer = Consumer::spawn(command).unwrap();
+    let msg = consumer.next_message().await.unwrap().unwrap();
+    assert_eq!(msg.message, "before stopping");
+    assert!(consumer.id().is_some());
+    consumer.stop().await.unwrap();
+}
+
+#[test]
+fn test_consumer_command() {
+    let command = Consumer::command("slog", &[slog::Level::Error]);
+    let args: Vec<_> = command.as_std().get_args().collect();
+    assert_eq!(
+        args,
+        [
+            "-q",
+            "-Z",
+            "-n",
+            r#"slog*:::error { printf("%s\n", copyinstr(arg0)); }"#
+        ]
+    );
+    assert_eq!(command.as_std().get_program(), "dtrace");
+}
@@ -0,0 +1,65 @@
+//! Check that `SLOG_DTRACE_DISABLE` turns off forwarding to DTrace.
+//!
+//! This is the only test in its binary, since setting the variable affects every drain built in
+//! the process while it is set.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use slog::{info, o, Drain, Logger};
+use slog_dtrace::{Dtrace, DtraceBuilder, DISABLE_ENV_VAR};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// A drain counting the messages it receives.
+#[derive(Clone, Default)]
+struct Counting(Arc<AtomicUsize>);
+
+impl Drain for Counting {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, _: &slog::Record<'_>, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_disable_env_var() {
+    std::env::set_var(DISABLE_ENV_VAR, "1");
+    let (drain, registration) = Dtrace::new();
+    assert!(registration.is_success());
+    assert!(drain.is_noop());
+    let counters = drain.counters();
+    let log = Logger::root(drain, o!());
+    info!(log, "a message");
+    assert_eq!(counters.total(slog::Level::Info), 0);
+
+    // The wrapped drain still receives every message.
+    let counting = Counting::default();
+    let (drain, _) = DtraceBuilder::new().build_with_drain(counting.clone());
+    assert!(drain.dtrace().is_noop());
+    let log = Logger::root(drain.fuse(), o!());
+    info!(log, "a message");
+    assert_eq!(counting.0.load(Ordering::Relaxed), 1);
+
+    std::env::set_var(DISABLE_ENV_VAR, "0");
+    let (drain, _) = Dtrace::new();
+    assert!(!drain.is_noop());
+
+    std::env::remove_var(DISABLE_ENV_VAR);
+    let (drain, _) = Dtrace::new();
+    assert!(!drain.is_noop());
+}
@@ -0,0 +1,61 @@
+//! Check the messages routed to DTrace with a `TestSink`, without running `dtrace(1)`.
+
+// Copyright 2022 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "test-sink")]
+
+use slog::{error, info, o, warn, Logger};
+use slog_dtrace::{DtraceBuilder, TestSink};
+
+// The code under test, which should report failures to DTrace with the failing request.
+fn handle(log: &Logger, request: &str) {
+    if request.is_empty() {
+        error!(log, "empty request"; "request_id" => 7);
+    } else {
+        info!(log, "handled request"; "request" => request);
+    }
+}
+
+#[test]
+fn test_sink_routes_errors() {
+    let (drain, _) = DtraceBuilder::new().provider::<TestSink>().build();
+    let log = Logger::root(drain, o!("component" => "server"));
+
+    handle(&log, "GET /");
+    handle(&log, "");
+
+    let captured = TestSink::take();
+    assert_eq!(captured.len(), 2);
+    let errors: Vec<_> = captured.iter().filter(|c| c.probe == "error").collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message.message, "empty request");
+    assert_eq!(errors[0].message.kv["request_id"], 7);
+    assert_eq!(errors[0].message.kv["component"], "server");
+    assert!(TestSink::take().is_empty());
+}
+
+#[test]
+fn test_sink_value_and_tagged_probes() {
+    let (drain, _) = DtraceBuilder::new()
+        .provider::<TestSink>()
+        .numeric_key("bytes")
+        .build();
+    let log = Logger::root(drain, o!());
+
+    warn!(log, #"net", "slow write"; "bytes" => 512);
+
+    let probes: Vec<_> = TestSink::take().into_iter().map(|c| c.probe).collect();
+    assert_eq!(probes, ["warn_value", "tagged"]);
+}
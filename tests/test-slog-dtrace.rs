@@ -21,8 +21,8 @@ fn main() {}
 
 #[cfg(test)]
 mod tests {
-    use slog::{info, o, warn, Drain, Logger};
-    use slog_dtrace::{Message, ProbeRegistration};
+    use slog::{error, info, o, warn, Drain, Logger};
+    use slog_dtrace::{DtraceBuilder, Message, ProbeRegistration, Provider};
     use std::ffi::{OsStr, OsString};
     use std::io::Read;
     use std::process::{Command, Stdio};
@@ -92,26 +92,34 @@ mod tests {
     where
         S: AsRef<str>,
     {
-        // The message is actually in an result-like enum, strip that bit.
-        let prefix = r#"{"ok":"#;
-        let suffix = "}";
-        let msg = line
-            .as_ref()
-            .trim()
-            .strip_prefix(prefix)?
-            .strip_suffix(suffix)?;
-        serde_json::from_str(msg).ok()
+        Message::from_probe_line(line.as_ref()).ok()
     }
 
     // Helper to run DTrace and emit a single warning message from a logger.
     fn run_dtrace_single_warn_message(cmd: &str) -> Option<Message> {
+        run_dtrace_single_message(cmd, DtraceBuilder::new(), |log| {
+            warn!(log, "a message"; "some-key" => 2);
+        })
+    }
+
+    // Helper to run DTrace and emit messages from a logger built with `builder`, returning the
+    // first message DTrace prints.
+    fn run_dtrace_single_message<P, F>(
+        cmd: &str,
+        builder: DtraceBuilder<P>,
+        f: F,
+    ) -> Option<Message>
+    where
+        P: Provider + 'static,
+        F: FnOnce(&Logger),
+    {
         let mut dtrace = run_dtrace(&["-Z", "-n", cmd, "-q"]).unwrap();
 
         {
-            let (drain, registration) = slog_dtrace::Dtrace::new();
+            let (drain, registration) = builder.build();
             assert!(registration.is_success(), "Failed to register probes");
             let log = Logger::root(drain.fuse(), o!("key" => "value"));
-            warn!(log, "a message"; "some-key" => 2);
+            f(&log);
         }
 
         let mut communicator = dtrace.communicate_start(None).limit_time(SUBPROC_WAIT);
@@ -143,6 +151,8 @@ mod tests {
         assert_eq!(msg.message, "a message");
         assert_eq!(msg.kv["key"], serde_json::Value::from("value"));
         assert_eq!(msg.kv["some-key"], serde_json::Value::from(2));
+        assert_eq!(msg.pid, std::process::id());
+        assert!(msg.process_token.is_none());
     }
 
     #[test]
@@ -168,6 +178,325 @@ mod tests {
         assert!(run_dtrace_single_warn_message(cmd).is_none());
     }
 
+    slog_dtrace::provider!(Custom, custom_probes, "custom");
+
+    #[test]
+    fn test_dtrace_custom_provider() {
+        let cmd = r#"
+        custom*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().provider::<Custom>();
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a message"; "some-key" => 2);
+        })
+        .expect("failed to parse a warning message");
+        assert_eq!(msg.message, "a message");
+        assert_eq!(msg.kv["key"], serde_json::Value::from("value"));
+    }
+
+    slog_dtrace::provider!(Other, other_probes, "other");
+
+    #[test]
+    fn test_dtrace_multiple_providers() {
+        let cmd = r#"
+        custom*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }
+        other*:::warn {
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().provider::<Custom>();
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            let (other, _) = DtraceBuilder::new().provider::<Other>().build();
+            let other = Logger::root(other.fuse(), o!());
+            warn!(log, "to custom");
+            warn!(other, "to other");
+        })
+        .expect("failed to parse a warning message");
+        assert_eq!(msg.message, "to custom");
+    }
+
+    #[test]
+    fn test_dtrace_batch() {
+        let cmd = r#"
+        slog*:::batch {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let mut dtrace = run_dtrace(&["-Z", "-x", "strsize=4k", "-n", cmd, "-q"]).unwrap();
+        {
+            let (drain, registration) = DtraceBuilder::new()
+                .batch(3, Duration::from_secs(1))
+                .build_async(16);
+            assert!(registration.is_success(), "Failed to register probes");
+            let log = Logger::root(drain.fuse(), o!());
+            for i in 0..3 {
+                warn!(log, "a message"; "i" => i);
+            }
+        }
+        let mut communicator = dtrace.communicate_start(None).limit_time(SUBPROC_WAIT);
+        let (stdout, _) = communicator
+            .read_string()
+            .expect("failed to read from dtrace");
+        dtrace
+            .wait_timeout(SUBPROC_WAIT)
+            .expect("failed to wait for dtrace child process");
+        let mut result: serde_json::Value = serde_json::from_str(stdout.unwrap().trim()).unwrap();
+        let batch: Vec<Message> = serde_json::from_value(result["ok"].take()).unwrap();
+        assert_eq!(batch.len(), 3);
+        for (i, msg) in batch.iter().enumerate() {
+            assert_eq!(msg.kv["i"], serde_json::Value::from(i));
+        }
+    }
+
+    #[test]
+    fn test_dtrace_request_probe() {
+        let cmd = r#"
+        slog*:::request /arg1 == 1500 && arg2 == 512/ {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().request_probe("latency_ns", "bytes");
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            info!(log, "a response"; "latency_ns" => 1500, "bytes" => 512);
+        })
+        .expect("failed to parse a request message");
+        assert_eq!(msg.message, "a response");
+    }
+
+    #[test]
+    fn test_dtrace_nested_keys() {
+        let cmd = r#"
+        slog*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().nested_keys(true);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a request"; "req.method" => "GET", "req.path" => "/");
+        })
+        .expect("failed to parse a warning message");
+        assert_eq!(msg.kv["req"]["method"], serde_json::Value::from("GET"));
+        assert_eq!(msg.kv["req"]["path"], serde_json::Value::from("/"));
+        assert_eq!(msg.kv["key"], serde_json::Value::from("value"));
+    }
+
+    #[test]
+    fn test_dtrace_max_message_bytes() {
+        let cmd = r#"
+        slog*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().max_message_bytes(256);
+        let long = "x".repeat(512);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a message"; "long" => &long);
+        })
+        .expect("failed to parse a truncated message");
+        assert!(msg.truncated);
+        assert!(msg.original_len.unwrap() > 512);
+        assert!(!msg.kv.contains_key("long"));
+        assert_eq!(msg.message, "a message");
+    }
+
+    #[test]
+    fn test_dtrace_numeric_key() {
+        let cmd = r#"
+        slog*:::warn_value /arg1 == 42/ {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().numeric_key("latency");
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "no value");
+            warn!(log, "a value"; "latency" => 42);
+        })
+        .expect("failed to parse a warning message");
+        assert_eq!(msg.message, "a value");
+        assert_eq!(msg.kv["latency"], serde_json::Value::from(42));
+    }
+
+    #[test]
+    fn test_dtrace_tagged() {
+        let cmd = r#"
+        slog*:::tagged /copyinstr(arg0) == "audit"/ {
+            printf("%s\n", copyinstr(arg1));
+            exit(0);
+        }"#;
+        let msg = run_dtrace_single_message(cmd, DtraceBuilder::new(), |log| {
+            info!(log, #"other", "another tag");
+            info!(log, #"audit", "an audit message");
+        })
+        .expect("failed to parse a tagged message");
+        assert_eq!(msg.message, "an audit message");
+        assert_eq!(msg.level, "INFO");
+    }
+
+    #[test]
+    fn test_dtrace_single_probe() {
+        let cmd = r#"
+        slog*:::message /json(copyinstr(arg0), "ok.level") == "ERRO"/ {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().single_probe(true);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a warning");
+            error!(log, "an error");
+        })
+        .expect("failed to parse an error message");
+        assert_eq!(msg.message, "an error");
+    }
+
+    #[test]
+    fn test_dtrace_min_level() {
+        // Only the error message should be forwarded, so that the first message is the last one
+        // emitted.
+        let cmd = r#"
+        slog*:::* {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().min_level(slog::Level::Error);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a warning");
+            error!(log, "an error");
+        })
+        .expect("failed to parse an error message");
+        assert_eq!(msg.message, "an error");
+        assert_eq!(msg.level, "ERRO");
+    }
+
+    #[test]
+    fn test_dtrace_thread_name() {
+        let cmd = r#"
+        slog*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().thread_names(true);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            let log = log.clone();
+            std::thread::Builder::new()
+                .name(String::from("logger"))
+                .spawn(move || warn!(log, "from a thread"))
+                .unwrap()
+                .join()
+                .unwrap();
+        })
+        .expect("failed to parse a warning message");
+        assert_eq!(msg.thread_name.as_deref(), Some("logger"));
+        assert_ne!(msg.thread_id, 0);
+    }
+
+    #[test]
+    fn test_dtrace_monotonic_timestamps() {
+        let cmd = r#"
+        slog*:::warn {
+            printf("%s\n", copyinstr(arg0));
+            exit(0);
+        }"#;
+        let builder = DtraceBuilder::new().monotonic_timestamps(true);
+        let msg = run_dtrace_single_message(cmd, builder, |log| {
+            warn!(log, "a message");
+        })
+        .expect("failed to parse a warning message");
+        assert!(msg.monotonic_ns.is_some());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_dtrace_log_logger() {
+        use log::Log;
+
+        let mut dtrace = run_dtrace(&[
+            "-Z",
+            "-n",
+            r#"
+            slog*:::warn {
+                printf("%s\n", copyinstr(arg0));
+                exit(0);
+            }"#,
+            "-q",
+        ])
+        .unwrap();
+
+        {
+            let (logger, registration) = slog_dtrace::DtraceLogger::new();
+            assert!(registration.is_success(), "Failed to register probes");
+            let kv = [("some_key", 2)];
+            logger.log(
+                &log::Record::builder()
+                    .level(log::Level::Warn)
+                    .args(format_args!("a message"))
+                    .key_values(&kv)
+                    .build(),
+            );
+        }
+
+        let mut communicator = dtrace.communicate_start(None).limit_time(SUBPROC_WAIT);
+        let stdout = communicator
+            .read_string()
+            .expect("failed to read dtrace output")
+            .0
+            .expect("failed to read a line from dtrace stdout");
+        dtrace
+            .wait_timeout(SUBPROC_WAIT)
+            .expect("failed to wait for dtrace child process");
+        let msg = read_message_from_line(&stdout).expect("failed to parse a warning message");
+        assert_eq!(msg.message, "a message");
+        assert_eq!(msg.level, "WARN");
+        assert_eq!(msg.kv["some_key"], serde_json::Value::from(2));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_dtrace_tracing_layer() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let mut dtrace = run_dtrace(&[
+            "-Z",
+            "-n",
+            r#"
+            slog*:::warn {
+                printf("%s\n", copyinstr(arg0));
+                exit(0);
+            }"#,
+            "-q",
+        ])
+        .unwrap();
+
+        {
+            let (layer, registration) = slog_dtrace::DtraceLayer::new();
+            assert!(registration.is_success(), "Failed to register probes");
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                let span = tracing::info_span!("request", key = "value");
+                let _guard = span.enter();
+                tracing::warn!(some_key = 2, "a message");
+            });
+        }
+
+        let mut communicator = dtrace.communicate_start(None).limit_time(SUBPROC_WAIT);
+        let stdout = communicator
+            .read_string()
+            .expect("failed to read dtrace output")
+            .0
+            .expect("failed to read a line from dtrace stdout");
+        dtrace
+            .wait_timeout(SUBPROC_WAIT)
+            .expect("failed to wait for dtrace child process");
+        let msg = read_message_from_line(&stdout).expect("failed to parse a warning message");
+        assert_eq!(msg.message, "a message");
+        assert_eq!(msg.level, "WARN");
+        assert_eq!(msg.kv["key"], serde_json::Value::from("value"));
+        assert_eq!(msg.kv["some_key"], serde_json::Value::from(2));
+    }
+
     #[test]
     fn test_dtrace_with_drain() {
         let mut dtrace = run_dtrace(&[
@@ -221,7 +550,7 @@ mod tests {
             .collect::<Vec<_>>();
         let messages: Vec<Message> = lines
             .iter()
-            .map(|line| read_message_from_line(&line).expect("failed to parse a message"))
+            .map(|line| read_message_from_line(line).expect("failed to parse a message"))
             .collect();
         assert_eq!(messages.len(), 2);
         assert_eq!(messages[0].message, "a message");